@@ -1,35 +1,172 @@
-use serde::{Deserialize, Serialize};
-use std::hash::{Hash, Hasher};
+use serde::de::{Error, Expected, Unexpected};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
 
 pub const CARDS_PER_DECK: usize = 5;
+// Every card's health and attack have to add up to this
+pub const CARD_STAT_TOTAL: u32 = 10;
+// Upper bound on recorded battle events so the journal stays small enough to prove
+pub const MAX_TIMELINE_EVENTS: usize = 128;
+// A battle still going after this many turns is a draw. Cards with no attack never wear each
+// other down, without a cap they'd fight until the guest ran out of cycles.
+pub const MAX_TURNS: u32 = 64;
 
+// The results a battle can end with
+pub const RESULT_PLAYER1_WINS: &str = "PLAYER1_WINS";
+pub const RESULT_PLAYER2_WINS: &str = "PLAYER2_WINS";
+// MAX_TURNS reached with both sides still standing. There's no winner.
+pub const RESULT_DRAW: &str = "DRAW";
+
+// One card of a creation. A stat over CARD_STAT_TOTAL can't be part of any valid card, so it's
+// refused as the card is read. validate_creation holds the stats to the rest of the rules:
+// health of at least 1, and health plus attack equal to CARD_STAT_TOTAL.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Card {
+    #[serde(deserialize_with = "stat")]
     pub health: u32,
+    #[serde(deserialize_with = "stat")]
     pub attack: u32,
 }
 
-impl Hash for Card {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.health.hash(state);
-        self.attack.hash(state);
+struct StatBound;
+
+impl Expected for StatBound {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "a stat of at most {}", CARD_STAT_TOTAL)
     }
 }
 
+fn stat<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let stat = u32::deserialize(deserializer)?;
+    if stat > CARD_STAT_TOTAL {
+        return Err(D::Error::invalid_value(Unexpected::Unsigned(stat.into()), &StatBound));
+    }
+    Ok(stat)
+}
 
+// A creation, the format the server takes it in, stores and hashes it as and sends to the guest.
+// Exactly CARDS_PER_DECK cards, and fields the format doesn't have are refused rather than
+// dropped, so a client that misspells a stat hears about it at the edge.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Deck {
     pub cards: [Card; CARDS_PER_DECK],
 }
 
-impl Hash for Deck {
-    fn hash<H: Hasher>(&self, state: &mut H) {
+impl Deck {
+    // The bytes a commitment to the deck is taken over: each card in deck order, health then
+    // attack, as 4 byte little endian. Spelled out here rather than left to a Hash impl or a
+    // serde format, so nothing the server or the guest derives can change what gets hashed.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CARDS_PER_DECK * 8);
         for card in self.cards {
-            card.hash(state);
+            bytes.extend_from_slice(&card.health.to_le_bytes());
+            bytes.extend_from_slice(&card.attack.to_le_bytes());
         }
+        bytes
     }
 }
 
+// Ends the nonce in a commitment, so the nonce and the deck bytes can't run into each other
+const NONCE_TERMINATOR: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreationError {
+    NoHealth { card: usize },
+    InvalidStatTotal { card: usize },
+}
+
+impl core::fmt::Display for CreationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CreationError::NoHealth { card } => write!(f, "Card {} has no health", card),
+            CreationError::InvalidStatTotal { card } => write!(
+                f,
+                "Card {} health and attack must add up to {}",
+                card, CARD_STAT_TOTAL
+            ),
+        }
+    }
+}
+
+// The commitment to a deck, shared so the server and the guest always agree on it: the hex
+// SHA-256 of the nonce and the deck's canonical bytes. There aren't many decks, so without the
+// nonce anyone could brute force a deck from its hash. An empty nonce hashes the deck alone,
+// which NPC decks and deck hashes use.
+pub fn hash_creation(deck: &Deck, nonce: &str) -> String {
+    let mut hasher = Sha256::new();
+    if !nonce.is_empty() {
+        hasher.update(nonce.as_bytes());
+        hasher.update([NONCE_TERMINATOR]);
+    }
+    hasher.update(deck.canonical_bytes());
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// The coin flip that settles which player's cards strike first, drawn from the battle seed so
+// it's recorded with the seed and a replay with the same seed flips the same way. Without it
+// player 1, whoever opened the lobby, would always go first. Whoever picks the seed picks the
+// flip, so a seed a player asked for has to be taken as choosing who goes first.
+pub fn coin_flip(battle_seed: u32) -> u8 {
+    match battle_seed % 2 {
+        0 => 1,
+        _ => 2,
+    }
+}
+
+// Checks a deck follows the deck building rules. Shared by the server, so bad decks are
+// rejected before proving, and the guest, so the rules are part of the proof.
+pub fn validate_creation(deck: &Deck) -> Result<(), CreationError> {
+    match creation_violations(deck).first() {
+        Some(err) => Err(*err),
+        None => Ok(()),
+    }
+}
+
+// Every rule the deck breaks, card by card, for deck builders to show all at once
+pub fn creation_violations(deck: &Deck) -> Vec<CreationError> {
+    let mut violations = Vec::new();
+    for (card, stats) in deck.cards.iter().enumerate() {
+        if stats.health == 0 {
+            violations.push(CreationError::NoHealth { card });
+        }
+
+        // checked so out of range stats can't wrap around to a valid total
+        if stats.health.checked_add(stats.attack) != Some(CARD_STAT_TOTAL) {
+            violations.push(CreationError::InvalidStatTotal { card });
+        }
+    }
+    violations
+}
+
+// A single hit in the battle, recorded in the order they happened
+#[derive(Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+pub struct BattleEvent {
+    pub turn: u32,
+    // 1 if player 1's card attacked, 2 if player 2's did
+    pub attacker: u8,
+    pub attacker_card: u8,
+    pub defender_card: u8,
+    pub damage: u32,
+    // a defender left on 0 health died
+    pub defender_health: u32,
+}
+
+// Why the guest couldn't play the battle out. Kept apart from the rules errors above since it
+// goes in the journal, where the variant order is part of the format: only add to the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GuestError {
+    InvalidCreation1,
+    InvalidCreation2,
+}
+
 #[derive(Debug, Hash, Serialize, Deserialize)]
 pub struct GameResult {
     pub player1_id: String,
@@ -39,5 +176,101 @@ pub struct GameResult {
     pub winner_creation_hash: String,
     pub winner_id: String,
     pub result: String,
-    pub error: String,
+    pub error: Option<GuestError>,
+    pub timeline: Vec<BattleEvent>,
+    // the arena that ran the battle, as reported by the guest itself
+    pub arena_id: String,
+    // the version of the arena's rules the guest was built with
+    pub arena_version: u32,
+    // the seed any random effects in the battle were drawn from
+    pub battle_seed: u32,
+    // whose cards struck first every turn, 1 or 2, as flipped from the seed
+    pub first_attacker: u8,
+}
+
+impl GameResult {
+    // A battle that finished without a winner. Results from before draws were named say "TIE".
+    pub fn is_draw(&self) -> bool {
+        self.error.is_none() && self.winner_id.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deck() -> Deck {
+        Deck {
+            cards: [0, 1, 2, 3, 4].map(|card| Card { health: 10 + card, attack: 1 }),
+        }
+    }
+
+    // deck() is only hashed, this one follows the deck rules so it can be read back
+    fn valid_deck() -> Deck {
+        Deck {
+            cards: [0, 1, 2, 3, 4].map(|card| Card { health: 10 - card, attack: card }),
+        }
+    }
+
+    fn deck_json(cards: usize) -> serde_json::Value {
+        let card = serde_json::json!({ "health": 9, "attack": 1 });
+        serde_json::json!({ "cards": vec![card; cards] })
+    }
+
+    #[test]
+    fn deck_round_trips_through_every_format() {
+        let json = serde_json::to_string(&valid_deck()).unwrap();
+        let from_json: Deck = serde_json::from_str(&json).unwrap();
+        assert_eq!(from_json.canonical_bytes(), valid_deck().canonical_bytes());
+
+        let stored = bson::to_bson(&valid_deck()).unwrap();
+        let from_bson: Deck = bson::from_bson(stored).unwrap();
+        assert_eq!(from_bson.canonical_bytes(), valid_deck().canonical_bytes());
+
+        // what the server hands the guest and the guest reads back
+        let words = risc0_zkvm::serde::to_vec(&valid_deck()).unwrap();
+        let from_words: Deck = risc0_zkvm::serde::from_slice(&words).unwrap();
+        assert_eq!(from_words.canonical_bytes(), valid_deck().canonical_bytes());
+    }
+
+    #[test]
+    fn deck_with_an_unknown_field_is_refused() {
+        let mut misspelt = deck_json(CARDS_PER_DECK);
+        misspelt["cards"][2] = serde_json::json!({ "health": 9, "atack": 1 });
+        assert!(serde_json::from_value::<Deck>(misspelt).is_err());
+
+        let mut extra = deck_json(CARDS_PER_DECK);
+        extra["name"] = serde_json::json!("deck");
+        assert!(serde_json::from_value::<Deck>(extra).is_err());
+    }
+
+    #[test]
+    fn deck_without_five_cards_is_refused() {
+        assert!(serde_json::from_value::<Deck>(deck_json(CARDS_PER_DECK)).is_ok());
+        for cards in [0, CARDS_PER_DECK - 1, CARDS_PER_DECK + 1] {
+            assert!(serde_json::from_value::<Deck>(deck_json(cards)).is_err(), "{}", cards);
+        }
+    }
+
+    #[test]
+    fn stat_over_the_total_is_refused() {
+        let card = |health: u32| serde_json::json!({ "health": health, "attack": 0 });
+        assert!(serde_json::from_value::<Card>(card(CARD_STAT_TOTAL)).is_ok());
+        assert!(serde_json::from_value::<Card>(card(CARD_STAT_TOTAL + 1)).is_err());
+        assert!(serde_json::from_value::<Card>(card(u32::MAX)).is_err());
+    }
+
+    // Pinned so a change to the encoding or the hash shows up here rather than as commitments
+    // that stop matching
+    #[test]
+    fn creation_hash_is_stable() {
+        assert_eq!(
+            hash_creation(&deck(), ""),
+            "7b6560b58f7ed3435cf798c1f3d28350c11f01919563a5b297326072cce6746c"
+        );
+        assert_eq!(
+            hash_creation(&deck(), "nonce"),
+            "6684ccde506176015d48af1b2706e543cba1c06cb5b36ed6248527b7f432b6dd"
+        );
+    }
 }
@@ -14,50 +14,102 @@
 
 #![no_main]
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-
 use risc0_zkvm::guest::env;
 
 risc0_zkvm::guest::entry!(main);
 
 use tenet_core;
 
-fn is_valid(deck: tenet_core::Deck) -> bool {
-    for card in deck.cards {
-        if card.health == 0 {
-            return false
-        }
+// The id the server registers this arena under
+const ARENA_ID: &str = "tenet_arena_1";
+// Bump along with the server's registry whenever the battle rules change
+const ARENA_VERSION: u32 = 6;
+
+fn record(timeline: &mut Vec<tenet_core::BattleEvent>, event: tenet_core::BattleEvent) {
+    // anything past the cap is dropped to keep the journal small
+    if timeline.len() < tenet_core::MAX_TIMELINE_EVENTS {
+        timeline.push(event);
+    }
+}
+
+// One side's attack in a turn, its damage carrying over onto the next card whenever it kills one
+struct Strike {
+    turn: u32,
+    attacker: u8,
+    attacker_card: u8,
+    damage: u32,
+}
 
-        // TODO: Use checked add
-        let sum = card.health + card.attack;
-        if sum != 10 {
-            return false
+impl Strike {
+    fn land(
+        mut self,
+        defender: &mut tenet_core::Deck,
+        defender_idx: &mut usize,
+        defender_card: &mut Option<tenet_core::Card>,
+        timeline: &mut Vec<tenet_core::BattleEvent>,
+    ) {
+        while self.damage > 0 && defender_card.is_some() {
+            let mut card = defender_card.unwrap();
+            if card.health > self.damage {
+                card.health -= self.damage;
+                defender.cards[*defender_idx] = card;
+                *defender_card = Some(card);
+                record(timeline, tenet_core::BattleEvent {
+                    turn: self.turn,
+                    attacker: self.attacker,
+                    attacker_card: self.attacker_card,
+                    defender_card: *defender_idx as u8,
+                    damage: self.damage,
+                    defender_health: card.health,
+                });
+                self.damage = 0;
+            } else {
+                self.damage -= card.health;
+                record(timeline, tenet_core::BattleEvent {
+                    turn: self.turn,
+                    attacker: self.attacker,
+                    attacker_card: self.attacker_card,
+                    defender_card: *defender_idx as u8,
+                    damage: card.health,
+                    defender_health: 0,
+                });
+                card.health = 0;
+                defender.cards[*defender_idx] = card;
+
+                // Move to next card
+                *defender_idx += 1;
+                if *defender_idx >= defender.cards.len() {
+                    *defender_card = None;
+                } else {
+                    *defender_card = Some(defender.cards[*defender_idx]);
+                }
+            }
         }
     }
-    return true
 }
 
 pub fn main() {
 
     let player1_id: String = env::read();
     let o_creation1: tenet_core::Deck = env::read();
+    let nonce1: String = env::read();
 
     let player2_id: String = env::read();
     let o_creation2: tenet_core::Deck = env::read();
+    let nonce2: String = env::read();
+
+    // all this arena leaves to chance is the coin flip, the seed is recorded so every arena's
+    // results say which seed they were played with
+    let battle_seed: u32 = env::read();
+    // which player's cards strike first, the server flips it from the seed. A flip that doesn't
+    // match the seed is refused below, so the server can't hand either side the first strike.
+    let first_attacker: u8 = env::read();
 
     let mut creation1 = o_creation1.clone();
     let mut creation2 = o_creation2.clone();
 
-    let creation1_hash = o_creation1;
-    let mut s = DefaultHasher::new();
-    creation1_hash.hash(&mut s);
-    let creation1_hash = s.finish().to_string();
-
-    let creation2_hash = o_creation2;
-    let mut s = DefaultHasher::new();
-    creation2_hash.hash(&mut s);
-    let creation2_hash = s.finish().to_string();
+    let creation1_hash = tenet_core::hash_creation(&o_creation1, &nonce1);
+    let creation2_hash = tenet_core::hash_creation(&o_creation2, &nonce2);
 
     let mut game_result = tenet_core::GameResult {
         player1_id: player1_id.clone(),
@@ -67,17 +119,28 @@ pub fn main() {
         winner_creation_hash: String::from(""),
         winner_id: String::from(""),
         result: String::from(""),
-        error: String::from(""),
+        error: None,
+        timeline: Vec::new(),
+        arena_id: String::from(ARENA_ID),
+        arena_version: ARENA_VERSION,
+        battle_seed: battle_seed,
+        first_attacker: first_attacker,
     };
 
+    assert_eq!(
+        first_attacker,
+        tenet_core::coin_flip(battle_seed),
+        "First attacker doesn't match the battle seed"
+    );
+
     // Check if creations are valid
-    if !is_valid(o_creation1) {
-        game_result.error = String::from("Invalid deck by player 1");
+    if tenet_core::validate_creation(&o_creation1).is_err() {
+        game_result.error = Some(tenet_core::GuestError::InvalidCreation1);
         env::commit(&game_result);
         return;
     }
-    if !is_valid(o_creation2) {
-        game_result.error = String::from("Invalid deck by player 2");
+    if tenet_core::validate_creation(&o_creation2).is_err() {
+        game_result.error = Some(tenet_core::GuestError::InvalidCreation2);
         env::commit(&game_result);
         return;
     }
@@ -88,72 +151,75 @@ pub fn main() {
 
     let mut player1_card = Some(creation1.cards[creation1_idx]);
     let mut player2_card = Some(creation2.cards[creation2_idx]);
-
-    while player1_card.is_some() && player2_card.is_some() {
-
-        // First player 1 attacks, and we see the possible damage, decide the next player 2 card
-        let mut player1_damage = player1_card.unwrap().attack;
-        let mut player2_damage = player2_card.unwrap().attack;
-
-        while player1_damage > 0 && player2_card.is_some() {
-            let mut player2_card_use = player2_card.unwrap();
-            if player2_card_use.health > player1_damage {
-                player2_card_use.health -= player1_damage;
-                creation2.cards[creation2_idx] = player2_card_use;
-                player2_card = Some(player2_card_use);
-                player1_damage = 0;
-            } else {
-                player1_damage -= player2_card_use.health;
-                player2_card_use.health = 0;
-                creation2.cards[creation2_idx] = player2_card_use;
-
-                // Move to next card
-                creation2_idx += 1;
-                if creation2_idx >= creation2.cards.len() {
-                    player2_card = None;
-                } else {
-                    player2_card = Some(creation2.cards[creation2_idx]);
+    let mut turn = 0;
+
+    // whoever won the flip strikes first every turn, so they're the one who gets to kill a card
+    // before it hits back
+    let order = if first_attacker == 1 { [1, 2] } else { [2, 1] };
+
+    while player1_card.is_some() && player2_card.is_some() && turn < tenet_core::MAX_TURNS {
+        turn += 1;
+
+        // Both cards' damage is settled at the start of the turn, but a card the first strike
+        // kills doesn't get to strike back
+        let player1_damage = player1_card.unwrap().attack;
+        let player2_damage = player2_card.unwrap().attack;
+        let player1_attacker = creation1_idx as u8;
+        let player2_attacker = creation2_idx as u8;
+
+        for attacker in order {
+            if attacker == 1 {
+                if creation1_idx as u8 != player1_attacker {
+                    continue;
                 }
-            }
-        }
-
-        // Second, player 1 attacks
-        while player2_damage > 0 && player1_card.is_some() {
-            let mut player1_card_use = player1_card.unwrap();
-            if player1_card_use.health > player2_damage {
-                player1_card_use.health -= player2_damage;
-                creation1.cards[creation1_idx] = player1_card_use;
-                player1_card = Some(player1_card_use);
-                player2_damage = 0;
+                let strike = Strike {
+                    turn: turn,
+                    attacker: 1,
+                    attacker_card: player1_attacker,
+                    damage: player1_damage,
+                };
+                strike.land(
+                    &mut creation2,
+                    &mut creation2_idx,
+                    &mut player2_card,
+                    &mut game_result.timeline,
+                );
             } else {
-                player2_damage -= player1_card_use.health;
-                player1_card_use.health = 0;
-                creation1.cards[creation1_idx] = player1_card_use;
-
-                // Move to next card
-                creation1_idx += 1;
-                if creation1_idx >= creation1.cards.len() {
-                    player1_card = None;
-                } else {
-                    player1_card = Some(creation1.cards[creation1_idx]);
+                if creation2_idx as u8 != player2_attacker {
+                    continue;
                 }
+                let strike = Strike {
+                    turn: turn,
+                    attacker: 2,
+                    attacker_card: player2_attacker,
+                    damage: player2_damage,
+                };
+                strike.land(
+                    &mut creation1,
+                    &mut creation1_idx,
+                    &mut player1_card,
+                    &mut game_result.timeline,
+                );
             }
         }
     }
 
-    if player1_card.is_some(){
+    if player1_card.is_some() && player2_card.is_some() {
+        // ran out of turns
+        game_result.result = String::from(tenet_core::RESULT_DRAW);
+    } else if player1_card.is_some() {
         // player 1 wins
         game_result.winner_id = player1_id.clone();
         game_result.winner_creation_hash = creation1_hash.clone();
-        game_result.result = String::from("PLAYER1_WINS");
+        game_result.result = String::from(tenet_core::RESULT_PLAYER1_WINS);
     } else if player2_card.is_some() {
         // player 2 wins
         game_result.winner_id = player2_id.clone();
         game_result.winner_creation_hash = creation2_hash.clone();
-        game_result.result = String::from("PLAYER2_WINS");
+        game_result.result = String::from(tenet_core::RESULT_PLAYER2_WINS);
     } else {
-        // tie
-        game_result.result = String::from("TIE");
+        // both sides wiped out, which the first strike rules out, but it's no winner all the same
+        game_result.result = String::from(tenet_core::RESULT_DRAW);
     }
 
     env::commit(&game_result);
@@ -0,0 +1,150 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::Database;
+use sha2::{Digest, Sha256};
+
+use crate::errors::AppError;
+
+// Custom Modules
+use methods::{TENET_ARENA_1_ID, TENET_ARENA_1_PATH};
+
+pub const DEFAULT_ARENA_ID: &str = "tenet_arena_1";
+
+// The risc0-zkvm release receipts are proven with, keep it in step with Cargo.toml
+pub const RISC0_VERSION: &str = "0.13";
+
+// The guest method a game is proven with
+#[derive(Debug, Clone, Copy)]
+pub struct Arena {
+    // shown to players picking an arena
+    pub name: &'static str,
+    // where the build put the method, see method_path for where it's read from
+    pub path: &'static str,
+    pub id: [u32; 8],
+    // the rules version the guest reports, a result from any other version is rejected
+    pub version: u32,
+}
+
+impl Arena {
+    // Hex SHA-256 of the method ID's words, stored on the game so the result can be tied back
+    // to its arena. Unlike DefaultHasher it's the same on every Rust release.
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        for word in self.id {
+            hasher.update(word.to_le_bytes());
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    // The DefaultHasher hash games were stored with before, only good for finding them on the
+    // Rust release that made them
+    fn legacy_hash(&self) -> String {
+        let mut s = DefaultHasher::new();
+        self.id.hash(&mut s);
+        s.finish().to_string()
+    }
+}
+
+pub fn default_arena_id() -> String {
+    String::from(DEFAULT_ARENA_ID)
+}
+
+// All the arenas games can be played in, keyed by the arena id clients send
+pub fn registry() -> HashMap<&'static str, Arena> {
+    let mut arenas = HashMap::new();
+    arenas.insert(
+        DEFAULT_ARENA_ID,
+        Arena {
+            name: "Tenet Arena",
+            path: TENET_ARENA_1_PATH,
+            id: TENET_ARENA_1_ID,
+            version: 6,
+        },
+    );
+    arenas
+}
+
+pub fn get(arena_id: &str) -> Option<Arena> {
+    registry().get(arena_id).copied()
+}
+
+// Where an arena's method is read from. ARENA_PATH_<ARENA_ID>, like ARENA_PATH_TENET_ARENA_1,
+// points it somewhere other than the build directory, as when the ELF is mounted into a
+// container. Without it the path the build wrote the method to is used.
+pub fn method_path(arena_id: &str, arena: &Arena) -> String {
+    let key = format!("ARENA_PATH_{}", arena_id.to_ascii_uppercase());
+    std::env::var(key).unwrap_or_else(|_| arena.path.to_string())
+}
+
+// Every arena's method, read once at startup so battles don't go to disk
+pub type ArenaMethods = Arc<HashMap<&'static str, Arc<Vec<u8>>>>;
+
+pub fn load_methods() -> Result<ArenaMethods, String> {
+    let mut methods = HashMap::new();
+    for (arena_id, arena) in registry() {
+        let path = method_path(arena_id, &arena);
+        let method = std::fs::read(&path).map_err(|err| {
+            format!("Arena {} method at {} can't be read: {}", arena_id, path, err)
+        })?;
+        tracing::info!("Loaded arena {} method from {}", arena_id, path);
+        methods.insert(arena_id, Arc::new(method));
+    }
+    Ok(Arc::new(methods))
+}
+
+// Moves the games and cached battles of every arena off its legacy hash onto its SHA-256 one.
+// A stored hash that isn't the legacy hash of the arena's current method, because the method or
+// the Rust release changed since, is left for check_receipt to reject. Run at startup, before
+// interrupted battles are resumed.
+pub async fn rehash_arenas(db: &Database) -> Result<u64, AppError> {
+    let mut rehashed = 0;
+    for (arena_id, arena) in registry() {
+        let legacy = doc! { "arena_id": arena_id, "arena_hash": arena.legacy_hash() };
+        let rehash = doc! { "$set": { "arena_hash": arena.hash() } };
+        let games = db.collection::<Document>("game");
+        rehashed += games.update_many(legacy.clone(), rehash.clone(), None).await?.modified_count;
+        db.collection::<Document>("proof_cache").update_many(legacy, rehash, None).await?;
+    }
+
+    Ok(rehashed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // Pinned so a change to the hash shows up here rather than as every unfinished game failing
+    // its arena check
+    #[test]
+    fn arena_hash_is_stable() {
+        let arena = Arena { id: [0; 8], ..get(DEFAULT_ARENA_ID).unwrap() };
+        assert_eq!(
+            arena.hash(),
+            "66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn games_under_the_legacy_hash_are_rehashed() {
+        let db = testing::db().await;
+        let arena = get(DEFAULT_ARENA_ID).unwrap();
+        let games = db.collection::<Document>("game");
+        let legacy = doc! { "arena_id": DEFAULT_ARENA_ID, "arena_hash": arena.legacy_hash() };
+        games.insert_one(legacy, None).await.unwrap();
+        let unknown = doc! { "arena_id": DEFAULT_ARENA_ID, "arena_hash": "1234" };
+        games.insert_one(unknown, None).await.unwrap();
+
+        assert_eq!(rehash_arenas(&db).await.unwrap(), 1);
+        let rehashed = doc! { "arena_hash": arena.hash() };
+        assert_eq!(games.count_documents(rehashed, None).await.unwrap(), 1);
+        let left = doc! { "arena_hash": "1234" };
+        assert_eq!(games.count_documents(left, None).await.unwrap(), 1);
+    }
+}
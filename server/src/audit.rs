@@ -0,0 +1,85 @@
+use serde::Serialize;
+
+// DB
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::Document;
+use mongodb::Database;
+
+// The points in a game's life that are kept in the audit log, stored as the snake_case name
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    LobbyCreated,
+    PlayerJoined,
+    CreationSubmitted,
+    BattleStarted,
+    ResultCommitted,
+    Errored,
+    ForceResolved,
+}
+
+// One entry in the "audit" collection. Unlike the tracing output these are kept, so a disputed
+// game can be pieced together after the fact.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub kind: AuditKind,
+    pub lobby_id: String,
+    pub game_id: Option<ObjectId>,
+    pub player_id: Option<String>,
+    // what the event was about, like the result committed or the error
+    pub detail: Option<String>,
+    pub at: bson::DateTime,
+}
+
+impl AuditEvent {
+    pub fn new(kind: AuditKind, lobby_id: &str) -> AuditEvent {
+        AuditEvent {
+            kind,
+            lobby_id: lobby_id.to_string(),
+            game_id: None,
+            player_id: None,
+            detail: None,
+            at: bson::DateTime::now(),
+        }
+    }
+
+    pub fn game(mut self, game_id: Option<ObjectId>) -> AuditEvent {
+        self.game_id = game_id;
+        self
+    }
+
+    pub fn player(mut self, player_id: &str) -> AuditEvent {
+        self.player_id = Some(player_id.to_string());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> AuditEvent {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+// Writes the event in the background. The game carries on whether or not the write lands, a
+// failed one is only logged.
+pub fn record(db: &Database, event: AuditEvent) {
+    let db = db.clone();
+    tokio::spawn(async move {
+        let written = match bson::to_document(&event) {
+            Ok(document) => db
+                .collection::<Document>("audit")
+                .insert_one(document, None)
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = written {
+            tracing::warn!(
+                "Failed to write {:?} audit event for lobby {}: {}",
+                event.kind,
+                event.lobby_id,
+                err
+            );
+        }
+    });
+}
@@ -0,0 +1,239 @@
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::Document;
+use mongodb::Database;
+
+use crate::errors::AppError;
+use crate::models::games;
+use crate::state::AuthConfig;
+
+const TOKEN_LENGTH: usize = 32;
+// Set by hand on a player document, nothing in the API hands it out
+const ADMIN_ROLE: &str = "admin";
+
+// What's stored in place of a token, the hex SHA-256 of it. The token itself only ever goes to
+// its player, so a copy of the player collection can't be used to act as anyone.
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+async fn player_with_token(db: &Database, token: &str) -> Result<Document, AppError> {
+    db.collection::<Document>("player")
+        .find_one(doc! { "token_hash": token_hash(token) }, None)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized(String::from("Invalid bearer token")))
+}
+
+// Swaps the tokens stored before they were hashed for their hashes, run once at startup so
+// those players keep their logins
+pub async fn hash_stored_tokens(db: &Database) -> Result<u64, AppError> {
+    let players = db.collection::<Document>("player");
+    let mut cursor = players.find(doc! { "token": { "$exists": true } }, None).await?;
+    let mut hashed = 0;
+    while cursor.advance().await? {
+        let player = cursor.deserialize_current()?;
+        let token = player.get_str("token")?;
+        players
+            .update_one(
+                doc! { "_id": player.get_object_id("_id")?, "token": token },
+                doc! {
+                    "$set": { "token_hash": token_hash(token) },
+                    "$unset": { "token": "" },
+                },
+                None,
+            )
+            .await?;
+        hashed += 1;
+    }
+
+    Ok(hashed)
+}
+
+// The player a request's bearer token belongs to, if it came with one. With auth required a
+// request without a token is turned away before the handler runs.
+pub struct AuthPlayer(pub Option<String>);
+
+impl AuthPlayer {
+    // Fails unless the token, when there is one, belongs to the player the request acts as
+    pub fn authorize(&self, player_id: &str) -> Result<(), AppError> {
+        match &self.0 {
+            Some(authenticated) if authenticated != player_id => Err(AppError::Unauthorized(
+                String::from("Token does not belong to this player"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthPlayer
+where
+    S: Send + Sync,
+    Database: FromRef<S>,
+    AuthConfig: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = AuthConfig::from_ref(state);
+
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let token = match token {
+            Some(token) => token,
+            None if config.required => {
+                return Err(AppError::Unauthorized(String::from("Missing bearer token")))
+            }
+            None => return Ok(AuthPlayer(None)),
+        };
+
+        let player = player_with_token(&Database::from_ref(state), token).await?;
+
+        Ok(AuthPlayer(Some(player.get_str("player_id")?.to_string())))
+    }
+}
+
+// A player with the admin role, for the support tools. Unlike AuthPlayer the token is always
+// required, whatever AUTH_REQUIRED says.
+pub struct AuthAdmin(pub String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthAdmin
+where
+    S: Send + Sync,
+    Database: FromRef<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| AppError::Unauthorized(String::from("Missing bearer token")))?;
+
+        let player = player_with_token(&Database::from_ref(state), token).await?;
+        if player.get_str("role") != Ok(ADMIN_ROLE) {
+            return Err(AppError::Forbidden(String::from("Admin role required")));
+        }
+
+        Ok(AuthAdmin(player.get_str("player_id")?.to_string()))
+    }
+}
+
+// Registers a new player and hands back the token it authenticates with
+pub async fn new_player(State(db): State<Database>) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("new_player called");
+
+    let player_id = ObjectId::new().to_string();
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+
+    db.collection::<Document>("player")
+        .insert_one(
+            doc! {
+                "player_id": player_id.clone(),
+                "token_hash": token_hash(&token),
+            },
+            None,
+        )
+        .await?;
+
+    let response = games::NewPlayerOutput {
+        player_id,
+        token,
+        error: String::from(""),
+    };
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use axum::response::Response;
+
+    use crate::testing;
+
+    fn bearer(token: &str) -> Parts {
+        let request = Request::builder()
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .body(())
+            .unwrap();
+        request.into_parts().0
+    }
+
+    async fn player_of(response: Response) -> (String, String) {
+        let body = testing::body_json(response).await;
+        let player_id = body["player_id"].as_str().unwrap().to_string();
+        (player_id, body["token"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn token_hash_is_the_hex_sha256() {
+        assert_eq!(
+            token_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn only_the_token_hash_is_stored() {
+        let state = testing::app_state(testing::db().await);
+        let response = new_player(State(state.db.clone())).await.unwrap().into_response();
+        let (player_id, token) = player_of(response).await;
+
+        let stored = state
+            .db
+            .collection::<Document>("player")
+            .find_one(doc! { "player_id": player_id.clone() }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!stored.contains_key("token"));
+        assert_eq!(stored.get_str("token_hash").unwrap(), token_hash(&token));
+
+        let auth = AuthPlayer::from_request_parts(&mut bearer(&token), &state).await.unwrap();
+        assert_eq!(auth.0, Some(player_id));
+        let hash_as_token = token_hash(&token);
+        let stolen = AuthPlayer::from_request_parts(&mut bearer(&hash_as_token), &state).await;
+        assert!(matches!(stolen, Err(AppError::Unauthorized(_))));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn tokens_stored_in_plain_keep_working_once_hashed() {
+        let state = testing::app_state(testing::db().await);
+        state
+            .db
+            .collection::<Document>("player")
+            .insert_one(doc! { "player_id": "p1", "token": "old_token", "role": ADMIN_ROLE }, None)
+            .await
+            .unwrap();
+
+        assert_eq!(hash_stored_tokens(&state.db).await.unwrap(), 1);
+        assert_eq!(hash_stored_tokens(&state.db).await.unwrap(), 0);
+        let admin = AuthAdmin::from_request_parts(&mut bearer("old_token"), &state).await.unwrap();
+        assert_eq!(admin.0, "p1");
+    }
+}
@@ -1,2 +1,8 @@
+pub mod admin;
+pub mod arenas;
+pub mod decks;
 pub mod games;
-pub mod players;
\ No newline at end of file
+pub mod health;
+pub mod npcs;
+pub mod players;
+pub mod series;
@@ -0,0 +1,263 @@
+use axum::{extract::Path, extract::State, http::StatusCode, response::IntoResponse, Json};
+use rand::{distributions::Alphanumeric, Rng};
+
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::Document;
+
+use crate::audit::{self, AuditEvent, AuditKind};
+use crate::auth::AuthAdmin;
+use crate::controllers::games::read_game;
+use crate::controllers::series;
+use crate::errors::AppError;
+use crate::extract::AppJson;
+use crate::maintenance;
+use crate::models::games::{self, ForcedOutcome, GameState};
+use crate::notifications::GameEvent;
+use crate::ratings;
+use crate::state::AppState;
+
+const WEBHOOK_SECRET_LENGTH: usize = 32;
+
+// Break glass for a game automated recovery couldn't get out of "playing": moves it to "error"
+// or gives it a winner, and records who did it and why. A game that isn't "playing" is only
+// touched with force set. Forcing a game that already ended changes its outcome but leaves the
+// ratings and series it already counted towards alone.
+pub async fn force_resolve(
+    State(state): State<AppState>,
+    admin: AuthAdmin,
+    AppJson(payload): AppJson<games::ForceResolveInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("force_resolve called");
+
+    let mut response = games::ForceResolveOutput {
+        lobby_id: String::from(""),
+        state: String::from(""),
+        error: String::from(""),
+    };
+
+    let game_id = match ObjectId::parse_str(&payload.game_id) {
+        Ok(game_id) => game_id,
+        Err(_) => {
+            response.error = String::from("Invalid game id");
+            return Ok((StatusCode::BAD_REQUEST, Json(response)));
+        }
+    };
+
+    let games_ref = state.db.collection::<Document>("game");
+    let game = games_ref.find_one(doc! { "_id": game_id }, None).await?;
+    let game = match game {
+        Some(game) => read_game(&game)?,
+        None => {
+            response.error = String::from("Game does not exist");
+            return Ok((StatusCode::NOT_FOUND, Json(response)));
+        }
+    };
+    response.lobby_id = game.lobby_id.clone();
+
+    if game.state != GameState::Playing && !payload.force {
+        response.error = format!("Game is {}, set force to resolve it anyway", game.state);
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+    let ended = !game.state.is_turn() && game.state != GameState::Playing;
+
+    // a battle still queued here is dropped, one being proven loses the race to commit below
+    state.proof_queue.cancel(game_id);
+
+    let winner = match payload.outcome {
+        ForcedOutcome::Error => None,
+        ForcedOutcome::Player1Wins => Some((
+            &game.player1_id,
+            &game.player2_id,
+            &game.creation1_hash,
+            &game.creation2_hash,
+            tenet_core::RESULT_PLAYER1_WINS,
+        )),
+        ForcedOutcome::Player2Wins => Some((
+            &game.player2_id,
+            &game.player1_id,
+            &game.creation2_hash,
+            &game.creation1_hash,
+            tenet_core::RESULT_PLAYER2_WINS,
+        )),
+    };
+    let (new_state, mut resolved) = match winner {
+        None => (
+            GameState::Error,
+            doc! {
+                "state": "error",
+                "error": format!("Resolved by an admin: {}", payload.reason),
+            },
+        ),
+        Some((winner_id, _, winner_creation_hash, loser_creation_hash, result)) => (
+            GameState::Complete,
+            doc! {
+                "state": "complete",
+                "result": result,
+                "winner_id": winner_id.clone(),
+                "winner_creation_hash": winner_creation_hash.clone(),
+                "loser_creation_hash": loser_creation_hash.clone(),
+            },
+        ),
+    };
+    resolved.insert("resolved_by", admin.0.clone());
+    resolved.insert("updated_at", bson::DateTime::now());
+
+    // guarded on the state we read, so a result that landed in the meantime isn't overwritten
+    // without the admin seeing it first
+    let update_result = games_ref
+        .update_one(
+            doc! {
+                "_id": game_id,
+                "state": game.state,
+            },
+            doc! {
+                "$set": resolved,
+                "$unset": { "creation1": "", "creation2": "", "progress": "" }
+            },
+            None,
+        )
+        .await?;
+    if update_result.modified_count != 1 {
+        response.error = String::from("Game was updated by another request");
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    tracing::warn!(
+        "Admin {} resolved lobby {} from {} as {:?}: {}",
+        admin.0,
+        game.lobby_id,
+        game.state,
+        payload.outcome,
+        payload.reason
+    );
+    let detail = format!("{} as {:?}: {}", game.state, payload.outcome, payload.reason);
+    audit::record(
+        &state.db,
+        AuditEvent::new(AuditKind::ForceResolved, &game.lobby_id)
+            .game(Some(game_id))
+            .player(&admin.0)
+            .detail(detail),
+    );
+
+    state.notifier.publish(GameEvent {
+        lobby_id: game.lobby_id.clone(),
+        state: new_state.to_string(),
+        winner_id: winner.map(|(winner_id, ..)| winner_id.clone()),
+    });
+
+    if let (Some((winner_id, loser_id, ..)), false) = (winner, ended) {
+        if game.rated {
+            ratings::apply_result(&state.db, winner_id, loser_id).await?;
+        }
+        if let Some(series_id) = &game.series_id {
+            series::record_game(&state.db, series_id, &game.lobby_id, Some(winner_id.as_str()))
+                .await?;
+        }
+    }
+
+    response.state = new_state.to_string();
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Registers a URL to be sent every completed game, signed with a secret made for it. The
+// secret is only in this response, an integration that lost it registers again.
+// Pauses new battles on every server while ops work an incident, or lets them start again. The
+// proofs already running finish either way.
+pub async fn set_maintenance(
+    State(state): State<AppState>,
+    admin: AuthAdmin,
+    AppJson(payload): AppJson<games::SetMaintenanceInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("set_maintenance called");
+
+    maintenance::set(&state.db, payload.enabled, payload.reason, &admin.0).await?;
+    let current = maintenance::current(&state).await?;
+    tracing::warn!("Admin {} set maintenance to {}", admin.0, payload.enabled);
+
+    let mut response = games::SetMaintenanceOutput {
+        maintenance: None,
+        error: String::from(""),
+    };
+    // MAINTENANCE_MODE on this server outlasts turning it off
+    if current.forced && !payload.enabled {
+        response.error = String::from("Maintenance is held on by MAINTENANCE_MODE on this server");
+    }
+    response.maintenance = Some(current);
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+pub async fn register_webhook(
+    State(state): State<AppState>,
+    admin: AuthAdmin,
+    AppJson(payload): AppJson<games::RegisterWebhookInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("register_webhook called");
+
+    let mut response = games::RegisterWebhookOutput {
+        webhook_id: String::from(""),
+        secret: String::from(""),
+        error: String::from(""),
+    };
+
+    let url = match reqwest::Url::parse(&payload.url) {
+        Ok(url) if url.scheme() == "https" || url.scheme() == "http" => url,
+        _ => {
+            response.error = String::from("Webhook URL must be an http or https URL");
+            return Ok((StatusCode::BAD_REQUEST, Json(response)));
+        }
+    };
+
+    let webhook_id = ObjectId::new().to_string();
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(WEBHOOK_SECRET_LENGTH)
+        .map(char::from)
+        .collect();
+    state
+        .db
+        .collection::<Document>("webhook")
+        .insert_one(
+            doc! {
+                "webhook_id": webhook_id.clone(),
+                "url": url.to_string(),
+                "secret": secret.clone(),
+                "created_by": admin.0.clone(),
+                "created_at": bson::DateTime::now(),
+            },
+            None,
+        )
+        .await?;
+    tracing::info!("Admin {} registered webhook {} for {}", admin.0, webhook_id, url);
+
+    response.webhook_id = webhook_id;
+    response.secret = secret;
+    Ok((StatusCode::OK, Json(response)))
+}
+
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    admin: AuthAdmin,
+    Path(webhook_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("delete_webhook called");
+
+    let mut response = games::DeleteWebhookOutput {
+        error: String::from(""),
+    };
+
+    let delete_result = state
+        .db
+        .collection::<Document>("webhook")
+        .delete_one(doc! { "webhook_id": webhook_id.clone() }, None)
+        .await?;
+    if delete_result.deleted_count == 0 {
+        response.error = String::from("Webhook does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
+    tracing::info!("Admin {} deleted webhook {}", admin.0, webhook_id);
+
+    Ok((StatusCode::OK, Json(response)))
+}
@@ -0,0 +1,34 @@
+use axum::{http::StatusCode, response::IntoResponse, Json};
+
+use crate::arenas;
+use crate::errors::AppError;
+use crate::models::games;
+
+// Every arena games can be played in with the rules a creation is checked against there, so
+// clients build decks from the same rules the server holds them to
+pub async fn get_arenas() -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_arenas called");
+
+    let mut arenas: Vec<games::ArenaInfo> = arenas::registry()
+        .into_iter()
+        .map(|(arena_id, arena)| games::ArenaInfo {
+            arena_id: arena_id.to_string(),
+            name: arena.name.to_string(),
+            version: arena.version,
+            arena_hash: arena.hash(),
+            rules: games::ArenaRules {
+                cards_per_deck: tenet_core::CARDS_PER_DECK,
+                card_stat_total: tenet_core::CARD_STAT_TOTAL,
+                min_health: 1,
+            },
+        })
+        .collect();
+    arenas.sort_by(|a, b| a.arena_id.cmp(&b.arena_id));
+
+    let response = games::GetArenasOutput {
+        arenas,
+        error: String::from(""),
+    };
+
+    Ok((StatusCode::OK, Json(response)))
+}
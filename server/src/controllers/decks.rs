@@ -0,0 +1,166 @@
+use axum::{extract::Path, extract::State, http::StatusCode, response::IntoResponse, Json};
+
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::Document;
+use mongodb::options::{FindOptions, UpdateOptions};
+use mongodb::Database;
+
+use crate::auth::AuthPlayer;
+use crate::errors::AppError;
+use crate::extract::AppJson;
+use crate::models::games::{self, ErrorCode};
+use tenet_core::Deck;
+
+// How many decks a player can keep, and how long their names can be
+const MAX_SAVED_DECKS: u64 = 50;
+const MAX_DECK_NAME_LENGTH: usize = 32;
+
+// Saves a creation under a name for the player to play by its deck_id. Saving under a name the
+// player already used replaces that deck and keeps its deck_id.
+pub async fn save_deck(
+    State(db): State<Database>,
+    auth: AuthPlayer,
+    Path(player_id): Path<String>,
+    AppJson(payload): AppJson<games::SaveDeckInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("save_deck called");
+
+    auth.authorize(&player_id)?;
+
+    let mut response = games::SaveDeckOutput {
+        deck_id: String::from(""),
+        error: String::from(""),
+        error_code: None,
+    };
+
+    let name = payload.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_DECK_NAME_LENGTH {
+        response.error = format!("Deck names are 1 to {} characters", MAX_DECK_NAME_LENGTH);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    // the arena's limits are checked when the deck is played, the deck rules hold everywhere
+    if let Err(err) = tenet_core::validate_creation(&payload.creation) {
+        response.error = format!("Invalid creation: {}", err);
+        response.error_code = Some(ErrorCode::InvalidCreation);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    let decks = db.collection::<Document>("deck");
+    let this_deck = doc! { "player_id": player_id.clone(), "name": name.clone() };
+    let is_new = decks.count_documents(this_deck.clone(), None).await? == 0;
+    if is_new
+        && decks.count_documents(doc! { "player_id": player_id.clone() }, None).await?
+            >= MAX_SAVED_DECKS
+    {
+        response.error = format!("You already have {} saved decks", MAX_SAVED_DECKS);
+        response.error_code = Some(ErrorCode::TooManyDecks);
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    let now = bson::DateTime::now();
+    let upsert = UpdateOptions::builder().upsert(true).build();
+    decks
+        .update_one(
+            this_deck.clone(),
+            doc! {
+                "$set": {
+                    "creation": bson::to_bson(&payload.creation)?,
+                    "updated_at": now,
+                },
+                "$setOnInsert": {
+                    "deck_id": ObjectId::new().to_string(),
+                    "created_at": now,
+                },
+            },
+            upsert,
+        )
+        .await?;
+
+    let saved = decks
+        .find_one(this_deck, None)
+        .await?
+        .ok_or_else(|| AppError::Internal(String::from("Saved deck is gone")))?;
+    response.deck_id = saved.get_str("deck_id")?.to_string();
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// The player's saved decks, most recently saved first
+pub async fn get_decks(
+    State(db): State<Database>,
+    auth: AuthPlayer,
+    Path(player_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_decks called");
+
+    auth.authorize(&player_id)?;
+
+    let newest_first = FindOptions::builder().sort(doc! { "updated_at": -1 }).build();
+    let mut cursor = db
+        .collection::<Document>("deck")
+        .find(doc! { "player_id": player_id }, newest_first)
+        .await?;
+    let mut decks = Vec::new();
+    while cursor.advance().await? {
+        let deck = bson::to_bson(&cursor.deserialize_current()?)?;
+        match bson::from_bson::<games::SavedDeck>(deck) {
+            Ok(deck) => decks.push(deck),
+            Err(err) => tracing::warn!("Skipping malformed deck document: {}", err),
+        }
+    }
+
+    let response = games::GetDecksOutput {
+        decks,
+        error: String::from(""),
+    };
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// The creation a play request is for: the one it sent, or the saved deck it named. Only the
+// player's own decks can be played, and a request has to pick exactly one of the two.
+pub async fn resolve(
+    db: &Database,
+    player_id: &str,
+    creation: Option<Deck>,
+    deck_id: &Option<String>,
+) -> Result<Result<Deck, (StatusCode, ErrorCode, String)>, AppError> {
+    let deck_id = match (creation, deck_id) {
+        (Some(creation), None) => return Ok(Ok(creation)),
+        (None, Some(deck_id)) => deck_id,
+        (Some(_), Some(_)) | (None, None) => {
+            return Ok(Err((
+                StatusCode::BAD_REQUEST,
+                ErrorCode::MissingCreation,
+                String::from("Send either a creation or a deck_id"),
+            )))
+        }
+    };
+
+    let deck = db
+        .collection::<Document>("deck")
+        .find_one(doc! { "deck_id": deck_id.clone() }, None)
+        .await?;
+    let deck = match deck {
+        Some(deck) => bson::from_bson::<games::SavedDeck>(bson::to_bson(&deck)?)?,
+        None => {
+            return Ok(Err((
+                StatusCode::NOT_FOUND,
+                ErrorCode::DeckNotFound,
+                String::from("Deck does not exist"),
+            )))
+        }
+    };
+    if deck.player_id != player_id {
+        return Ok(Err((
+            StatusCode::FORBIDDEN,
+            ErrorCode::DeckNotOwned,
+            String::from("Deck belongs to another player"),
+        )));
+    }
+
+    Ok(Ok(deck.creation))
+}
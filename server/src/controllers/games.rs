@@ -1,593 +1,5318 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
-use std::collections::HashMap;
+use axum::{extract::Path, extract::Query, extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::HeaderMap;
+use axum::response::Response;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use rand::{distributions::Alphanumeric, Rng};
 use tokio;
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::Instrument;
 
 // DB
 use mongodb::bson::doc;
+use mongodb::bson::Bson;
+use mongodb::bson::oid::ObjectId;
 use mongodb::bson::Document;
-use mongodb::{Collection, Database};
+use mongodb::options::{FindOneOptions, FindOptions};
+use mongodb::Database;
 
 // ZK VM
 use risc0_zkvm::serde::{from_slice, to_vec};
 use risc0_zkvm::Prover;
 
 // Custom Modules
-use methods::{TENET_ARENA_1_ID, TENET_ARENA_1_PATH};
-
+use crate::arenas;
+use crate::audit::{self, AuditEvent, AuditKind};
+use crate::auth::AuthPlayer;
+use crate::creations;
+use crate::controllers::decks;
+use crate::db;
+use crate::errors::{with_backoff, AppError, BattleError};
+use crate::extract::AppJson;
+use crate::maintenance;
 use crate::models::games;
+use crate::models::games::{ErrorCode, GameState};
+use crate::ratings;
+use crate::notifications::{GameEvent, Notifier};
+use crate::controllers::series;
+use crate::proof_cache;
+use crate::state::{AppState, MatchmakingConfig, ProofMode};
+use crate::timelines;
+use crate::webhooks;
+use tenet_core::{Deck, GuestError};
+
+// Page size bounds for the public games listing
+const DEFAULT_GAMES_LIMIT: i64 = 20;
+const MAX_GAMES_LIMIT: i64 = 100;
+
+// Fewest battles a deck needs before it shows up in the deck stats
+const DEFAULT_DECK_MIN_GAMES: i64 = 10;
+
+// Most NPCs a single gauntlet can take on, each one is a proof
+const MAX_GAUNTLET_NPCS: usize = 10;
 
-pub async fn get_all_games(State(db): State<Database>) -> impl IntoResponse {
+const NONCE_LENGTH: usize = 32;
+
+// First wait before a proof that failed on the host is tried again, doubling every try
+const PROOF_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+// Private lobby join codes, and how many to draw before giving up on finding an unused one
+const JOIN_CODE_LENGTH: usize = 6;
+const JOIN_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const JOIN_CODE_ATTEMPTS: usize = 5;
+
+// The feed only changes when a game completes, so it's served with an ETag of the page and a
+// poll with a matching If-None-Match gets an empty 304
+pub async fn get_all_games(
+    State(db): State<Database>,
+    headers: HeaderMap,
+    Query(query): Query<games::GetGamesQuery>,
+) -> Result<Response, AppError> {
     tracing::info!("get_all_games called");
 
+    let limit = query.limit.unwrap_or(DEFAULT_GAMES_LIMIT).clamp(1, MAX_GAMES_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+
+    // get all games that have state complete, optionally only the ones a player was in
+    let mut filter = doc! {
+        "state": "complete"
+    };
+    if let Some(player_id) = query.player_id {
+        filter.insert(
+            "$or",
+            vec![
+                doc! { "player1_id": player_id.clone() },
+                doc! { "player2_id": player_id },
+            ],
+        );
+    }
+    if query.exclude_npc_games {
+        filter.insert("is_npc_game", doc! { "$ne": true });
+    }
+
     let games = db.collection::<Document>("game");
-    // get all games that have state complete
-    let mut cursor = games
-        .find(
-            doc! {
-                "state": "complete"
-            },
-            None,
-        )
-        .await
-        .unwrap();
+    let total = games.count_documents(filter.clone(), None).await?;
+    let newest_first = FindOptions::builder()
+        .sort(doc! { "_id": -1 })
+        .skip(offset)
+        .limit(limit)
+        .build();
+    let mut cursor = games.find(filter, newest_first).await?;
     let mut games: Vec<games::Game> = Vec::new();
 
-    // go through each document
-    while cursor.advance().await.unwrap() {
-        let game = bson::to_bson(&cursor.deserialize_current().unwrap()).unwrap();
-        let mut game = bson::from_bson::<games::Game>(game).unwrap();
+    // a document that no longer matches the Game model fails the page with a 500 rather than
+    // quietly leaving the game out
+    while cursor.advance().await? {
+        let mut game = read_game(&cursor.deserialize_current()?)?;
         game.id = None;
         game.creation1 = None;
         game.creation2 = None;
         game.lobby_id = String::from("");
+        // the timeline is only served for a single game
+        game.timeline = Vec::new();
+        game.journal = None;
         games.push(game);
     }
 
+    let next_offset = offset + limit as u64;
     let response = games::GetGamesOutput {
-        games: games,
+        games,
+        total,
+        next_offset: if next_offset < total { Some(next_offset) } else { None },
         error: String::from(""),
+        error_code: None,
     };
 
-    (StatusCode::OK, Json(response))
+    let body = serde_json::to_vec(&response)
+        .map_err(|err| AppError::Internal(format!("Failed to serialize games: {}", err)))?;
+    let etag = etag(&body);
+    // clients keep the page but check it's still current every time
+    let cache_control = String::from("no-cache");
+    if matches_etag(&headers, &etag) {
+        let headers = [(ETAG, etag), (CACHE_CONTROL, cache_control)];
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    let headers = [
+        (ETAG, etag),
+        (CACHE_CONTROL, cache_control),
+        (CONTENT_TYPE, String::from("application/json")),
+    ];
+    Ok((StatusCode::OK, headers, body).into_response())
 }
 
-pub async fn join_game(
-    // this argument tells axum to parse the request body
+// Battles being proven right now with their lobby and player ids, latest started first, for a
+// lobby browser to offer up for spectating. Progress says whether each one is still queued. The
+// creations stay hidden until the game is over.
+pub async fn get_live_games(
     State(db): State<Database>,
-    Json(payload): Json<games::JoinGameInput>,
-) -> impl IntoResponse {
-    tracing::info!("join_game called");
+    Query(query): Query<games::GetGamesQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_live_games called");
 
-    let mut response = games::JoinGameOutput {
-        lobby_id: String::from(""),
-        error: String::from(""),
+    let limit = query.limit.unwrap_or(DEFAULT_GAMES_LIMIT).clamp(1, MAX_GAMES_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+
+    let mut filter = doc! {
+        "state": games::GameState::Playing,
     };
-    let lobbies = db.collection::<Document>("lobby");
+    if let Some(player_id) = query.player_id {
+        filter.insert(
+            "$or",
+            vec![
+                doc! { "player1_id": player_id.clone() },
+                doc! { "player2_id": player_id },
+            ],
+        );
+    }
+    if query.exclude_npc_games {
+        filter.insert("is_npc_game", doc! { "$ne": true });
+    }
 
-    let player_id: String = payload.player_id;
-    let lobby_id: String = payload.lobby_id;
-    if lobby_id.is_empty() {
-        // check for existing open lobbies
-        let open_lobby = lobbies
-            .find_one(
-                doc! {
-                    "player2_id": null,
-                    "player1_id": {
-                        "$ne": player_id.clone()
-                    }
-                },
-                None,
-            )
-            .await
-            .unwrap();
-        if !payload.create_new && open_lobby.is_some() {
-            // join the lobby
-            let lobby = open_lobby.unwrap();
-            let lobby_id = lobby.get("_id").unwrap().as_object_id().unwrap();
-            let update_result = lobbies
-                .update_one(
-                    doc! {
-                        "_id": lobby_id,
-                    },
-                    doc! {
-                        "$set": { "player2_id": player_id }
-                    },
-                    None,
-                )
-                .await
-                .unwrap();
+    let games = db.collection::<Document>("game");
+    let total = games.count_documents(filter.clone(), None).await?;
+    // games left playing from before started_at was kept have none, so they come last
+    let latest_first = FindOptions::builder()
+        .sort(doc! { "started_at": -1, "created_at": -1, "_id": -1 })
+        .skip(offset)
+        .limit(limit)
+        .build();
+    let mut cursor = games.find(filter, latest_first).await?;
+    let mut games: Vec<games::Game> = Vec::new();
 
-            response.lobby_id = lobby_id.to_string();
-        } else {
-            // if no open lobbies, create a new one
-            let new_lobby = doc! {
-                "lobby_id": null,
-                "player1_id": player_id,
-                "player2_id": null,
-            };
-            let insert_result = lobbies.insert_one(new_lobby.clone(), None).await.unwrap();
-            let newlobby_id = insert_result.inserted_id.as_object_id().unwrap();
+    while cursor.advance().await? {
+        let mut game = read_game(&cursor.deserialize_current()?)?;
+        game.id = None;
+        game.creation1 = None;
+        game.creation2 = None;
+        game.timeline = Vec::new();
+        game.journal = None;
+        games.push(game);
+    }
 
-            let update_result = lobbies
-                .update_one(
-                    doc! {
-                        "_id": newlobby_id,
-                    },
-                    doc! {
-                        "$set": { "lobby_id": newlobby_id.to_string() }
-                    },
-                    None,
-                )
-                .await
-                .unwrap();
+    let next_offset = offset + limit as u64;
+    let response = games::GetGamesOutput {
+        games,
+        total,
+        next_offset: if next_offset < total { Some(next_offset) } else { None },
+        error: String::from(""),
+        error_code: None,
+    };
 
-            response.lobby_id = newlobby_id.to_string();
-        }
-    } else {
-        // join this specific lobby, fail if already full
-        let update_result = lobbies
-            .update_one(
-                doc! {
-                    "lobby_id": lobby_id.clone(),
-                    "player1_id": {
-                        "$ne": player_id.clone()
-                    },
-                    "player2_id": null,
-                },
-                doc! {
-                    "$set": { "player2_id": player_id }
-                },
-                None,
-            )
-            .await
-            .unwrap();
-        if update_result.modified_count == 1 {
-            response.lobby_id = lobby_id;
-        } else {
-            // TODO: Separate is full vs does not exist vs already in it
-            response.error = String::from("Lobby is full or does not exist");
-            return (StatusCode::BAD_REQUEST, Json(response));
-        }
-    }
+    Ok((StatusCode::OK, Json(response)))
+}
 
-    return (StatusCode::OK, Json(response));
+// A strong ETag over the serialized body, a new completion on the page changes it
+fn etag(body: &[u8]) -> String {
+    let mut s = DefaultHasher::new();
+    body.hash(&mut s);
+    format!("\"{:016x}\"", s.finish())
 }
 
-async fn commence_battle(game: &games::Game) -> risc0_zkvm::Receipt {
-    // start the battle with both user inputs
-    let arena_src = std::fs::read(TENET_ARENA_1_PATH)
-    .expect("Method code should be present at the specified path; did you use the correct *_PATH constant?");
+// Whether the client already holds this version, If-None-Match can list several or be "*"
+fn matches_etag(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
 
-    let prover_opts = risc0_zkvm::ProverOpts::default().with_skip_seal(true);
-    let mut prover = Prover::new_with_opts(&arena_src, TENET_ARENA_1_ID, prover_opts).expect(
-        "Prover should be constructed from valid method source code and corresponding method ID",
-    );
+// How often each deck won and lost its battles across finished games. Games that ended without
+// a battle, by a concede or a lapsed turn, say nothing about the decks and aren't counted.
+pub async fn get_deck_stats(
+    State(db): State<Database>,
+    Query(query): Query<games::DeckStatsQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_deck_stats called");
 
-    // Next we send a & b to the guest
-    prover.add_input_u32_slice(&to_vec(&game.player1_id).unwrap().as_slice());
-    prover.add_input_u32_slice(&to_vec(&game.creation1.unwrap()).unwrap().as_slice());
-    prover.add_input_u32_slice(&to_vec(&game.player2_id).unwrap().as_slice());
-    prover.add_input_u32_slice(&to_vec(&game.creation2.unwrap()).unwrap().as_slice());
+    let limit = query.limit.unwrap_or(DEFAULT_GAMES_LIMIT).clamp(1, MAX_GAMES_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+    let min_games = query.min_games.unwrap_or(DEFAULT_DECK_MIN_GAMES).max(1);
 
-    tracing::info!("Starting proof");
+    let mut filter = doc! {
+        "state": "complete",
+        "creation1_deck_hash": { "$exists": true },
+        "creation2_deck_hash": { "$exists": true },
+    };
+    if let Some(arena_id) = query.arena_id {
+        filter.insert("arena_id", arena_id);
+    }
 
-    // Run prover & generate receipt
-    let receipt = prover.run()
-    .expect("Valid code should be provable if it doesn't overflow the cycle limit. See `embed_methods_with_options` for information on adjusting maximum cycle count.");
+    // one entry per side of every battle, then tallied per deck
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! {
+            "$project": {
+                "sides": [
+                    {
+                        "deck_hash": "$creation1_deck_hash",
+                        "won": { "$eq": ["$result", "PLAYER1_WINS"] },
+                        "lost": { "$eq": ["$result", "PLAYER2_WINS"] },
+                    },
+                    {
+                        "deck_hash": "$creation2_deck_hash",
+                        "won": { "$eq": ["$result", "PLAYER2_WINS"] },
+                        "lost": { "$eq": ["$result", "PLAYER1_WINS"] },
+                    },
+                ],
+            }
+        },
+        doc! { "$unwind": "$sides" },
+        doc! {
+            "$group": {
+                "_id": "$sides.deck_hash",
+                "games": { "$sum": 1 },
+                "wins": { "$sum": { "$cond": ["$sides.won", 1, 0] } },
+                "losses": { "$sum": { "$cond": ["$sides.lost", 1, 0] } },
+            }
+        },
+        doc! { "$match": { "games": { "$gte": min_games } } },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "deck_hash": "$_id",
+                "games": 1,
+                "wins": 1,
+                "losses": 1,
+                "win_rate": { "$divide": ["$wins", "$games"] },
+            }
+        },
+        doc! { "$sort": { "win_rate": -1, "games": -1, "deck_hash": 1 } },
+        doc! {
+            "$facet": {
+                "total": [{ "$count": "count" }],
+                "decks": [{ "$skip": offset as i64 }, { "$limit": limit }],
+            }
+        },
+    ];
 
-    tracing::info!("Proof done!");
+    let games_ref = db.collection::<Document>("game");
+    let mut cursor = games_ref.aggregate(pipeline, None).await?;
+    let page = if cursor.advance().await? {
+        cursor.deserialize_current()?
+    } else {
+        Document::new()
+    };
 
-    return receipt;
-}
+    let total = page
+        .get_array("total")
+        .ok()
+        .and_then(|total| total.first())
+        .and_then(|total| total.as_document())
+        .and_then(|total| total.get_i32("count").ok())
+        .unwrap_or(0) as u64;
+    let mut decks = Vec::new();
+    if let Ok(entries) = page.get_array("decks") {
+        for entry in entries {
+            decks.push(bson::from_bson::<games::DeckStatsEntry>(entry.clone())?);
+        }
+    }
 
-async fn commit_game_result(
-    games_ref: Collection<Document>,
-    game: &games::Game,
-    receipt: &risc0_zkvm::Receipt,
-) {
-    // Verify receipt
-    // HACK: Verification turned off, since seal is skipped for performance reasons
-    // receipt
-    //     .verify(&TENET_ARENA_1_ID)
-    //     .expect("Receipt should be valid for the given method ID");
+    let next_offset = offset + limit as u64;
+    let response = games::DeckStatsOutput {
+        decks,
+        total,
+        next_offset: if next_offset < total { Some(next_offset) } else { None },
+        error: String::from(""),
+    };
 
-    // battle has finished update the game document
-    // remove the user creations and add the battle result
-    let vec = &receipt.journal;
-    let game_result: tenet_core::GameResult = from_slice(vec).unwrap();
+    Ok((StatusCode::OK, Json(response)))
+}
 
+pub async fn get_game(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_game called");
 
-    // Sanity check
-    assert!(*game.creation1_hash.as_ref().unwrap() == game_result.creation1_hash);
-    assert!(*game.creation2_hash.as_ref().unwrap() == game_result.creation2_hash);
+    let db = state.db.clone();
+    let mut response = games::GetGameOutput {
+        game: None,
+        queue_position: None,
+        estimated_wait_secs: None,
+        error: String::from(""),
+    };
 
-    if !game_result.error.is_empty() {
-        let update_result = games_ref
-        .update_one(
-            doc! {
-                "_id": game.id,
-            },
+    let games = db.collection::<Document>("game");
+    let game = games
+        .find_one(
             doc! {
-                "$set": doc! {
-                    "state": "error",
-                    "error": game_result.error.clone()
-                },
+                "lobby_id": lobby_id.clone(),
             },
             None,
         )
-        .await
-        .unwrap();
-    } else {
-        let mut new_game_doc = doc! {
-            "winner_creation_hash": null,
-            "winner_id": null,
-            "result": game_result.result.clone(),
-            "state": "complete"
-        };
-
-        if !game_result.winner_creation_hash.is_empty() {
-            new_game_doc.insert(
-                "winner_creation_hash",
-                game_result.winner_creation_hash.clone(),
-            );
-            new_game_doc.insert("winner_id", game_result.winner_id.clone());
-        }
+        .await?;
 
-        let update_result = games_ref
-            .update_one(
-                doc! {
-                    "_id": game.id,
-                },
-                doc! {
-                    "$set": new_game_doc,
-                    "$unset": { "creation1": "", "creation2": "" }
-                },
-                None,
-            )
-            .await
-            .unwrap();
+    if game.is_none() {
+        response.error = String::from("Game does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
 
+    let mut game = game.unwrap();
+    timelines::inflate(&mut game)?;
+    let mut game = read_game(&game)?;
+    // each proof slot works down the line, so one in `slots` waits a proof longer per place
+    let queued = game.state == GameState::Playing && game.progress.as_deref() == Some("queued");
+    if let Some(position) = game.id.filter(|_| queued).and_then(|id| state.proof_queue.position(id)) {
+        let slots = state.proof_concurrency;
+        response.queue_position = Some(position);
+        response.estimated_wait_secs = state.proof_durations.average().map(|average| {
+            let rounds = position.div_ceil(slots);
+            (average * rounds as u32).as_secs()
+        });
+    }
+    game.id = None;
+    // creations stay hidden until the battle result is committed
+    if game.state != GameState::Complete {
+        game.creation1 = None;
+        game.creation2 = None;
     }
 
+    response.game = Some(game);
+
+    Ok((StatusCode::OK, Json(response)))
 }
 
-// TODO: Which hash function to use?
-pub async fn play_game(
-    // this argument tells axum to parse the request body
+// Replays a finished battle turn by turn for anyone watching. Games that are still going can't
+// be watched, their creations aren't committed yet.
+pub async fn spectate_game(
     State(db): State<Database>,
-    Json(payload): Json<games::PlayGameInput>,
-) -> impl IntoResponse {
-    tracing::info!("play_game called");
+    Path(lobby_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("spectate_game called");
 
-    let lobby_id = payload.lobby_id;
-    let mut response = games::PlayGameOutput {
+    let mut response = games::SpectateOutput {
+        lobby_id: lobby_id.clone(),
+        player1_id: String::from(""),
+        player2_id: String::from(""),
+        result: None,
+        winner_id: None,
+        timeline: Vec::new(),
         error: String::from(""),
     };
 
-    // check if lobby exists
-    let lobbies = db.collection::<Document>("lobby");
-    let lobby = lobbies
+    let game = db
+        .collection::<Document>("game")
         .find_one(
             doc! {
-                "lobby_id": lobby_id.clone(),
+                "lobby_id": lobby_id,
             },
             None,
         )
-        .await
-        .unwrap();
-    if lobby.is_none() {
-        response.error = String::from("Lobby does not exist");
-        return (StatusCode::BAD_REQUEST, Json(response));
+        .await?;
+    if game.is_none() {
+        response.error = String::from("Game does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
     }
 
-    // lobby.unwrap()
-    let lobby = bson::to_bson(&lobby.unwrap()).unwrap();
-    let lobby = bson::from_bson::<games::Lobby>(lobby).unwrap();
-    // from_bson::<games::Lobby>(lobby);
-
-    // check if player ids exist, otherwise return
-    if lobby.player1_id.is_none() || lobby.player2_id.is_none() {
-        response.error = String::from("Lobby is not full");
-        return (StatusCode::BAD_REQUEST, Json(response));
+    let mut game = game.unwrap();
+    timelines::inflate(&mut game)?;
+    let game = read_game(&game)?;
+    if game.state != GameState::Complete {
+        response.error = String::from("Only finished games can be spectated");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
     }
 
-    let player1_id = lobby.player1_id.unwrap();
-    let player2_id = lobby.player2_id.unwrap();
-    let is_player_1 = player1_id == payload.player_id;
+    response.player1_id = game.player1_id;
+    response.player2_id = game.player2_id;
+    response.result = game.result;
+    response.winner_id = game.winner_id;
+    response.timeline = game.timeline;
 
-    if !is_player_1 && player2_id != payload.player_id {
-        response.error = String::from("Player is not in this lobby");
-        return (StatusCode::BAD_REQUEST, Json(response));
-    }
+    Ok((StatusCode::OK, Json(response)))
+}
 
-    // check if game document exists
-    let games = db.collection::<Document>("game");
-    let game = games
-        .find_one(
-            doc! {
-                "lobby_id": lobby_id.clone(),
-            },
-            None,
-        )
-        .await
-        .unwrap();
+// Lobbies still waiting on an opponent, oldest first so long waits get picked up
+pub async fn get_open_lobbies(
+    State(db): State<Database>,
+    Query(query): Query<games::OpenLobbiesQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_open_lobbies called");
 
-    if game.is_none() {
-        let arena_hash = TENET_ARENA_1_ID;
-        let mut s = DefaultHasher::new();
-        arena_hash.hash(&mut s);
-        let arena_hash = s.finish().to_string();
-
-        let creation_bson = bson::to_bson(&payload.creation).unwrap();
-
-        let creation_hash = payload.creation;
-        let mut s = DefaultHasher::new();
-        creation_hash.hash(&mut s);
-        let creation_hash = s.finish().to_string();
-
-        let mut new_game = doc! {
-            "lobby_id": lobby_id,
-            "player1_id": player1_id,
-            "player2_id": player2_id,
-            "creation1": null,
-            "creation1_hash": null,
-            "creation2": null,
-            "creation2_hash": null,
-            "arena_hash": arena_hash,
-            "winner_creation_hash": null,
-            "winner_id": null,
-            "state": null,
-            "result": null,
-            "error": null
-        };
+    let limit = query.limit.unwrap_or(DEFAULT_GAMES_LIMIT).clamp(1, MAX_GAMES_LIMIT);
+    let offset = query.offset.unwrap_or(0);
 
-        if is_player_1 {
-            new_game.insert("state", "player2Turn");
-            new_game.insert("creation1", creation_bson);
-            new_game.insert("creation1_hash", creation_hash);
-        } else {
-            new_game.insert("state", "player1Turn");
-            new_game.insert("creation2", creation_bson);
-            new_game.insert("creation2_hash", creation_hash);
-        }
+    // private lobbies are only reached by their join code, and a lobby handed to an NPC or
+    // waiting on the bot fallback isn't one a player can take
+    let mut filter = doc! {
+        "player2_id": null,
+        "lobby_id": { "$ne": null },
+        "private": { "$ne": true },
+        "bot_fallback": { "$ne": true },
+        "npc_id": null,
+    };
+    if let Some(player_id) = query.player_id {
+        filter.insert("player1_id", doc! { "$ne": player_id });
+    }
 
-        // create it
-        let insert_result = games.insert_one(new_game.clone(), None).await.unwrap();
-    } else {
-        // game exists, check if it's in the right state
-        let game_doc = game.unwrap();
-        let game_id = game_doc.get("_id").unwrap().as_object_id().unwrap();
-        let game = bson::to_bson(&game_doc).unwrap();
-        let game = bson::from_bson::<games::Game>(game).unwrap();
+    let lobbies = db.collection::<Document>("lobby");
+    let total = lobbies.count_documents(filter.clone(), None).await?;
+    let oldest_first = FindOptions::builder()
+        .sort(doc! { "created_at": 1 })
+        .skip(offset)
+        .limit(limit)
+        .build();
+    let mut cursor = lobbies.find(filter, oldest_first).await?;
+    let mut open_lobbies: Vec<games::OpenLobby> = Vec::new();
 
-        if (game.state == "player1Turn" && !is_player_1)
-            || (game.state == "player2Turn" && is_player_1)
-        {
-            response.error = String::from("It's not your turn");
-            return (StatusCode::BAD_REQUEST, Json(response));
-        }
-
-        if game.state == "playing" {
-            response.error = String::from("Game is in progress");
-            return (StatusCode::BAD_REQUEST, Json(response));
-        } else if game.state == "complete" {
-            response.error = String::from("Game is finished");
-            return (StatusCode::BAD_REQUEST, Json(response));
-        }
-
-        let creation_bson = bson::to_bson(&payload.creation).unwrap();
-        let creation_hash = payload.creation;
-        let mut s = DefaultHasher::new();
-        creation_hash.hash(&mut s);
-        let creation_hash = s.finish().to_string();
-
-        let mut new_game_doc = None;
-
-        // Check if creation exists
-        if game.state == "player1Turn" {
-            if game.creation1_hash.is_some()
-                && creation_hash == *game.creation1_hash.as_ref().unwrap()
-            {
-                // COMMENCE AUTO BATTLE
-                new_game_doc = Some(doc! {
-                    "$set": {
-                        "state": "playing",
-                    }
-                });
-                let games_ref = games.clone();
-                let game_thread = game.clone();
-                tokio::task::spawn(async move {
-                    // TODO: Catch error in proof of battle
-                    let receipt = commence_battle(&game_thread).await;
-                    commit_game_result(games_ref, &game_thread, &receipt).await;
-                });
-                // // println!("Receipt: {:?}", committed_state);
-            } else {
-                new_game_doc = Some(doc! {
-                    "$set": {
-                        "creation1": creation_bson,
-                        "creation1_hash": creation_hash,
-                        "state": "player2Turn",
-                    }
-                });
-            }
-        } else if game.state == "player2Turn" {
-            if game.creation2_hash.is_some()
-                && creation_hash == *game.creation2_hash.as_ref().unwrap()
-            {
-                // COMMENCE AUTO BATTLE
-                new_game_doc = Some(doc! {
-                    "$set": {
-                        "state": "playing",
-                    }
-                });
-                let games_ref = games.clone();
-                let game_thread = game.clone();
-                tokio::task::spawn(async move {
-                    // TODO: Catch error in proof of battle
-                    let receipt = commence_battle(&game_thread).await;
-                    commit_game_result(games_ref, &game_thread, &receipt).await;
-                });
-            } else {
-                // update game state
-                new_game_doc = Some(doc! {
-                    "$set": {
-                        "creation2": creation_bson,
-                        "creation2_hash": creation_hash,
-                        "state": "player1Turn",
-                    }
-                });
-            }
+    while cursor.advance().await? {
+        let lobby = bson::to_bson(&cursor.deserialize_current()?)?;
+        match bson::from_bson::<games::OpenLobby>(lobby) {
+            Ok(lobby) => open_lobbies.push(lobby),
+            Err(err) => tracing::warn!("Skipping malformed lobby document: {}", err),
         }
-
-        // update game state
-        let update_result = games
-            .update_one(
-                doc! {
-                    "_id": game_id,
-                },
-                new_game_doc.unwrap(),
-                None,
-            )
-            .await
-            .unwrap();
-        // Check if creation changed
     }
 
-    return (StatusCode::OK, Json(response));
+    let next_offset = offset + limit as u64;
+    let response = games::OpenLobbiesOutput {
+        lobbies: open_lobbies,
+        total,
+        next_offset: if next_offset < total { Some(next_offset) } else { None },
+        error: String::from(""),
+    };
+
+    Ok((StatusCode::OK, Json(response)))
 }
 
-pub async fn play_npc_game(
-    // this argument tells axum to parse the request body
+// A lobby's membership, for the player who opened it to poll for an opponent before committing
+// a deck. The player ids aren't given out, only whether each slot is taken.
+pub async fn get_lobby(
     State(db): State<Database>,
-    Json(payload): Json<games::PlayNPCGameInput>,
-) -> impl IntoResponse {
-    tracing::info!("play_npc_game called");
+    Path(lobby_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_lobby called");
 
-    let mut response = games::PlayNPCGameOutput {
+    let mut response = games::GetLobbyOutput {
+        lobby_id: lobby_id.clone(),
+        has_player1: false,
+        has_player2: false,
+        full: false,
+        game_started: false,
+        state: None,
         error: String::from(""),
     };
 
-    // Check if player has battled this NPC before by checking if game exists with player creation and NCP creation
-    let games = db.collection("game");
+    let lobby = db
+        .collection::<Document>("lobby")
+        .find_one(doc! { "lobby_id": lobby_id.clone() }, None)
+        .await?;
+    let lobby = match lobby {
+        Some(lobby) => bson::from_bson::<games::Lobby>(bson::to_bson(&lobby)?)?,
+        None => {
+            response.error = String::from("Lobby does not exist");
+            return Ok((StatusCode::NOT_FOUND, Json(response)));
+        }
+    };
 
-    let player_creation_hah = payload.creation;
-    let mut s = DefaultHasher::new();
-    player_creation_hah.hash(&mut s);
-    let player_creation_hash = s.finish().to_string();
+    let game = db
+        .collection::<Document>("game")
+        .find_one(doc! { "lobby_id": lobby_id }, None)
+        .await?;
 
-    let npc_creation_hash = payload.npc_creation;
-    let mut s = DefaultHasher::new();
-    npc_creation_hash.hash(&mut s);
-    let npc_creation_hash = s.finish().to_string();
+    response.has_player1 = lobby.player1_id.is_some();
+    response.has_player2 = lobby.player2_id.is_some();
+    response.full = response.has_player1 && response.has_player2;
+    response.game_started = game.is_some();
+    response.state = game.and_then(|game| game.get_str("state").ok().map(String::from));
 
-    let game = games
-        .find_one(
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// How many games the player is in that haven't ended yet, against players and NPCs
+async fn active_games(db: &Database, player_id: &str) -> Result<u64, AppError> {
+    let count = db
+        .collection::<Document>("game")
+        .count_documents(
             doc! {
-                "player1_id": payload.player_id.clone(),
-                "creation1_hash": player_creation_hash.clone(),
-                "creation2_hash": npc_creation_hash.clone(),
+                "state": { "$in": ["player1Turn", "player2Turn", "playing"] },
+                "$or": [
+                    { "player1_id": player_id },
+                    { "player2_id": player_id },
+                ],
             },
             None,
         )
-        .await
-        .unwrap();
+        .await?;
+    Ok(count)
+}
 
-    if game.is_some() {
-        // game played, return error
-        response.error = String::from("You have already played this NPC with this deck");
-        return (StatusCode::BAD_REQUEST, Json(response));
+fn too_many_games(matchmaking: &MatchmakingConfig) -> String {
+    format!(
+        "You already have {} games in progress, finish one before starting another",
+        matchmaking.max_active_games
+    )
+}
+
+// How matchmaking went for a player
+enum Matchmade {
+    Lobby(String),
+    // the lobby picked was taken before the player could join it
+    Taken,
+    // the player asked for a new lobby but already has as many open as they're allowed
+    AtLobbyCap,
+}
+
+// Puts the player in the open lobby closest to their rating, or opens one for them. A player
+// who already has the most open lobbies allowed is handed back their oldest one instead, unless
+// they asked for a new one.
+async fn matchmake(
+    db: &Database,
+    matchmaking: &MatchmakingConfig,
+    player_id: &str,
+    create_new: bool,
+) -> Result<Matchmade, AppError> {
+    let lobbies = db.collection::<Document>("lobby");
+    let rating = ratings::get_rating(db, player_id).await?;
+
+    // check for existing open lobbies, preferring creators close to our rating. The band
+    // widens the longer a lobby has been waiting so nobody waits forever.
+    let oldest_first = FindOneOptions::builder().sort(doc! { "created_at": 1 }).build();
+    let mut open_lobby = lobbies
+        .find_one(
+            doc! {
+                "player2_id": null,
+                "player1_id": {
+                    "$ne": player_id
+                },
+                "private": { "$ne": true },
+                "player1_rating": { "$exists": true },
+                "$expr": {
+                    "$lte": [
+                        { "$abs": { "$subtract": ["$player1_rating", rating] } },
+                        { "$add": [
+                            matchmaking.rating_band,
+                            { "$multiply": [
+                                matchmaking.band_growth_per_minute,
+                                { "$divide": [{ "$subtract": ["$$NOW", "$created_at"] }, 60_000] },
+                            ] },
+                        ] },
+                    ]
+                }
+            },
+            oldest_first,
+        )
+        .await?;
+    if open_lobby.is_none() {
+        // nobody in range, fall back to any open lobby
+        open_lobby = lobbies
+            .find_one(
+                doc! {
+                    "player2_id": null,
+                    "player1_id": {
+                        "$ne": player_id
+                    },
+                    "private": { "$ne": true },
+                },
+                None,
+            )
+            .await?;
+    }
+    if let Some(lobby) = open_lobby.filter(|_| !create_new) {
+        // join the lobby
+        let lobby_id = lobby.get_object_id("_id")?;
+        // guarded like the specific lobby join, nobody can take both slots of a lobby
+        let update_result = lobbies
+            .update_one(
+                doc! {
+                    "_id": lobby_id,
+                    "player1_id": {
+                        "$ne": player_id
+                    },
+                    "player2_id": null,
+                },
+                doc! {
+                    "$set": { "player2_id": player_id }
+                },
+                None,
+            )
+            .await?;
+        if update_result.modified_count != 1 {
+            return Ok(Matchmade::Taken);
+        }
+        let joined = AuditEvent::new(AuditKind::PlayerJoined, &lobby_id.to_string()).player(player_id);
+        audit::record(db, joined);
+
+        Ok(Matchmade::Lobby(lobby_id.to_string()))
+    } else {
+        let open_count = lobbies.count_documents(own_open_lobbies(player_id), None).await?;
+        if open_count < matchmaking.max_open_lobbies {
+            // if no open lobbies, create a new one
+            let new_lobby = doc! {
+                "player1_id": player_id,
+                "player1_rating": rating,
+                "player2_id": null,
+            };
+            let created = create_capped_lobby(db, matchmaking, player_id, new_lobby).await?;
+            if let Some(lobby_id) = created {
+                return Ok(Matchmade::Lobby(lobby_id));
+            }
+        }
+
+        if create_new {
+            return Ok(Matchmade::AtLobbyCap);
+        }
+        // private lobbies count towards the cap but are only handed back through their code
+        let mut own_public_lobbies = own_open_lobbies(player_id);
+        own_public_lobbies.insert("private", doc! { "$ne": true });
+        let oldest_first = FindOneOptions::builder().sort(doc! { "created_at": 1 }).build();
+        match lobbies.find_one(own_public_lobbies, oldest_first).await? {
+            Some(own_lobby) => Ok(Matchmade::Lobby(own_lobby.get_str("lobby_id")?.to_string())),
+            None => Ok(Matchmade::AtLobbyCap),
+        }
+    }
+}
+
+// The lobbies a player opened that are still waiting on an opponent. An open lobby never has a
+// game, that only starts once both players are in.
+fn own_open_lobbies(player_id: &str) -> Document {
+    doc! {
+        "player1_id": player_id,
+        "player2_id": null,
+        "lobby_id": { "$ne": null },
+    }
+}
+
+// Opens a lobby unless it takes the player over their open lobby cap. The lobbies are counted
+// again once it's in, so of two requests racing past the first count the later one takes its
+// lobby back and the cap holds.
+async fn create_capped_lobby(
+    db: &Database,
+    matchmaking: &MatchmakingConfig,
+    player_id: &str,
+    lobby: Document,
+) -> Result<Option<String>, AppError> {
+    let lobbies = db.collection::<Document>("lobby");
+    let lobby_id = create_lobby(db, lobby).await?;
+    let open_count = lobbies.count_documents(own_open_lobbies(player_id), None).await?;
+    if open_count <= matchmaking.max_open_lobbies {
+        return Ok(Some(lobby_id));
+    }
+    // an opponent may have joined it since, then it's no longer open and stays
+    let taken_back = lobbies
+        .delete_one(doc! { "lobby_id": lobby_id.clone(), "player2_id": null }, None)
+        .await?;
+    if taken_back.deleted_count == 1 {
+        tracing::info!("Lobby {} went over {}'s open lobby cap, taken back", lobby_id, player_id);
+        return Ok(None);
+    }
+    Ok(Some(lobby_id))
+}
+
+pub async fn join_game(
+    // this argument tells axum to parse the request body
+    State(db): State<Database>,
+    State(matchmaking): State<MatchmakingConfig>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::JoinGameInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("join_game called");
+
+    auth.authorize(&payload.player_id)?;
+
+    let mut response = games::JoinGameOutput {
+        lobby_id: String::from(""),
+        error: String::from(""),
+        error_code: None,
+    };
+    let lobbies = db.collection::<Document>("lobby");
+
+    let player_id: String = payload.player_id;
+    if active_games(&db, &player_id).await? >= matchmaking.max_active_games {
+        response.error = too_many_games(&matchmaking);
+        response.error_code = Some(ErrorCode::TooManyGames);
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+    if let Some(join_code) = payload.join_code {
+        return join_private_lobby(&db, &player_id, &join_code).await;
+    }
+    let lobby_id: String = payload.lobby_id;
+    if lobby_id.is_empty() {
+        match matchmake(&db, &matchmaking, &player_id, payload.create_new).await? {
+            Matchmade::Lobby(lobby_id) => {
+                // only a lobby still waiting on an opponent can fall back to an NPC
+                if payload.bot_fallback {
+                    lobbies
+                        .update_one(
+                            doc! {
+                                "lobby_id": lobby_id.clone(),
+                                "player1_id": player_id.clone(),
+                                "player2_id": null,
+                            },
+                            doc! { "$set": { "bot_fallback": true } },
+                            None,
+                        )
+                        .await?;
+                }
+                response.lobby_id = lobby_id;
+            }
+            Matchmade::Taken => {
+                response.error = String::from("Lobby was taken by another player, try again");
+                response.error_code = Some(ErrorCode::LobbyTaken);
+                return Ok((StatusCode::CONFLICT, Json(response)));
+            }
+            Matchmade::AtLobbyCap => {
+                response.error = format!(
+                    "You already have {} open lobbies, wait for an opponent in one of them",
+                    matchmaking.max_open_lobbies
+                );
+                response.error_code = Some(ErrorCode::TooManyLobbies);
+                return Ok((StatusCode::CONFLICT, Json(response)));
+            }
+        }
+    } else {
+        // join this specific lobby, fail if already full
+        let lobby = lobbies
+            .find_one(
+                doc! {
+                    "lobby_id": lobby_id.clone(),
+                },
+                None,
+            )
+            .await?;
+        if lobby.is_none() {
+            response.error = String::from("Lobby does not exist");
+            response.error_code = Some(ErrorCode::LobbyNotFound);
+            return Ok((StatusCode::NOT_FOUND, Json(response)));
+        }
+
+        let lobby = bson::to_bson(&lobby.unwrap())?;
+        let lobby = bson::from_bson::<games::Lobby>(lobby)?;
+        if lobby.player1_id.as_ref() == Some(&player_id)
+            || lobby.player2_id.as_ref() == Some(&player_id)
+        {
+            // already in it, nothing to do
+            response.lobby_id = lobby_id;
+            return Ok((StatusCode::OK, Json(response)));
+        }
+        if lobby.private {
+            response.error = String::from("Private lobbies are only joined with their join code");
+            response.error_code = Some(ErrorCode::PrivateLobby);
+            return Ok((StatusCode::FORBIDDEN, Json(response)));
+        }
+        if lobby.player2_id.is_some() {
+            response.error = String::from("Lobby is full");
+            response.error_code = Some(ErrorCode::LobbyFull);
+            return Ok((StatusCode::CONFLICT, Json(response)));
+        }
+
+        // the filter still guards on the open slot, in case someone else joined since we looked
+        let update_result = lobbies
+            .update_one(
+                doc! {
+                    "lobby_id": lobby_id.clone(),
+                    "player1_id": {
+                        "$ne": player_id.clone()
+                    },
+                    "player2_id": null,
+                },
+                doc! {
+                    "$set": { "player2_id": player_id.clone() }
+                },
+                None,
+            )
+            .await?;
+        if update_result.modified_count == 1 {
+            let joined = AuditEvent::new(AuditKind::PlayerJoined, &lobby_id).player(&player_id);
+            audit::record(&db, joined);
+            response.lobby_id = lobby_id;
+        } else {
+            response.error = String::from("Lobby is full");
+            response.error_code = Some(ErrorCode::LobbyFull);
+            return Ok((StatusCode::CONFLICT, Json(response)));
+        }
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Opens a lobby that matchmaking never hands out, for a player to invite a friend into by
+// sharing its join code. It counts towards the player's open lobby cap like any other.
+pub async fn create_private_lobby(
+    State(db): State<Database>,
+    State(matchmaking): State<MatchmakingConfig>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::CreatePrivateLobbyInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("create_private_lobby called");
+
+    auth.authorize(&payload.player_id)?;
+
+    let mut response = games::CreatePrivateLobbyOutput {
+        lobby_id: String::from(""),
+        join_code: String::from(""),
+        expires_at: None,
+        error: String::from(""),
+        error_code: None,
+    };
+    let lobbies = db.collection::<Document>("lobby");
+
+    let open_count = lobbies.count_documents(own_open_lobbies(&payload.player_id), None).await?;
+    if open_count >= matchmaking.max_open_lobbies {
+        return Ok(at_lobby_cap(&matchmaking, response));
     }
 
-    let lobbies = db.collection("lobby");
+    // codes are never reused, so an old code can't lead into somebody else's new lobby
+    let mut join_code = None;
+    for _ in 0..JOIN_CODE_ATTEMPTS {
+        let code = new_join_code();
+        if lobbies.count_documents(doc! { "join_code": code.clone() }, None).await? == 0 {
+            join_code = Some(code);
+            break;
+        }
+    }
+    let join_code = join_code
+        .ok_or_else(|| AppError::Internal(String::from("Every join code tried was taken")))?;
 
-    // Create new lobby with player and NPC
+    let ttl = chrono::Duration::from_std(matchmaking.join_code_ttl)
+        .map_err(|err| AppError::Internal(format!("Invalid join code TTL: {}", err)))?;
+    let expires_at = bson::DateTime::from_chrono(chrono::Utc::now() + ttl);
     let new_lobby = doc! {
-        "lobby_id": null,
         "player1_id": payload.player_id.clone(),
-        "player2_id": payload.npc_id.clone(),
+        "player2_id": null,
+        "private": true,
+        "join_code": join_code.clone(),
+        "join_code_expires_at": expires_at,
+    };
+    match create_capped_lobby(&db, &matchmaking, &payload.player_id, new_lobby).await? {
+        Some(lobby_id) => response.lobby_id = lobby_id,
+        None => return Ok(at_lobby_cap(&matchmaking, response)),
+    }
+    response.join_code = join_code;
+    response.expires_at = Some(expires_at);
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+fn at_lobby_cap(
+    matchmaking: &MatchmakingConfig,
+    mut response: games::CreatePrivateLobbyOutput,
+) -> (StatusCode, Json<games::CreatePrivateLobbyOutput>) {
+    response.error = format!(
+        "You already have {} open lobbies, wait for an opponent in one of them",
+        matchmaking.max_open_lobbies
+    );
+    response.error_code = Some(ErrorCode::TooManyLobbies);
+    (StatusCode::CONFLICT, Json(response))
+}
+
+// Takes the open slot of the private lobby the code belongs to. A code is good for one opponent
+// until it expires, codes are matched without regard to case.
+async fn join_private_lobby(
+    db: &Database,
+    player_id: &str,
+    join_code: &str,
+) -> Result<(StatusCode, Json<games::JoinGameOutput>), AppError> {
+    let mut response = games::JoinGameOutput {
+        lobby_id: String::from(""),
+        error: String::from(""),
+        error_code: None,
+    };
+    let lobbies = db.collection::<Document>("lobby");
+
+    let join_code = join_code.trim().to_ascii_uppercase();
+    let lobby = lobbies
+        .find_one(doc! { "private": true, "join_code": join_code.clone() }, None)
+        .await?;
+    let lobby = match lobby {
+        Some(lobby) => bson::from_bson::<games::Lobby>(bson::to_bson(&lobby)?)?,
+        None => {
+            response.error = String::from("No lobby has this join code");
+            response.error_code = Some(ErrorCode::JoinCodeNotFound);
+            return Ok((StatusCode::NOT_FOUND, Json(response)));
+        }
     };
-    let insert_result = lobbies.insert_one(new_lobby.clone(), None).await.unwrap();
-    let newlobby_id = insert_result.inserted_id.as_object_id().unwrap();
 
+    if lobby.player1_id.as_deref() == Some(player_id)
+        || lobby.player2_id.as_deref() == Some(player_id)
+    {
+        // already in it, nothing to do
+        response.lobby_id = lobby.lobby_id;
+        return Ok((StatusCode::OK, Json(response)));
+    }
+    if lobby.player2_id.is_some() {
+        response.error = String::from("Join code was already used");
+        response.error_code = Some(ErrorCode::JoinCodeUsed);
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+    let now = bson::DateTime::now();
+    if lobby.join_code_expires_at.is_none_or(|expires_at| expires_at < now) {
+        response.error = String::from("Join code has expired");
+        response.error_code = Some(ErrorCode::JoinCodeExpired);
+        return Ok((StatusCode::GONE, Json(response)));
+    }
+
+    // guarded on the open slot and the expiry, in case either changed since we looked
     let update_result = lobbies
         .update_one(
             doc! {
-                "_id": newlobby_id,
+                "lobby_id": lobby.lobby_id.clone(),
+                "player1_id": { "$ne": player_id },
+                "player2_id": null,
+                "join_code_expires_at": { "$gte": now },
             },
             doc! {
-                "$set": { "lobby_id": newlobby_id.to_string() }
+                "$set": { "player2_id": player_id }
             },
             None,
         )
-        .await
-        .unwrap();
+        .await?;
+    if update_result.modified_count != 1 {
+        response.error = String::from("Join code was already used");
+        response.error_code = Some(ErrorCode::JoinCodeUsed);
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
 
-    // create new game
+    let joined = AuditEvent::new(AuditKind::PlayerJoined, &lobby.lobby_id).player(player_id);
+    audit::record(db, joined);
+    response.lobby_id = lobby.lobby_id;
 
-    let arena_hash = TENET_ARENA_1_ID;
-    let mut s = DefaultHasher::new();
-    arena_hash.hash(&mut s);
-    let arena_hash = s.finish().to_string();
+    Ok((StatusCode::OK, Json(response)))
+}
 
-    let creation1_bson = bson::to_bson(&payload.creation).unwrap();
-    let creation2_bson = bson::to_bson(&payload.npc_creation).unwrap();
+// A short code that's easy to read out, without the letters and digits that look alike
+fn new_join_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..JOIN_CODE_LENGTH)
+        .map(|_| JOIN_CODE_ALPHABET[rng.gen_range(0..JOIN_CODE_ALPHABET.len())] as char)
+        .collect()
+}
 
-    let mut new_game = doc! {
-        "lobby_id": newlobby_id.to_string(),
-        "player1_id": payload.player_id.clone(),
-        "player2_id": payload.npc_id.clone(),
-        "creation1": creation1_bson,
-        "creation1_hash": player_creation_hash.clone(),
-        "creation2": creation2_bson,
-        "creation2_hash": npc_creation_hash.clone(),
-        "arena_hash": arena_hash,
-        "winner_creation_hash": null,
-        "winner_id": null,
-        "state": "playing",
-        "result": null
-    };
+// The receipt of a proven battle, with the number of cycles the guest ran for. Proving is CPU
+// bound for minutes, so it runs on the blocking pool where it can't stall the request handlers.
+// risc0-zkvm 0.13 proves the whole guest as one segment, there are no continuations to save
+// part way through. The stored receipt is the only checkpoint: a game resumed after a restart
+// with its receipt stored is committed without proving again, one without starts from zero.
+// TODO: persist finished segments once risc0 is upgraded to a version with continuations
+async fn commence_battle(
+    game: &games::Game,
+    arena_src: Arc<Vec<u8>>,
+    permit: OwnedSemaphorePermit,
+    proof_timeout: Duration,
+    proof_mode: ProofMode,
+) -> Result<(risc0_zkvm::Receipt, usize), BattleError> {
+    let game = game.clone();
+    prove_in_slot(permit, proof_timeout, move || prove_battle(&game, &arena_src, proof_mode)).await
+}
 
-    let insert_result = games.insert_one(new_game.clone(), None).await.unwrap();
+// Runs `prove` on the blocking pool holding the proof slot `permit`, giving up on it after
+// `proof_timeout`. A blocking thread can't be stopped, so a proof past the deadline runs on
+// until the guest's cycle limit ends it and keeps its slot until then. The game gives up on it
+// either way, but it still counts against the proofs allowed at once.
+async fn prove_in_slot<T: Send + 'static>(
+    permit: OwnedSemaphorePermit,
+    proof_timeout: Duration,
+    prove: impl FnOnce() -> Result<T, BattleError> + Send + 'static,
+) -> Result<T, BattleError> {
+    let span = tracing::Span::current();
+    let proof = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let _span = span.entered();
+        prove()
+    });
 
-    // get inserted game
-    let game_id = insert_result.inserted_id.as_object_id().unwrap();
-    let game_doc = games
-        .find_one(
+    match tokio::time::timeout(proof_timeout, proof).await {
+        Ok(Ok(proven)) => proven,
+        // the prover can still panic on malformed guest state, so make sure that ends up as an
+        // error on the game rather than a silently dead task
+        Ok(Err(join_err)) => {
+            let reason = match join_err.try_into_panic() {
+                Ok(panic) => panic
+                    .downcast_ref::<&str>()
+                    .map(|reason| reason.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("unknown panic")),
+                Err(join_err) => join_err.to_string(),
+            };
+            Err(BattleError::Panicked(reason))
+        }
+        Err(_) => Err(BattleError::TimedOut(proof_timeout)),
+    }
+}
+
+fn prove_battle(
+    game: &games::Game,
+    arena_src: &[u8],
+    proof_mode: ProofMode,
+) -> Result<(risc0_zkvm::Receipt, usize), BattleError> {
+    let _span = tracing::info_span!("prove").entered();
+    let started = Instant::now();
+
+    // start the battle with both user inputs
+    let arena = arenas::get(&game.arena_id)
+        .ok_or_else(|| BattleError::UnknownArena(game.arena_id.clone()))?;
+
+    let prover_opts = risc0_zkvm::ProverOpts::default().with_skip_seal(proof_mode.skip_seal());
+    let mut prover = Prover::new_with_opts(arena_src, arena.id, prover_opts)
+        .map_err(|err| BattleError::Prover(err.to_string()))?;
+
+    prover.add_input_u32_slice(guest_input(game)?.as_slice());
+
+    tracing::info!("Starting proof");
+
+    // Run prover & generate receipt, this fails if the guest overflows the cycle limit
+    let receipt = prover
+        .run()
+        .map_err(|err| BattleError::Prover(err.to_string()))?;
+
+    tracing::info!(
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        cycles = prover.cycles,
+        "Proof done!"
+    );
+    metrics::histogram!(
+        "proof_duration_seconds",
+        started.elapsed().as_secs_f64(),
+        "arena_id" => game.arena_id.clone()
+    );
+
+    Ok((receipt, prover.cycles))
+}
+
+// Everything the guest reads for the game, in the order it reads it: a & b, each with the nonce
+// its hash was made with, then the seed and the coin flip drawn from it. Players proving the
+// battle themselves are handed these same words.
+fn guest_input(game: &games::Game) -> Result<Vec<u32>, BattleError> {
+    let creation1 = game.creation1.as_ref().ok_or(BattleError::MissingCreation)?;
+    let creation2 = game.creation2.as_ref().ok_or(BattleError::MissingCreation)?;
+
+    let nonce1 = game.creation1_nonce.clone().unwrap_or_default();
+    let nonce2 = game.creation2_nonce.clone().unwrap_or_default();
+    // games from before seeds were stored all played with 0
+    let battle_seed = game.battle_seed.unwrap_or(0);
+
+    let mut input = Vec::new();
+    input.extend(battle_input(&game.player1_id)?);
+    input.extend(battle_input(creation1)?);
+    input.extend(battle_input(&nonce1)?);
+    input.extend(battle_input(&game.player2_id)?);
+    input.extend(battle_input(creation2)?);
+    input.extend(battle_input(&nonce2)?);
+    input.extend(battle_input(&battle_seed)?);
+    input.extend(battle_input(&tenet_core::coin_flip(battle_seed))?);
+    Ok(input)
+}
+
+fn battle_input<T: serde::Serialize>(value: &T) -> Result<Vec<u32>, BattleError> {
+    to_vec(value).map_err(|err| BattleError::Input(err.to_string()))
+}
+
+// Records how far along the battle is. The game is still "playing" while this changes, so a
+// failed write only costs the client its spinner text.
+async fn set_progress(state: &AppState, game: &games::Game, progress: &str) {
+    let update_result = state
+        .db
+        .collection::<Document>("game")
+        .update_one(
             doc! {
-                "_id": game_id,
+                "_id": game.id,
+                "state": "playing",
+            },
+            doc! {
+                "$set": {
+                    "progress": progress,
+                    "updated_at": bson::DateTime::now(),
+                },
             },
             None,
         )
-        .await
-        .unwrap()
-        .unwrap();
+        .await;
+    if let Err(err) = update_result {
+        tracing::warn!("Failed to record progress for lobby {}: {}", game.lobby_id, err);
+    }
+}
 
-    let game_id = game_doc.get("_id").unwrap().as_object_id().unwrap();
-    let game = bson::to_bson(&game_doc).unwrap();
-    let game = bson::from_bson::<games::Game>(game).unwrap();
+// Moves a game whose battle could not be proven to "error" so it doesn't stay "playing" forever
+async fn record_battle_error(
+    state: &AppState,
+    game: &games::Game,
+    battle_error: &BattleError,
+) -> Result<(), AppError> {
+    tracing::error!("Battle for lobby {} failed: {}", game.lobby_id, battle_error);
+    metrics::increment_counter!("battles_failed_total", "arena_id" => game.arena_id.clone());
+
+    // a battle that was proven twice can fail after the other run already committed its result
+    let games_ref = state.db.collection::<Document>("game");
+    let errored = doc! {
+        "$set": {
+            "state": "error",
+            "error": battle_error.to_string(),
+            "updated_at": bson::DateTime::now(),
+        },
+        "$unset": { "progress": "" },
+    };
+    let update_result = db::retry_write("record battle error", || {
+        games_ref.update_one(doc! { "_id": game.id, "state": "playing" }, errored.clone(), None)
+    })
+    .await?;
+    if update_result.modified_count != 1 {
+        tracing::info!("Lobby {} was already finalized, not recording the error", game.lobby_id);
+        return Ok(());
+    }
+    audit::record(
+        &state.db,
+        AuditEvent::new(AuditKind::Errored, &game.lobby_id)
+            .game(game.id)
+            .detail(battle_error.to_string()),
+    );
 
-    let games_ref = games.clone();
-    let game_thread = game.clone();
-    tokio::task::spawn(async move {
-        // TODO: Catch error in proof of battle
-        let receipt = commence_battle(&game_thread).await;
-        commit_game_result(games_ref, &game_thread, &receipt).await;
+    state.notifier.publish(GameEvent {
+        lobby_id: game.lobby_id.clone(),
+        state: String::from("error"),
+        winner_id: None,
     });
 
-    return (StatusCode::OK, Json(response));
+    Ok(())
 }
 
-pub async fn commit_outcome(
-    // this argument tells axum to parse the request body
-    State(db): State<Database>,
-    Json(payload): Json<games::CommitOutcomeInput>,
-) -> impl IntoResponse {
-    tracing::info!("commit_outcome called");
+// Reads a stored game document as a Game. One that no longer fits the model, say after a schema
+// change, is logged with its id and handed back as a 500 rather than taking anything down.
+pub(crate) fn read_game(game_doc: &Document) -> Result<games::Game, AppError> {
+    bson::from_bson::<games::Game>(Bson::Document(game_doc.clone())).map_err(|err| {
+        let game_id = game_doc.get_object_id("_id").map(|id| id.to_hex()).unwrap_or_default();
+        let lobby_id = game_doc.get_str("lobby_id").unwrap_or_default();
+        tracing::error!("Game {} for lobby {} is malformed: {}", game_id, lobby_id, err);
+        AppError::Internal(format!("Game {} is malformed: {}", game_id, err))
+    })
+}
+
+// Moves a game whose stored creation can't be upgraded to the current schema to "error", it
+// could never be proven
+async fn record_unmigratable(
+    state: &AppState,
+    game_id: ObjectId,
+    lobby_id: &str,
+    err: &creations::MigrationError,
+) -> Result<(), AppError> {
+    tracing::error!("Creation for lobby {} can't be upgraded: {}", lobby_id, err);
+    record_unplayable(state, game_id, lobby_id, err.to_string()).await
+}
+
+// Moves a game that can't be played on to "error" with why, unless it already ended
+async fn record_unplayable(
+    state: &AppState,
+    game_id: ObjectId,
+    lobby_id: &str,
+    error: String,
+) -> Result<(), AppError> {
+    let update_result = state
+        .db
+        .collection::<Document>("game")
+        .update_one(
+            doc! {
+                "_id": game_id,
+                "state": { "$in": ["player1Turn", "player2Turn", "playing"] },
+            },
+            doc! {
+                "$set": {
+                    "state": "error",
+                    "error": error,
+                    "updated_at": bson::DateTime::now(),
+                },
+                "$unset": { "progress": "" },
+            },
+            None,
+        )
+        .await?;
+    if update_result.modified_count == 1 {
+        state.notifier.publish(GameEvent {
+            lobby_id: lobby_id.to_string(),
+            state: String::from("error"),
+            winner_id: None,
+        });
+    }
+
+    Ok(())
+}
+
+async fn commit_game_result(
+    state: &AppState,
+    game: &games::Game,
+    receipt: &risc0_zkvm::Receipt,
+) -> Result<(), AppError> {
+    // a battle spawned twice, say by a restart resuming it while it still ran, finishes twice.
+    // Only the first result counts, the second is dropped before it's checked again.
+    let current = state
+        .db
+        .collection::<Document>("game")
+        .find_one(doc! { "_id": game.id }, None)
+        .await?;
+    let current_state = current.as_ref().and_then(|current| current.get_str("state").ok());
+    if current_state != Some(GameState::Playing.as_str()) {
+        tracing::info!("Lobby {} was already finalized, dropping the duplicate result", game.lobby_id);
+        return Ok(());
+    }
+
+    set_progress(state, game, "verifying").await;
 
-    let out = "Done";
+    let checked = async {
+        let started = Instant::now();
+        let checked = check_receipt_blocking(game, receipt, state.proof_mode.verify()).await;
+        tracing::info!(elapsed_ms = started.elapsed().as_millis() as u64, "Receipt checked");
+        checked
+    }
+    .instrument(tracing::info_span!("verify"))
+    .await;
+    // a receipt that doesn't belong to this game must not be committed
+    let game_result = match checked {
+        Ok(game_result) => game_result,
+        Err(battle_error) => return record_battle_error(state, game, &battle_error).await,
+    };
 
-    (StatusCode::OK, out)
+    set_progress(state, game, "committing").await;
+
+    let started = Instant::now();
+    let prover_host = &state.prover_host;
+    let committed = store_game_result(state, game, receipt, &game_result, &["playing"], prover_host)
+        .instrument(tracing::info_span!("commit"))
+        .await?;
+    if committed && state.proof_mode.verify_later() && receipt.seal.is_empty() {
+        spawn_verification(state.clone(), game.clone(), receipt.journal.clone());
+    }
+    tracing::info!(elapsed_ms = started.elapsed().as_millis() as u64, "Result committed");
+    metrics::histogram!(
+        "commit_duration_seconds",
+        started.elapsed().as_secs_f64(),
+        "arena_id" => game.arena_id.clone()
+    );
+
+    Ok(())
+}
+
+// Verifying a seal is CPU bound like proving, so it runs on the blocking pool as well
+async fn check_receipt_blocking(
+    game: &games::Game,
+    receipt: &risc0_zkvm::Receipt,
+    require_seal: bool,
+) -> Result<tenet_core::GameResult, BattleError> {
+    let span = tracing::Span::current();
+    let (game, receipt) = (game.clone(), receipt.clone());
+    tokio::task::spawn_blocking(move || {
+        let _span = span.entered();
+        check_receipt(&game, &receipt, require_seal)
+    })
+    .await
+    .unwrap_or_else(|join_err| Err(BattleError::Panicked(join_err.to_string())))
+}
+
+// Decodes the journal and makes sure it is a result for this game, proven by its arena. Receipts
+// from our own prover only carry a seal in prod proof mode, anyone else's must always have one.
+fn check_receipt(
+    game: &games::Game,
+    receipt: &risc0_zkvm::Receipt,
+    require_seal: bool,
+) -> Result<tenet_core::GameResult, BattleError> {
+    let arena = arenas::get(&game.arena_id)
+        .ok_or_else(|| BattleError::UnknownArena(game.arena_id.clone()))?;
+    if arena.hash() != game.arena_hash {
+        return Err(BattleError::Mismatch(format!(
+            "arena hash {} is not the hash of {}",
+            game.arena_hash, game.arena_id
+        )));
+    }
+
+    // Verify receipt
+    // Verification only runs with seals, dev proof mode skips them
+    if require_seal {
+        receipt
+            .verify(&arena.id)
+            .map_err(|err| BattleError::Verification(err.to_string()))?;
+    }
+
+    // battle has finished update the game document
+    // remove the user creations and add the battle result
+    let vec = &receipt.journal;
+    let game_result: tenet_core::GameResult =
+        from_slice(vec).map_err(|err| BattleError::Journal(err.to_string()))?;
+
+    // Sanity check
+    if game_result.arena_id != game.arena_id {
+        return Err(BattleError::Mismatch(format!(
+            "proven in arena {} instead of {}",
+            game_result.arena_id, game.arena_id
+        )));
+    }
+    // a stale method binary proves the old rules, however valid its receipt looks
+    if game_result.arena_version != arena.version {
+        return Err(BattleError::Mismatch(format!(
+            "proven with version {} of {}, expected version {}",
+            game_result.arena_version, game.arena_id, arena.version
+        )));
+    }
+    if game.creation1_hash.as_deref() != Some(game_result.creation1_hash.as_str())
+        || game.creation2_hash.as_deref() != Some(game_result.creation2_hash.as_str())
+    {
+        return Err(BattleError::Mismatch(String::from("creation hashes differ")));
+    }
+    if game_result.player1_id != game.player1_id || game_result.player2_id != game.player2_id {
+        return Err(BattleError::Mismatch(String::from("players differ")));
+    }
+    if game_result.battle_seed != game.battle_seed.unwrap_or(0) {
+        return Err(BattleError::Mismatch(String::from("battle seeds differ")));
+    }
+    if game_result.first_attacker != tenet_core::coin_flip(game_result.battle_seed) {
+        return Err(BattleError::Mismatch(String::from("coin flip doesn't match the seed")));
+    }
+    // a draw has no winner at all, any other result is won by one of the players
+    let winner_is_player =
+        game_result.winner_id == game.player1_id || game_result.winner_id == game.player2_id;
+    if game_result.error.is_none() && !game_result.is_draw() && !winner_is_player {
+        return Err(BattleError::Mismatch(String::from("winner is not one of the players")));
+    }
+    // the NPC has to have fought with the deck it had on the roster when the game started
+    if let Some(npc_deck_hash) = &game.npc_deck_hash {
+        if game.is_npc_game && game_result.creation2_hash != *npc_deck_hash {
+            return Err(BattleError::Mismatch(format!(
+                "NPC deck {} is not the roster deck {}",
+                game_result.creation2_hash, npc_deck_hash
+            )));
+        }
+    }
+
+    Ok(game_result)
+}
+
+// Writes a checked result to a game still in one of `from_states`. Only the first result for a
+// game is kept, false if another one got there first.
+async fn store_game_result(
+    state: &AppState,
+    game: &games::Game,
+    receipt: &risc0_zkvm::Receipt,
+    game_result: &tenet_core::GameResult,
+    from_states: &[&str],
+    prover_host: &str,
+) -> Result<bool, AppError> {
+    let db = &state.db;
+    let games_ref = db.collection::<Document>("game");
+    let uncommitted = doc! {
+        "_id": game.id,
+        "state": { "$in": from_states },
+    };
+
+    // only a receipt with a seal was verified before getting here. It's stored before the result
+    // so a restart in between can still commit it, and never replaced, so a duplicate can't
+    // leave another receipt behind the committed result.
+    let verified = !receipt.seal.is_empty();
+    if verified && !store_receipt(db, game, receipt, prover_host).await? {
+        tracing::info!(
+            "Lobby {} has a receipt for another result, dropping this one",
+            game.lobby_id
+        );
+        return Ok(false);
+    }
+
+    if let Some(guest_error) = game_result.error {
+        let message = guest_error_message(guest_error);
+        let errored = doc! {
+            "$set": doc! {
+                "state": "error",
+                "error": message.clone(),
+                "guest_error": bson::to_bson(&guest_error)?,
+                "updated_at": bson::DateTime::now(),
+            },
+            "$unset": { "progress": "" },
+        };
+        let update_result = db::retry_write("store errored result", || {
+            games_ref.update_one(uncommitted.clone(), errored.clone(), None)
+        })
+        .await?;
+        if update_result.modified_count != 1 {
+            tracing::info!(
+                "Lobby {} was already finalized, dropping the duplicate result",
+                game.lobby_id
+            );
+            return Ok(false);
+        }
+        audit::record(
+            db,
+            AuditEvent::new(AuditKind::Errored, &game.lobby_id)
+                .game(game.id)
+                .detail(message),
+        );
+
+        state.notifier.publish(GameEvent {
+            lobby_id: game.lobby_id.clone(),
+            state: String::from("error"),
+            winner_id: None,
+        });
+    } else {
+        // the hashes come from the journal so they're exactly what was proven. Only the
+        // creations are dropped, the hashes stay for analytics.
+        let mut new_game_doc = doc! {
+            "creation1_hash": game_result.creation1_hash.clone(),
+            "creation2_hash": game_result.creation2_hash.clone(),
+            "winner_creation_hash": null,
+            "loser_creation_hash": null,
+            "winner_id": null,
+            "result": game_result.result.clone(),
+            "state": "complete",
+            "journal": bson::to_bson(&receipt.journal)?,
+            "first_attacker": game_result.first_attacker as i32,
+            "verified": verified,
+            "updated_at": bson::DateTime::now(),
+        };
+
+        new_game_doc.extend(timelines::compressed(&game_result.timeline)?);
+
+        // the decks hashed without their nonces, so the same deck adds up across games for the
+        // deck stats. Only written now that the battle is over and the decks are no secret.
+        if let (Some(creation1), Some(creation2)) = (&game.creation1, &game.creation2) {
+            new_game_doc.insert("creation1_deck_hash", tenet_core::hash_creation(creation1, ""));
+            new_game_doc.insert("creation2_deck_hash", tenet_core::hash_creation(creation2, ""));
+        }
+
+        if !game_result.winner_creation_hash.is_empty() {
+            new_game_doc.insert(
+                "winner_creation_hash",
+                game_result.winner_creation_hash.clone(),
+            );
+            new_game_doc.insert("winner_id", game_result.winner_id.clone());
+            let loser_creation_hash = if game_result.winner_id == game.player1_id {
+                &game_result.creation2_hash
+            } else {
+                &game_result.creation1_hash
+            };
+            new_game_doc.insert("loser_creation_hash", loser_creation_hash.clone());
+        }
+
+        let completed = doc! {
+            "$set": new_game_doc,
+            "$unset": { "creation1": "", "creation2": "", "progress": "" }
+        };
+        let update_result = db::retry_write("store game result", || {
+            games_ref.update_one(uncommitted.clone(), completed.clone(), None)
+        })
+        .await?;
+        if update_result.modified_count != 1 {
+            tracing::info!(
+                "Lobby {} was already finalized, dropping the duplicate result",
+                game.lobby_id
+            );
+            return Ok(false);
+        }
+        audit::record(
+            db,
+            AuditEvent::new(AuditKind::ResultCommitted, &game.lobby_id)
+                .game(game.id)
+                .detail(game_result.result.clone()),
+        );
+
+        let winner_id =
+            Some(game_result.winner_id.clone()).filter(|winner_id| !winner_id.is_empty());
+        state.notifier.publish(GameEvent {
+            lobby_id: game.lobby_id.clone(),
+            state: String::from("complete"),
+            winner_id: winner_id.clone(),
+        });
+        let completed = webhooks::GameCompleted {
+            event: "game.completed",
+            game_id: game.id.map(|id| id.to_string()).unwrap_or_default(),
+            lobby_id: game.lobby_id.clone(),
+            arena_id: game.arena_id.clone(),
+            winner_id,
+            result: game_result.result.clone(),
+            completed_at: bson::DateTime::now(),
+        };
+        state.webhooks.game_completed(db, completed);
+
+        if game.rated {
+            let change = if game_result.is_draw() {
+                ratings::apply_draw(db, &game.player1_id, &game.player2_id).await?
+            } else {
+                let loser_id = if game_result.winner_id == game.player1_id {
+                    &game.player2_id
+                } else {
+                    &game.player1_id
+                };
+                ratings::apply_result(db, &game_result.winner_id, loser_id).await?
+            };
+            // kept so the change can be taken back if the result fails verification later
+            games_ref
+                .update_one(
+                    doc! { "_id": game.id },
+                    doc! { "$set": { "rating_change": change } },
+                    None,
+                )
+                .await?;
+        }
+
+        if let Some(series_id) = &game.series_id {
+            let winner_id =
+                Some(game_result.winner_id.as_str()).filter(|winner_id| !winner_id.is_empty());
+            series::record_game(db, series_id, &game.lobby_id, winner_id).await?;
+        }
+
+        if game.is_npc_game {
+            let winner = if game_result.winner_id.is_empty() {
+                0
+            } else if game_result.winner_id == game.player1_id {
+                1
+            } else {
+                2
+            };
+            let cached = games::CachedBattle {
+                arena_id: game.arena_id.clone(),
+                arena_hash: game.arena_hash.clone(),
+                creation1_hash: game_result.creation1_hash.clone(),
+                creation2_hash: game_result.creation2_hash.clone(),
+                game_id: game.id.ok_or_else(|| AppError::Internal(String::from("Game has no ObjectId")))?,
+                result: game_result.result.clone(),
+                winner,
+                timeline: game_result.timeline.clone(),
+                battle_seed: Some(game_result.battle_seed),
+                seed_chosen: game.seed_chosen,
+                verified,
+            };
+            proof_cache::store(db, &cached).await?;
+        }
+    }
+
+    Ok(true)
+}
+
+// What players are told when the guest couldn't play their battle out, the game's guest_error
+// says the same for clients to act on
+fn guest_error_message(guest_error: GuestError) -> String {
+    match guest_error {
+        GuestError::InvalidCreation1 => String::from("Player 1's creation breaks the deck rules"),
+        GuestError::InvalidCreation2 => String::from("Player 2's creation breaks the deck rules"),
+    }
+}
+
+// Keeps the full receipt so the result can be re-verified later, e.g. when disputed. Only the
+// first receipt for a game is kept: false if it's for another result than this one. A resumed
+// battle committing its stored receipt again finds its own.
+async fn store_receipt(
+    db: &Database,
+    game: &games::Game,
+    receipt: &risc0_zkvm::Receipt,
+    prover_host: &str,
+) -> Result<bool, AppError> {
+    let stored_receipt = games::Receipt {
+        game_id: game.id.ok_or_else(|| AppError::Internal(String::from("Game has no ObjectId")))?,
+        journal: receipt.journal.clone(),
+        seal: receipt.seal.clone(),
+        method_id: arenas::get(&game.arena_id).map(|arena| arena.id),
+        risc0_version: Some(String::from(arenas::RISC0_VERSION)),
+        prover_host: Some(String::from(prover_host)),
+    };
+    let receipts = db.collection::<Document>("receipt");
+    match receipts.insert_one(bson::to_document(&stored_receipt)?, None).await {
+        Ok(_) => Ok(true),
+        Err(err) if db::is_duplicate_key(&err) => {
+            let stored = receipts
+                .find_one(doc! { "game_id": stored_receipt.game_id }, None)
+                .await?
+                .ok_or_else(|| AppError::Internal(String::from("Stored receipt went missing")))?;
+            let stored = bson::from_bson::<games::Receipt>(bson::to_bson(&stored)?)?;
+            Ok(stored.journal == receipt.journal)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Proves an optimistically committed battle again, this time with a seal, and verifies it. The
+// committed result stands only if the sealed proof verifies and proves exactly the same journal.
+// A restart loses the pending proof, leaving the game unverified.
+fn spawn_verification(state: AppState, game: games::Game, journal: Vec<u8>) {
+    let span = tracing::info_span!(
+        "verify_seal",
+        game_id = %game.id.map(|id| id.to_string()).unwrap_or_default(),
+        lobby_id = %game.lobby_id,
+        arena_id = %game.arena_id,
+    );
+    let guard = state.battles.start();
+    tokio::task::spawn(
+        async move {
+            let _guard = guard;
+            let proven = {
+                let permit = state
+                    .proof_slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Proof slots are never closed");
+                match state.arena_methods.get(game.arena_id.as_str()) {
+                    Some(arena_src) => {
+                        let (arena_src, proof_timeout) = (arena_src.clone(), state.proof_timeout);
+                        commence_battle(&game, arena_src, permit, proof_timeout, ProofMode::Prod)
+                            .await
+                    }
+                    None => Err(BattleError::UnknownArena(game.arena_id.clone())),
+                }
+            };
+            let verified = match proven {
+                Ok((receipt, _)) => match check_receipt_blocking(&game, &receipt, true).await {
+                    Ok(_) if receipt.journal != journal => Err(BattleError::Mismatch(
+                        String::from("sealed proof has a different result than the one committed"),
+                    )),
+                    Ok(_) => Ok(receipt),
+                    Err(battle_error) => Err(battle_error),
+                },
+                Err(battle_error) => Err(battle_error),
+            };
+            let recorded = match verified {
+                Ok(receipt) => record_verified(&state, &game, &receipt).await,
+                Err(battle_error) => revert_unverified(&state, &game, &battle_error).await,
+            };
+            if let Err(err) = recorded {
+                tracing::error!(
+                    "Failed to record verification of lobby {}: {}",
+                    game.lobby_id,
+                    err
+                );
+            }
+        }
+        .instrument(span),
+    );
+}
+
+async fn record_verified(
+    state: &AppState,
+    game: &games::Game,
+    receipt: &risc0_zkvm::Receipt,
+) -> Result<(), AppError> {
+    if !store_receipt(&state.db, game, receipt, &state.prover_host).await? {
+        return Err(AppError::Internal(format!(
+            "Lobby {} already has a receipt for another result",
+            game.lobby_id
+        )));
+    }
+    state
+        .db
+        .collection::<Document>("game")
+        .update_one(
+            doc! {
+                "_id": game.id,
+                "state": "complete",
+            },
+            doc! {
+                "$set": {
+                    "verified": true,
+                    "updated_at": bson::DateTime::now(),
+                }
+            },
+            None,
+        )
+        .await?;
+    state
+        .db
+        .collection::<Document>("proof_cache")
+        .update_many(doc! { "game_id": game.id }, doc! { "$set": { "verified": true } }, None)
+        .await?;
+    // the games that reused this battle's outcome are backed by the same proof
+    state
+        .db
+        .collection::<Document>("game")
+        .update_many(
+            doc! {
+                "cached_from": game.id,
+                "state": "complete",
+            },
+            doc! {
+                "$set": {
+                    "verified": true,
+                    "updated_at": bson::DateTime::now(),
+                }
+            },
+            None,
+        )
+        .await?;
+    tracing::info!("Result for lobby {} verified", game.lobby_id);
+
+    Ok(())
+}
+
+// Takes back a result whose sealed proof failed: the game moves to "error", any rating change
+// is reverted and the battle is no longer reused for NPC games. The NPC games that already reused
+// it go the same way. A series keeps counting it.
+async fn revert_unverified(
+    state: &AppState,
+    game: &games::Game,
+    battle_error: &BattleError,
+) -> Result<(), AppError> {
+    tracing::error!("Result for lobby {} failed verification: {}", game.lobby_id, battle_error);
+    metrics::increment_counter!("verifications_failed_total", "arena_id" => game.arena_id.clone());
+
+    let reason = format!("Result failed verification: {}", battle_error);
+    if !revert_game(state, doc! { "_id": game.id }, &reason).await? {
+        tracing::info!("Lobby {} is no longer awaiting verification", game.lobby_id);
+        return Ok(());
+    }
+
+    let copies = state
+        .db
+        .collection::<Document>("game")
+        .distinct("_id", doc! { "cached_from": game.id }, None)
+        .await?;
+    for copy_id in copies {
+        revert_game(state, doc! { "_id": copy_id }, &reason).await?;
+    }
+
+    state
+        .db
+        .collection::<Document>("proof_cache")
+        .delete_many(doc! { "game_id": game.id }, None)
+        .await?;
+
+    Ok(())
+}
+
+// Moves one unverified complete game to "error" and takes back its rating change. Returns false
+// if the game had already moved on.
+async fn revert_game(state: &AppState, filter: Document, reason: &str) -> Result<bool, AppError> {
+    let mut filter = filter;
+    filter.insert("state", "complete");
+    filter.insert("verified", false);
+
+    // the document from before the update, with the rating change to take back
+    let reverted = state
+        .db
+        .collection::<Document>("game")
+        .find_one_and_update(
+            filter,
+            doc! {
+                "$set": {
+                    "state": "error",
+                    "error": reason,
+                    "updated_at": bson::DateTime::now(),
+                },
+                "$unset": { "rating_change": "" },
+            },
+            None,
+        )
+        .await?;
+    let (reverted, rating_change) = match reverted {
+        Some(reverted) => (read_game(&reverted)?, reverted.get_f64("rating_change").ok()),
+        None => return Ok(false),
+    };
+
+    // a draw's change is player 1's, so it's taken back as if they had won
+    if let Some(change) = rating_change {
+        let (winner_id, loser_id) = match &reverted.winner_id {
+            Some(winner_id) if *winner_id == reverted.player2_id => {
+                (&reverted.player2_id, &reverted.player1_id)
+            }
+            _ => (&reverted.player1_id, &reverted.player2_id),
+        };
+        ratings::revert_result(&state.db, winner_id, loser_id, change).await?;
+    }
+
+    state.notifier.publish(GameEvent {
+        lobby_id: reverted.lobby_id.clone(),
+        state: String::from("error"),
+        winner_id: None,
+    });
+
+    Ok(true)
+}
+
+// Proves the battle in the background and commits the result once it's done
+pub(crate) fn spawn_battle(state: AppState, game: games::Game) {
+    // every phase of the battle logs under this span, so proof latency can be pulled per game
+    let span = tracing::info_span!(
+        "battle",
+        game_id = %game.id.map(|id| id.to_string()).unwrap_or_default(),
+        lobby_id = %game.lobby_id,
+        arena_id = %game.arena_id,
+    );
+    metrics::increment_counter!("battles_started_total", "arena_id" => game.arena_id.clone());
+    let started = AuditEvent::new(AuditKind::BattleStarted, &game.lobby_id).game(game.id);
+    audit::record(&state.db, started);
+    let guard = state.battles.start();
+    // queued before the task runs, so a cancel straight after the submission finds it
+    let mut cancelled = state.proof_queue.enqueue(game.id);
+    tokio::task::spawn(
+        async move {
+            let _guard = guard;
+            let started = Instant::now();
+            set_progress(&state, &game, "queued").await;
+            // only the proof itself holds a slot, committing the result is cheap
+            let mut permit = tokio::select! {
+                permit = state.proof_slots.clone().acquire_owned() => {
+                    permit.expect("Proof slots are never closed")
+                }
+                Ok(()) = &mut cancelled => {
+                    tracing::info!("Battle cancelled while queued");
+                    return;
+                }
+            };
+            // a cancel that landed between the slot freeing up and here still wins
+            if !state.proof_queue.dequeue(game.id) {
+                tracing::info!("Battle cancelled while queued");
+                return;
+            }
+            let mut backoff = PROOF_RETRY_BACKOFF;
+            let proven = loop {
+                set_progress(&state, &game, "proving").await;
+                let proving = Instant::now();
+                // the proof gives its slot back once its thread is done, which is before a
+                // retry waits to try again
+                let proven = match state.arena_methods.get(game.arena_id.as_str()) {
+                    Some(arena_src) => {
+                        let (arena_src, proof_timeout) = (arena_src.clone(), state.proof_timeout);
+                        commence_battle(&game, arena_src, permit, proof_timeout, state.proof_mode)
+                            .await
+                    }
+                    None => Err(BattleError::UnknownArena(game.arena_id.clone())),
+                };
+                if proven.is_ok() {
+                    state.proof_durations.record(proving.elapsed());
+                }
+                match proven {
+                    Err(battle_error) if battle_error.is_transient() => {
+                        match count_proof_retry(&state, &game).await {
+                            Ok(true) => tracing::warn!(
+                                "Proof failed, trying again in {:?}: {}",
+                                backoff,
+                                battle_error
+                            ),
+                            Ok(false) => break Err(battle_error),
+                            Err(err) => {
+                                tracing::error!("Failed to count proof retry: {}", err);
+                                break Err(battle_error);
+                            }
+                        }
+                    }
+                    proven => break proven,
+                }
+                set_progress(&state, &game, "queued").await;
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                permit = state
+                    .proof_slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Proof slots are never closed");
+            };
+            let mut cycles = None;
+            let committed = match proven {
+                Ok((receipt, proof_cycles)) => {
+                    cycles = Some(proof_cycles);
+                    commit_game_result(&state, &game, &receipt).await
+                }
+                Err(battle_error) => record_battle_error(&state, &game, &battle_error).await,
+            };
+            if let Err(err) = &committed {
+                tracing::error!("Failed to commit game result: {}", err);
+            }
+            tracing::info!(
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                cycles,
+                committed = committed.is_ok(),
+                "Battle finished"
+            );
+        }
+        .instrument(span),
+    );
+}
+
+// Counts another try at proving the game, on the game itself so that neither this task nor the
+// stalled battle watchdog proves it past the cap. False once the game has no retries left, or
+// it moved on from "playing".
+async fn count_proof_retry(state: &AppState, game: &games::Game) -> Result<bool, AppError> {
+    let update_result = state
+        .db
+        .collection::<Document>("game")
+        .update_one(
+            doc! {
+                "_id": game.id,
+                "state": "playing",
+                "$expr": {
+                    "$lt": [{ "$ifNull": ["$proof_retries", 0] }, state.proof_max_retries]
+                },
+            },
+            doc! {
+                "$inc": { "proof_retries": 1 },
+                "$set": { "updated_at": bson::DateTime::now() },
+            },
+            None,
+        )
+        .await?;
+    Ok(update_result.modified_count == 1)
+}
+
+// Restarts the battles of games left "playing" by a shutdown that didn't wait for them. A game
+// whose receipt was already stored only needs its result committed.
+pub async fn resume_battles(state: &AppState) -> Result<usize, AppError> {
+    let games_ref = state.db.collection::<Document>("game");
+    let receipts = state.db.collection::<Document>("receipt");
+
+    let mut cursor = games_ref.find(doc! { "state": "playing" }, None).await?;
+    let mut resumed = 0;
+    while cursor.advance().await? {
+        let mut game_doc = cursor.deserialize_current()?;
+        if let Err(err) = creations::upgrade(&mut game_doc) {
+            let lobby_id = game_doc.get_str("lobby_id").unwrap_or_default().to_string();
+            record_unmigratable(state, game_doc.get_object_id("_id")?, &lobby_id, &err).await?;
+            continue;
+        }
+        let game = match read_game(&game_doc) {
+            Ok(game) => game,
+            Err(err) => {
+                let lobby_id = game_doc.get_str("lobby_id").unwrap_or_default().to_string();
+                let game_id = game_doc.get_object_id("_id")?;
+                record_unplayable(state, game_id, &lobby_id, err.to_string()).await?;
+                continue;
+            }
+        };
+
+        let stored_receipt = receipts.find_one(doc! { "game_id": game.id }, None).await?;
+        if let Some(stored_receipt) = stored_receipt {
+            let stored_receipt = bson::from_bson::<games::Receipt>(bson::to_bson(&stored_receipt)?)?;
+            let receipt = risc0_zkvm::Receipt::new(&stored_receipt.journal, &stored_receipt.seal);
+            commit_game_result(state, &game, &receipt).await?;
+        } else {
+            spawn_battle(state.clone(), game);
+        }
+        resumed += 1;
+    }
+
+    Ok(resumed)
+}
+
+// Re-verifies the stored receipt of a finished game against the arena it was played in
+pub async fn verify_game(
+    db: &Database,
+    game_id: ObjectId,
+) -> Result<games::VerifyGameOutput, AppError> {
+    let game = db
+        .collection::<Document>("game")
+        .find_one(doc! { "_id": game_id }, None)
+        .await?
+        .ok_or_else(|| AppError::NotFound(String::from("Game")))?;
+    let game = read_game(&game)?;
+
+    // a cached outcome is backed by the receipt of the game it came from
+    let stored_receipt = db
+        .collection::<Document>("receipt")
+        .find_one(doc! { "game_id": game.cached_from.unwrap_or(game_id) }, None)
+        .await?
+        .ok_or_else(|| AppError::NotFound(String::from("Receipt")))?;
+    let stored_receipt = bson::from_bson::<games::Receipt>(bson::to_bson(&stored_receipt)?)?;
+
+    let arena = arenas::get(&game.arena_id)
+        .ok_or_else(|| AppError::Internal(format!("Unknown arena {}", game.arena_id)))?;
+
+    let mut output = games::VerifyGameOutput {
+        verified: false,
+        warning: String::from(""),
+        error: String::from(""),
+    };
+
+    // a valid receipt can still fail here because the arena binary was rebuilt since
+    if let Some(method_id) = stored_receipt.method_id.filter(|method_id| *method_id != arena.id) {
+        output.warning = format!(
+            "Receipt was proven with method {:?} on risc0 {} by {}, {} is now method {:?}",
+            method_id,
+            stored_receipt.risc0_version.as_deref().unwrap_or("unknown"),
+            stored_receipt.prover_host.as_deref().unwrap_or("unknown"),
+            game.arena_id,
+            arena.id
+        );
+        tracing::warn!("Game {}: {}", game_id, output.warning);
+    }
+
+    let receipt = risc0_zkvm::Receipt::new(&stored_receipt.journal, &stored_receipt.seal);
+    let verified = tokio::task::spawn_blocking(move || receipt.verify(&arena.id))
+        .await
+        .map_err(|err| AppError::Internal(format!("Verification task failed: {}", err)))?;
+    match verified {
+        Ok(()) => output.verified = true,
+        Err(err) => {
+            tracing::warn!("Receipt for game {} failed verification: {}", game_id, err);
+        }
+    }
+
+    Ok(output)
+}
+
+pub async fn verify_game_receipt(
+    State(db): State<Database>,
+    Path(game_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("verify_game_receipt called");
+
+    let mut response = games::VerifyGameOutput {
+        verified: false,
+        warning: String::from(""),
+        error: String::from(""),
+    };
+
+    let game_id = ObjectId::parse_str(&game_id);
+    if game_id.is_err() {
+        response.error = String::from("Invalid game id");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    response = verify_game(&db, game_id.unwrap()).await?;
+    if !response.verified {
+        response.error = String::from("Receipt failed verification");
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// The stored receipt of a game with its arena's method ID, for clients that verify battles
+// themselves. A game whose outcome came from the proof cache is backed by the original game's
+// receipt. Games proven without a seal have no receipt to give.
+pub async fn get_game_receipt(
+    State(db): State<Database>,
+    Path(game_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_game_receipt called");
+
+    let mut response = games::GetReceiptOutput {
+        game_id: game_id.clone(),
+        arena_id: String::from(""),
+        method_id: [0; 8],
+        journal: Vec::new(),
+        seal: Vec::new(),
+        error: String::from(""),
+    };
+
+    let game_id = match ObjectId::parse_str(&game_id) {
+        Ok(game_id) => game_id,
+        Err(_) => {
+            response.error = String::from("Invalid game id");
+            return Ok((StatusCode::BAD_REQUEST, Json(response)));
+        }
+    };
+
+    let game = db
+        .collection::<Document>("game")
+        .find_one(doc! { "_id": game_id }, None)
+        .await?;
+    let game = match game {
+        Some(game) => read_game(&game)?,
+        None => {
+            response.error = String::from("Game does not exist");
+            return Ok((StatusCode::NOT_FOUND, Json(response)));
+        }
+    };
+
+    let stored_receipt = db
+        .collection::<Document>("receipt")
+        .find_one(doc! { "game_id": game.cached_from.unwrap_or(game_id) }, None)
+        .await?;
+    let stored_receipt = match stored_receipt {
+        Some(stored_receipt) => bson::from_bson::<games::Receipt>(bson::to_bson(&stored_receipt)?)?,
+        None => {
+            response.error = String::from("Game has no stored receipt");
+            return Ok((StatusCode::NOT_FOUND, Json(response)));
+        }
+    };
+
+    let arena = arenas::get(&game.arena_id)
+        .ok_or_else(|| AppError::Internal(format!("Unknown arena {}", game.arena_id)))?;
+
+    response.arena_id = game.arena_id;
+    response.method_id = arena.id;
+    response.journal = stored_receipt.journal;
+    response.seal = stored_receipt.seal;
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// The stored response for a replayed idempotency key, if this is a replay
+fn replayed_response<'a>(
+    game_doc: &'a Document,
+    player_id: &str,
+    idempotency_key: &Option<String>,
+) -> Option<&'a Document> {
+    let last_request = game_doc.get_document("last_request").ok()?;
+    let idempotency_key = idempotency_key.as_ref()?;
+    if last_request.get_str("player_id").ok()? != player_id
+        || last_request.get_str("idempotency_key").ok()? != idempotency_key
+    {
+        return None;
+    }
+    last_request.get_document("response").ok()
+}
+
+fn last_request(
+    player_id: &str,
+    idempotency_key: &str,
+    response: &games::PlayGameOutput,
+) -> Result<Document, AppError> {
+    Ok(doc! {
+        "player_id": player_id,
+        "idempotency_key": idempotency_key,
+        "response": bson::to_document(response)?,
+    })
+}
+
+// Every player creation goes through this before a game is made with it, whichever endpoint it
+// came in on, so nothing is proven that the guest would reject. The status and message for a
+// creation that breaks the rules. There's no size to check: a creation has exactly
+// CARDS_PER_DECK cards of bounded stats, anything else is turned away as it's read.
+fn check_creation(creation: &Deck) -> Result<(), (StatusCode, ErrorCode, String)> {
+    match creation_violations(creation).into_iter().next() {
+        Some(violation) => Err((StatusCode::BAD_REQUEST, violation.code, violation.message)),
+        None => Ok(()),
+    }
+}
+
+// Everything wrong with a creation, card by card. check_creation enforces the first one.
+fn creation_violations(creation: &Deck) -> Vec<games::CreationViolation> {
+    let mut violations = Vec::new();
+    for err in tenet_core::creation_violations(creation) {
+        let card = match err {
+            tenet_core::CreationError::NoHealth { card }
+            | tenet_core::CreationError::InvalidStatTotal { card } => card,
+        };
+        violations.push(games::CreationViolation {
+            code: ErrorCode::InvalidCreation,
+            card: Some(card),
+            message: format!("Invalid creation: {}", err),
+        });
+    }
+
+    violations
+}
+
+// Checks a creation the way a submission of it would be, without starting a game or proving
+// anything, so deck builders can show what's wrong as it's built
+pub async fn validate_creation_endpoint(
+    AppJson(payload): AppJson<games::ValidateCreationInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("validate_creation_endpoint called");
+
+    let mut response = games::ValidateCreationOutput {
+        valid: false,
+        violations: Vec::new(),
+        error: String::from(""),
+        error_code: None,
+    };
+
+    if arenas::get(&payload.arena_id).is_none() {
+        response.error = String::from("Arena does not exist");
+        response.error_code = Some(ErrorCode::ArenaNotFound);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    response.violations = creation_violations(&payload.creation);
+    response.valid = response.violations.is_empty();
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// A fresh nonce for each submitted creation, so the same deck never commits to the same hash
+fn new_nonce() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(NONCE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+// Inserts the lobby and points its lobby_id at the new document's ObjectId
+pub(crate) async fn create_lobby(db: &Database, mut lobby: Document) -> Result<String, AppError> {
+    let lobbies = db.collection::<Document>("lobby");
+
+    lobby.insert("lobby_id", Bson::Null);
+    lobby.insert("created_at", bson::DateTime::now());
+    let player1_id = lobby.get_str("player1_id").ok().map(String::from);
+    let insert_result = lobbies.insert_one(lobby, None).await?;
+    metrics::increment_counter!("lobbies_created_total");
+    let newlobby_id = insert_result
+        .inserted_id
+        .as_object_id()
+        .ok_or_else(|| AppError::Internal(String::from("Inserted lobby has no ObjectId")))?;
+
+    lobbies
+        .update_one(
+            doc! {
+                "_id": newlobby_id,
+            },
+            doc! {
+                "$set": { "lobby_id": newlobby_id.to_string() }
+            },
+            None,
+        )
+        .await?;
+
+    let mut created = AuditEvent::new(AuditKind::LobbyCreated, &newlobby_id.to_string());
+    if let Some(player1_id) = &player1_id {
+        created = created.player(player1_id);
+    }
+    audit::record(db, created);
+
+    Ok(newlobby_id.to_string())
+}
+
+// A game holding the first submitted creation, along with the state it starts in
+fn initial_game_doc(
+    lobby_id: &str,
+    player1_id: &str,
+    player2_id: &str,
+    arena_id: &str,
+    arena: &arenas::Arena,
+    is_player_1: bool,
+    creation: &Deck,
+) -> Result<(Document, GameState), AppError> {
+    let creation_bson = bson::to_bson(creation)?;
+    let creation_nonce = new_nonce();
+    let creation_hash = tenet_core::hash_creation(creation, &creation_nonce);
+
+    let mut new_game = doc! {
+        "lobby_id": lobby_id,
+        "player1_id": player1_id,
+        "player2_id": player2_id,
+        "creation1": null,
+        "creation1_hash": null,
+        "creation1_nonce": null,
+        "creation2": null,
+        "creation2_hash": null,
+        "creation2_nonce": null,
+        "arena_id": arena_id,
+        "arena_hash": arena.hash(),
+        "winner_creation_hash": null,
+        "winner_id": null,
+        "state": null,
+        "player1_ready": false,
+        "player2_ready": false,
+        "result": null,
+        "error": null,
+        "is_npc_game": false,
+        "rated": true,
+        "battle_seed": rand::random::<u32>(),
+        "created_at": bson::DateTime::now(),
+        "updated_at": bson::DateTime::now(),
+    };
+
+    let new_state = if is_player_1 {
+        new_game.insert("creation1", creation_bson);
+        new_game.insert("creation1_version", creations::CREATION_VERSION);
+        new_game.insert("creation1_hash", creation_hash);
+        new_game.insert("creation1_nonce", creation_nonce);
+        GameState::Player2Turn
+    } else {
+        new_game.insert("creation2", creation_bson);
+        new_game.insert("creation2_version", creations::CREATION_VERSION);
+        new_game.insert("creation2_hash", creation_hash);
+        new_game.insert("creation2_nonce", creation_nonce);
+        GameState::Player1Turn
+    };
+    new_game.insert("state", new_state);
+
+    Ok((new_game, new_state))
+}
+
+pub async fn play_game(
+    // this argument tells axum to parse the request body
+    State(state): State<AppState>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::PlayGameInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("play_game called");
+
+    auth.authorize(&payload.player_id)?;
+    state.limits.play.check(&payload.player_id).map_err(AppError::RateLimited)?;
+
+    let (status, response) = submit_creation(state, payload).await?;
+    Ok(with_backoff(status, response))
+}
+
+async fn submit_creation(
+    state: AppState,
+    payload: games::PlayGameInput,
+) -> Result<(StatusCode, Json<games::PlayGameOutput>), AppError> {
+    let db = state.db.clone();
+
+    let lobby_id = payload.lobby_id;
+    let mut response = games::PlayGameOutput {
+        lobby_id: lobby_id.clone(),
+        state: String::from(""),
+        error: String::from(""),
+        error_code: None,
+    };
+
+    let resolved =
+        decks::resolve(&db, &payload.player_id, payload.creation, &payload.deck_id).await?;
+    let creation = match resolved {
+        Ok(creation) => creation,
+        Err((status, code, err)) => {
+            response.error = err;
+            response.error_code = Some(code);
+            return Ok((status, Json(response)));
+        }
+    };
+
+    let arena = arenas::get(&payload.arena_id);
+    if arena.is_none() {
+        response.error = String::from("Arena does not exist");
+        response.error_code = Some(ErrorCode::ArenaNotFound);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+    let arena = arena.unwrap();
+
+    if let Err((status, code, err)) = check_creation(&creation) {
+        response.error = err;
+        response.error_code = Some(code);
+        return Ok((status, Json(response)));
+    }
+
+    // catches the client serializing the creation differently before the guest would. The
+    // nonce is the server's, so the claim is the hash of the deck alone.
+    if let Some(claimed_hash) = &payload.claimed_hash {
+        if *claimed_hash != tenet_core::hash_creation(&creation, "") {
+            response.error = String::from("Creation doesn't match its claimed hash");
+            response.error_code = Some(ErrorCode::CreationHashMismatch);
+            return Ok((StatusCode::BAD_REQUEST, Json(response)));
+        }
+    }
+
+    // check if lobby exists
+    let lobbies = db.collection::<Document>("lobby");
+    let lobby = lobbies
+        .find_one(
+            doc! {
+                "lobby_id": lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+    if lobby.is_none() {
+        response.error = String::from("Lobby does not exist");
+        response.error_code = Some(ErrorCode::LobbyNotFound);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    // lobby.unwrap()
+    let lobby = bson::to_bson(&lobby.unwrap())?;
+    let lobby = bson::from_bson::<games::Lobby>(lobby)?;
+    // from_bson::<games::Lobby>(lobby);
+
+    // check if player ids exist, otherwise return
+    if lobby.player1_id.is_none() || lobby.player2_id.is_none() {
+        response.error = String::from("Lobby is not full");
+        response.error_code = Some(ErrorCode::LobbyNotFull);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    let player1_id = lobby.player1_id.unwrap();
+    let player2_id = lobby.player2_id.unwrap();
+    let is_player_1 = player1_id == payload.player_id;
+
+    if !is_player_1 && player2_id != payload.player_id {
+        response.error = String::from("Player is not in this lobby");
+        response.error_code = Some(ErrorCode::NotInLobby);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    // check if game document exists
+    let games = db.collection::<Document>("game");
+    let game = games
+        .find_one(
+            doc! {
+                "lobby_id": lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+
+    // an NPC took the slot after the player waited too long, so their creation goes straight
+    // into an NPC battle in this lobby
+    if let (None, Some(npc_id)) = (&game, &lobby.npc_id) {
+        let npc_game = games::PlayNPCGameInput {
+            player_id: payload.player_id,
+            creation: Some(creation),
+            deck_id: None,
+            npc_id: npc_id.clone(),
+            arena_id: payload.arena_id,
+            battle_seed: None,
+        };
+        let (status, npc_response) = start_npc_game(&state, &npc_game, Some(lobby_id)).await?;
+        response.state = npc_response.state;
+        response.error = npc_response.error;
+        response.error_code = npc_response.error_code;
+        return Ok((status, Json(response)));
+    }
+
+    if game.is_none() {
+        // a self-match would let a player grind their own rating
+        if player1_id == player2_id {
+            response.error = String::from("A player can't play against themselves");
+            response.error_code = Some(ErrorCode::SelfMatch);
+            return Ok((StatusCode::BAD_REQUEST, Json(response)));
+        }
+
+        // the first submission is what starts a game, later ones only play it
+        if active_games(&db, &payload.player_id).await? >= state.matchmaking.max_active_games {
+            response.error = too_many_games(&state.matchmaking);
+            response.error_code = Some(ErrorCode::TooManyGames);
+            return Ok((StatusCode::CONFLICT, Json(response)));
+        }
+
+        let (mut new_game, new_state) = initial_game_doc(
+            &lobby_id,
+            &player1_id,
+            &player2_id,
+            &payload.arena_id,
+            &arena,
+            is_player_1,
+            &creation,
+        )?;
+        if let Some(series_id) = &lobby.series_id {
+            new_game.insert("series_id", series_id.clone());
+        }
+        response.state = new_state.to_string();
+        if let Some(idempotency_key) = &payload.idempotency_key {
+            new_game.insert("last_request", last_request(&payload.player_id, idempotency_key, &response)?);
+        }
+
+        // create it. The other player's first submission may have created the game since it
+        // was looked up, the unique index on lobby_id turns the second one away.
+        let insert_result = match games.insert_one(new_game.clone(), None).await {
+            Ok(insert_result) => insert_result,
+            Err(err) if db::is_duplicate_key(&err) => {
+                response.state = String::from("");
+                response.error = String::from("Game was created by another request");
+                response.error_code = Some(ErrorCode::Conflict);
+                return Ok((StatusCode::CONFLICT, Json(response)));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        metrics::increment_counter!("games_created_total", "kind" => "player");
+        audit::record(
+            &db,
+            AuditEvent::new(AuditKind::CreationSubmitted, &lobby_id)
+                .game(insert_result.inserted_id.as_object_id())
+                .player(&payload.player_id),
+        );
+
+        state.notifier.publish(GameEvent {
+            lobby_id,
+            state: new_state.to_string(),
+            winner_id: None,
+        });
+    } else if let Some(mut game_doc) = game {
+        // game exists, check if it's in the right state
+        let game_id = game_doc.get_object_id("_id")?;
+
+        // a retry of the last request this player made on this game gets the same answer,
+        // rather than being applied again
+        let replayed =
+            replayed_response(&game_doc, &payload.player_id, &payload.idempotency_key);
+        if let Some(previous_response) = replayed {
+            let previous_response =
+                bson::from_document::<games::PlayGameOutput>(previous_response.clone())?;
+            return Ok((StatusCode::OK, Json(previous_response)));
+        }
+
+        // a creation stored by an older deploy is upgraded before it's read. One that can't be
+        // would fail its proof, so the game ends here instead.
+        if let Err(err) = creations::upgrade(&mut game_doc) {
+            record_unmigratable(&state, game_id, &lobby_id, &err).await?;
+            response.error = err.to_string();
+            response.error_code = Some(ErrorCode::UnmigratableCreation);
+            return Ok((StatusCode::INTERNAL_SERVER_ERROR, Json(response)));
+        }
+
+        let game = match read_game(&game_doc) {
+            Ok(game) => game,
+            Err(err) => {
+                record_unplayable(&state, game_id, &lobby_id, err.to_string()).await?;
+                return Err(err);
+            }
+        };
+
+        // the arena is settled by the first submission, the other player has to play in it too
+        if payload.arena_id != game.arena_id {
+            response.error = format!("Game is played in arena {}", game.arena_id);
+            response.error_code = Some(ErrorCode::ArenaMismatch);
+            return Ok((StatusCode::BAD_REQUEST, Json(response)));
+        }
+
+        // the player whose turn it is either confirms the creation they already submitted, which
+        // marks them ready, or submits one, replacing any they sent before. Every other state
+        // turns the submission away.
+        let (stored_hash, stored_nonce, creation_field, next_state) = match (game.state, is_player_1) {
+            (GameState::Player1Turn, true) => (
+                &game.creation1_hash,
+                &game.creation1_nonce,
+                "creation1",
+                GameState::Player2Turn,
+            ),
+            (GameState::Player2Turn, false) => (
+                &game.creation2_hash,
+                &game.creation2_nonce,
+                "creation2",
+                GameState::Player1Turn,
+            ),
+            (GameState::Player1Turn, false) | (GameState::Player2Turn, true) => {
+                response.error = String::from("It's not your turn");
+                response.error_code = Some(ErrorCode::NotYourTurn);
+                return Ok((StatusCode::CONFLICT, Json(response)));
+            }
+            (GameState::Playing, _) => {
+                response.error = String::from("Game is in progress");
+                response.error_code = Some(ErrorCode::GameInProgress);
+                return Ok((StatusCode::CONFLICT, Json(response)));
+            }
+            (GameState::Complete, _) => {
+                response.error = String::from("Game is finished");
+                response.error_code = Some(ErrorCode::GameFinished);
+                return Ok((StatusCode::BAD_REQUEST, Json(response)));
+            }
+            (GameState::Error, _) | (GameState::Abandoned, _) | (GameState::Cancelled, _) => {
+                response.error = format!("Game has ended ({})", game.state);
+                response.error_code = Some(ErrorCode::GameEnded);
+                return Ok((StatusCode::BAD_REQUEST, Json(response)));
+            }
+        };
+
+        let creation_bson = bson::to_bson(&creation)?;
+        let stored_nonce = stored_nonce.as_deref().unwrap_or("");
+        let matches_stored = stored_hash.as_deref()
+            == Some(tenet_core::hash_creation(&creation, stored_nonce).as_str());
+        if payload.confirm && !matches_stored {
+            (response.error, response.error_code) = if stored_hash.is_none() {
+                (
+                    String::from("There is no creation to confirm yet"),
+                    Some(ErrorCode::NothingToConfirm),
+                )
+            } else {
+                (
+                    String::from("Confirmed creation doesn't match the one submitted"),
+                    Some(ErrorCode::ConfirmMismatch),
+                )
+            };
+            return Ok((StatusCode::BAD_REQUEST, Json(response)));
+        }
+
+        let (ready_field, is_ready, other_ready) = if is_player_1 {
+            ("player1_ready", game.player1_ready, game.player2_ready)
+        } else {
+            ("player2_ready", game.player2_ready, game.player1_ready)
+        };
+        let confirming = payload.confirm || matches_stored;
+        if !confirming && is_ready {
+            response.error = String::from("Take back ready before swapping your creation");
+            response.error_code = Some(ErrorCode::PlayerReady);
+            return Ok((StatusCode::CONFLICT, Json(response)));
+        }
+
+        let (mut new_game_doc, new_state, start_battle) = if confirming {
+            // DEPRECATED: resending the same creation used to be the only way to confirm it
+            if !payload.confirm {
+                tracing::warn!(
+                    "Lobby {} confirmed by resubmitting the same creation, clients should send confirm",
+                    lobby_id
+                );
+            }
+
+            // COMMENCE AUTO BATTLE, once the other player is ready too. Until then the turn
+            // passes to them.
+            let new_state = if other_ready { GameState::Playing } else { next_state };
+            let mut ready = doc! {
+                "state": new_state,
+                ready_field: true,
+                "updated_at": bson::DateTime::now(),
+            };
+            if other_ready {
+                ready.insert("started_at", bson::DateTime::now());
+            }
+            let ready = doc! { "$set": ready };
+            (ready, new_state, other_ready)
+        } else {
+            let mut submission = doc! {
+                "state": next_state,
+                "updated_at": bson::DateTime::now(),
+            };
+            // a new nonce every time, so resubmitting doesn't give away which deck it was
+            let creation_nonce = new_nonce();
+            submission.insert(
+                format!("{}_hash", creation_field),
+                tenet_core::hash_creation(&creation, &creation_nonce),
+            );
+            submission.insert(format!("{}_nonce", creation_field), creation_nonce);
+            submission.insert(creation_field, creation_bson);
+            submission.insert(format!("{}_version", creation_field), creations::CREATION_VERSION);
+            (doc! { "$set": submission }, next_state, false)
+        };
+        // only the confirm that starts the battle is turned away during maintenance, the game
+        // waits in its turn state until it's over
+        if start_battle {
+            maintenance::check(&state).await?;
+        }
+
+        response.state = new_state.to_string();
+        if let Some(idempotency_key) = &payload.idempotency_key {
+            new_game_doc
+                .get_document_mut("$set")?
+                .insert("last_request", last_request(&payload.player_id, idempotency_key, &response)?);
+        }
+
+        // update game state, guarded on the state and ready flags we read so concurrent
+        // submissions can't both make the same transition (and both start a battle). A game with
+        // the same player in both slots never moves on.
+        let submitted = doc! {
+            "_id": game_id,
+            "state": game.state,
+            "player1_ready": ready_guard(game.player1_ready),
+            "player2_ready": ready_guard(game.player2_ready),
+            "$expr": { "$ne": ["$player1_id", "$player2_id"] },
+        };
+        let update_result = db::retry_write("submit creation", || {
+            games.update_one(submitted.clone(), new_game_doc.clone(), None)
+        })
+        .await?;
+        if update_result.modified_count != 1 {
+            response.error = String::from("Game was updated by another request");
+            response.error_code = Some(ErrorCode::Conflict);
+            return Ok((StatusCode::CONFLICT, Json(response)));
+        }
+        if !confirming {
+            audit::record(
+                &db,
+                AuditEvent::new(AuditKind::CreationSubmitted, &lobby_id)
+                    .game(Some(game_id))
+                    .player(&payload.player_id),
+            );
+        }
+
+        state.notifier.publish(GameEvent {
+            lobby_id,
+            state: new_state.to_string(),
+            winner_id: None,
+        });
+
+        if start_battle {
+            spawn_battle(state.clone(), game);
+        }
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Matches a ready flag as it was read. Games stored before the ready handshake have no flags.
+fn ready_guard(ready: bool) -> Bson {
+    if ready {
+        Bson::Boolean(true)
+    } else {
+        Bson::Document(doc! { "$ne": true })
+    }
+}
+
+// Marks a player ready to battle with both creations as they are, or takes it back so they can
+// swap theirs on their turn. Unlike a submission it doesn't wait for the player's turn, and the
+// battle starts as soon as both players are ready.
+pub async fn set_ready(
+    State(state): State<AppState>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::ReadyInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("set_ready called");
+
+    auth.authorize(&payload.player_id)?;
+    state.limits.play.check(&payload.player_id).map_err(AppError::RateLimited)?;
+
+    mark_ready(&state, payload).await
+}
+
+async fn mark_ready(
+    state: &AppState,
+    payload: games::ReadyInput,
+) -> Result<(StatusCode, Json<games::ReadyOutput>), AppError> {
+    let db = state.db.clone();
+    let lobby_id = payload.lobby_id;
+    let mut response = games::ReadyOutput {
+        lobby_id: lobby_id.clone(),
+        state: String::from(""),
+        player1_ready: false,
+        player2_ready: false,
+        error: String::from(""),
+        error_code: None,
+    };
+
+    let games = db.collection::<Document>("game");
+    let game = games
+        .find_one(
+            doc! {
+                "lobby_id": lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+    if game.is_none() {
+        response.error = String::from("Game does not exist");
+        response.error_code = Some(ErrorCode::LobbyNotFound);
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
+
+    let mut game_doc = game.unwrap();
+    let game_id = game_doc.get_object_id("_id")?;
+    // the battle may start from here, so the creations are brought up to date like a submission
+    if let Err(err) = creations::upgrade(&mut game_doc) {
+        record_unmigratable(state, game_id, &lobby_id, &err).await?;
+        response.error = err.to_string();
+        response.error_code = Some(ErrorCode::UnmigratableCreation);
+        return Ok((StatusCode::INTERNAL_SERVER_ERROR, Json(response)));
+    }
+    let game = match read_game(&game_doc) {
+        Ok(game) => game,
+        Err(err) => {
+            record_unplayable(state, game_id, &lobby_id, err.to_string()).await?;
+            return Err(err);
+        }
+    };
+
+    if game.player1_id != payload.player_id && game.player2_id != payload.player_id {
+        response.error = String::from("Player is not in this game");
+        response.error_code = Some(ErrorCode::NotInLobby);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    response.state = game.state.to_string();
+    response.player1_ready = game.player1_ready;
+    response.player2_ready = game.player2_ready;
+
+    if game.state == GameState::Playing {
+        response.error = String::from("Game is in progress");
+        response.error_code = Some(ErrorCode::GameInProgress);
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+    if !game.state.is_turn() {
+        response.error = format!("Game has ended ({})", game.state);
+        response.error_code = Some(ErrorCode::GameEnded);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+    if game.creation1_hash.is_none() || game.creation2_hash.is_none() {
+        response.error = String::from("Both creations have to be submitted first");
+        response.error_code = Some(ErrorCode::CreationsPending);
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    let (player1_ready, player2_ready) = if game.player1_id == payload.player_id {
+        (payload.ready, game.player2_ready)
+    } else {
+        (game.player1_ready, payload.ready)
+    };
+    let start_battle = player1_ready && player2_ready;
+    let new_state = if start_battle { GameState::Playing } else { game.state };
+    if start_battle {
+        maintenance::check(state).await?;
+    }
+
+    // guarded on the state and both flags as read, so of two players readying at once exactly
+    // one starts the battle and the other gets a 409 to retry
+    let read = doc! {
+        "_id": game_id,
+        "state": game.state,
+        "player1_ready": ready_guard(game.player1_ready),
+        "player2_ready": ready_guard(game.player2_ready),
+        "$expr": { "$ne": ["$player1_id", "$player2_id"] },
+    };
+    let mut update = doc! {
+        "state": new_state,
+        "player1_ready": player1_ready,
+        "player2_ready": player2_ready,
+        "updated_at": bson::DateTime::now(),
+    };
+    if start_battle {
+        update.insert("started_at", bson::DateTime::now());
+    }
+    let update = doc! { "$set": update };
+    let update_result = db::retry_write("set ready", || {
+        games.update_one(read.clone(), update.clone(), None)
+    })
+    .await?;
+    if update_result.modified_count != 1 {
+        response.error = String::from("Game was updated by another request");
+        response.error_code = Some(ErrorCode::Conflict);
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    response.state = new_state.to_string();
+    response.player1_ready = player1_ready;
+    response.player2_ready = player2_ready;
+
+    state.notifier.publish(GameEvent {
+        lobby_id,
+        state: new_state.to_string(),
+        winner_id: None,
+    });
+
+    if start_battle {
+        spawn_battle(state.clone(), game);
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+pub async fn play_npc_game(
+    // this argument tells axum to parse the request body
+    State(state): State<AppState>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::PlayNPCGameInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("play_npc_game called");
+
+    auth.authorize(&payload.player_id)?;
+    state.limits.npc.check(&payload.player_id).map_err(AppError::RateLimited)?;
+
+    let (status, response) = start_npc_game(&state, &payload, None).await?;
+    Ok(with_backoff(status, Json(response)))
+}
+
+// Starts a game against each of a list of NPCs with the same creation, for onboarding. The
+// proofs all go through the proof slots, so a gauntlet can't take over the prover.
+pub async fn play_npc_gauntlet(
+    State(state): State<AppState>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::PlayNPCGauntletInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("play_npc_gauntlet called");
+
+    auth.authorize(&payload.player_id)?;
+    // a gauntlet is one onboarding step, so it takes a single token however many NPCs it has
+    state.limits.npc.check(&payload.player_id).map_err(AppError::RateLimited)?;
+
+    let mut response = games::PlayNPCGauntletOutput {
+        games: Vec::new(),
+        error: String::from(""),
+    };
+
+    if payload.npc_ids.is_empty() || payload.npc_ids.len() > MAX_GAUNTLET_NPCS {
+        response.error = format!("A gauntlet takes 1 to {} NPCs", MAX_GAUNTLET_NPCS);
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    let mut first_failure = None;
+    for npc_id in &payload.npc_ids {
+        let npc_game = games::PlayNPCGameInput {
+            player_id: payload.player_id.clone(),
+            creation: Some(payload.creation),
+            deck_id: None,
+            npc_id: npc_id.clone(),
+            arena_id: payload.arena_id.clone(),
+            battle_seed: None,
+        };
+        let (status, game) = start_npc_game(&state, &npc_game, None).await?;
+        if !status.is_success() && first_failure.is_none() {
+            first_failure = Some(status);
+        }
+        response.games.push(game);
+    }
+
+    // only a failure if not a single game could be started
+    let status = if response.games.iter().any(|game| !game.game_id.is_empty()) {
+        StatusCode::OK
+    } else {
+        first_failure.unwrap_or(StatusCode::OK)
+    };
+
+    Ok((status, Json(response)))
+}
+
+// The answer when another request created the game in the NPC's lobby first, only possible for
+// a lobby the NPC took over
+fn npc_game_taken(
+    mut response: games::PlayNPCGameOutput,
+) -> (StatusCode, games::PlayNPCGameOutput) {
+    response.state = String::from("");
+    response.error = String::from("Game was created by another request");
+    response.error_code = Some(ErrorCode::Conflict);
+    (StatusCode::CONFLICT, response)
+}
+
+// Starts a game against an NPC from the roster and queues its proof, or completes it straight
+// away from an identical earlier battle. Anything wrong with the request comes back as the
+// status and output to answer with.
+// Plays the NPC game in `lobby_id` when the NPC already holds a slot there, in a new lobby
+// otherwise
+async fn start_npc_game(
+    state: &AppState,
+    payload: &games::PlayNPCGameInput,
+    lobby_id: Option<String>,
+) -> Result<(StatusCode, games::PlayNPCGameOutput), AppError> {
+    let db = state.db.clone();
+
+    let mut response = games::PlayNPCGameOutput {
+        lobby_id: String::from(""),
+        game_id: String::from(""),
+        state: String::from(""),
+        error: String::from(""),
+        error_code: None,
+    };
+
+    let resolved =
+        decks::resolve(&db, &payload.player_id, payload.creation, &payload.deck_id).await?;
+    let creation = match resolved {
+        Ok(creation) => creation,
+        Err((status, code, err)) => {
+            response.error = err;
+            response.error_code = Some(code);
+            return Ok((status, response));
+        }
+    };
+
+    let arena = arenas::get(&payload.arena_id);
+    if arena.is_none() {
+        response.error = String::from("Arena does not exist");
+        response.error_code = Some(ErrorCode::ArenaNotFound);
+        return Ok((StatusCode::BAD_REQUEST, response));
+    }
+    let arena = arena.unwrap();
+
+    if let Err((status, code, err)) = check_creation(&creation) {
+        response.error = err;
+        response.error_code = Some(code);
+        return Ok((status, response));
+    }
+
+    if payload.player_id == payload.npc_id {
+        response.error = String::from("A player can't play against themselves");
+        response.error_code = Some(ErrorCode::SelfMatch);
+        return Ok((StatusCode::BAD_REQUEST, response));
+    }
+
+    // the NPC's deck comes from the roster, never from the client
+    let npc = db
+        .collection::<Document>("npc")
+        .find_one(
+            doc! {
+                "npc_id": payload.npc_id.clone(),
+            },
+            None,
+        )
+        .await?;
+    if npc.is_none() {
+        response.error = String::from("NPC does not exist");
+        response.error_code = Some(ErrorCode::NpcNotFound);
+        return Ok((StatusCode::NOT_FOUND, response));
+    }
+    let npc = bson::to_bson(&npc.unwrap())?;
+    let npc = bson::from_bson::<games::Npc>(npc)?;
+    let npc_creation = npc.creation;
+    if let Err(err) = tenet_core::validate_creation(&npc_creation) {
+        return Err(AppError::Internal(format!(
+            "NPC {} has an invalid creation: {}",
+            npc.npc_id, err
+        )));
+    }
+
+    // Check if player has battled this NPC before by checking if game exists with player creation and NCP creation
+    let games = db.collection("game");
+
+    // both decks go in at once and the battle starts straight away, so there's nothing to hide
+    // and no nonce. That also keeps the hashes stable for the duplicate check and proof cache.
+    let player_creation_hash = tenet_core::hash_creation(&creation, "");
+    let npc_creation_hash = tenet_core::hash_creation(&npc_creation, "");
+
+    let game: Option<Document> = games
+        .find_one(
+            doc! {
+                "player1_id": payload.player_id.clone(),
+                "creation1_hash": player_creation_hash.clone(),
+                "creation2_hash": npc_creation_hash.clone(),
+                "arena_id": payload.arena_id.clone(),
+            },
+            None,
+        )
+        .await?;
+
+    if let Some(game) = game {
+        // an impatient retry of a battle still being proven gets pointed at that one rather
+        // than proving it a second time
+        if game.get_str("state") == Ok(GameState::Playing.as_str()) {
+            response.lobby_id = game.get_str("lobby_id").unwrap_or_default().to_string();
+            response.game_id = game.get_object_id("_id").map(|id| id.to_string()).unwrap_or_default();
+            response.state = GameState::Playing.to_string();
+            response.error = String::from("Battle already in progress");
+            response.error_code = Some(ErrorCode::BattleInProgress);
+            return Ok((StatusCode::CONFLICT, response));
+        }
+
+        // game played, return error
+        response.error = String::from("You have already played this NPC with this deck");
+        response.error_code = Some(ErrorCode::AlreadyPlayedNpc);
+        return Ok((StatusCode::BAD_REQUEST, response));
+    }
+
+    // checked per game, so a gauntlet stops starting games once the player is at the cap
+    if active_games(&db, &payload.player_id).await? >= state.matchmaking.max_active_games {
+        response.error = too_many_games(&state.matchmaking);
+        response.error_code = Some(ErrorCode::TooManyGames);
+        return Ok((StatusCode::CONFLICT, response));
+    }
+
+    let newlobby_id = match lobby_id {
+        Some(lobby_id) => lobby_id,
+        None => {
+            // Create new lobby with player and NPC
+            let new_lobby = doc! {
+                "player1_id": payload.player_id.clone(),
+                "player2_id": payload.npc_id.clone(),
+            };
+            create_lobby(&db, new_lobby).await?
+        }
+    };
+    response.lobby_id = newlobby_id.clone();
+
+    // create new game
+
+    let arena_hash = arena.hash();
+
+    let creation1_bson = bson::to_bson(&creation)?;
+    let creation2_bson = bson::to_bson(&npc_creation)?;
+
+    // a seed the player asked for picks the coin flip too, so that battle never moves a rating
+    let seed_chosen = payload.battle_seed.is_some();
+    let rated = state.rating.rate_npc_games && !seed_chosen;
+    let mut new_game = doc! {
+        "lobby_id": newlobby_id.to_string(),
+        "player1_id": payload.player_id.clone(),
+        "player2_id": payload.npc_id.clone(),
+        "creation1": creation1_bson,
+        "creation1_version": creations::CREATION_VERSION,
+        "creation1_hash": player_creation_hash.clone(),
+        "creation2": creation2_bson,
+        "creation2_version": creations::CREATION_VERSION,
+        "creation2_hash": npc_creation_hash.clone(),
+        "npc_deck_hash": npc_creation_hash.clone(),
+        "arena_id": payload.arena_id.clone(),
+        "arena_hash": arena_hash.clone(),
+        "winner_creation_hash": null,
+        "winner_id": null,
+        "state": "playing",
+        "result": null,
+        "is_npc_game": true,
+        "rated": rated,
+        "battle_seed": payload.battle_seed.unwrap_or_else(rand::random::<u32>),
+        "seed_chosen": seed_chosen,
+        "created_at": bson::DateTime::now(),
+        "started_at": bson::DateTime::now(),
+        "updated_at": bson::DateTime::now(),
+    };
+
+    // the same battle was proven before, so take its outcome instead of proving it again
+    let cached = proof_cache::lookup(
+        &db,
+        &payload.arena_id,
+        &arena_hash,
+        &player_creation_hash,
+        &npc_creation_hash,
+        payload.battle_seed,
+    )
+    .await?;
+    if let Some(cached) = cached {
+        let winner = match cached.winner {
+            1 => Some((
+                &payload.player_id,
+                &payload.npc_id,
+                &player_creation_hash,
+                &npc_creation_hash,
+            )),
+            2 => Some((
+                &payload.npc_id,
+                &payload.player_id,
+                &npc_creation_hash,
+                &player_creation_hash,
+            )),
+            _ => None,
+        };
+
+        new_game.insert("state", "complete");
+        new_game.insert("result", cached.result.clone());
+        new_game.extend(timelines::compressed(&cached.timeline)?);
+        new_game.insert("cached_from", cached.game_id);
+        new_game.insert("verified", cached.verified);
+        // the outcome is the proven game's, so it owns the seed too
+        if let Some(battle_seed) = cached.battle_seed {
+            new_game.insert("battle_seed", battle_seed);
+            new_game.insert("first_attacker", tenet_core::coin_flip(battle_seed) as i32);
+        }
+        new_game.remove("creation1");
+        new_game.remove("creation2");
+        // NPC games hash without nonces already
+        new_game.insert("creation1_deck_hash", player_creation_hash.clone());
+        new_game.insert("creation2_deck_hash", npc_creation_hash.clone());
+        if let Some((winner_id, _, winner_creation_hash, loser_creation_hash)) = winner {
+            new_game.insert("winner_id", winner_id.clone());
+            new_game.insert("winner_creation_hash", winner_creation_hash.clone());
+            new_game.insert("loser_creation_hash", loser_creation_hash.clone());
+        }
+        let insert_result = match games.insert_one(new_game, None).await {
+            Ok(insert_result) => insert_result,
+            Err(err) if db::is_duplicate_key(&err) => return Ok(npc_game_taken(response)),
+            Err(err) => return Err(err.into()),
+        };
+        metrics::increment_counter!("games_created_total", "kind" => "npc");
+        if let Some(game_id) = insert_result.inserted_id.as_object_id() {
+            response.game_id = game_id.to_string();
+        }
+        metrics::increment_counter!("proof_cache_hits_total", "arena_id" => payload.arena_id.clone());
+
+        response.state = String::from("complete");
+        let winner_id = winner.map(|(winner_id, _, _, _)| winner_id.clone());
+        state.notifier.publish(GameEvent {
+            lobby_id: newlobby_id.clone(),
+            state: String::from("complete"),
+            winner_id: winner_id.clone(),
+        });
+        let completed = webhooks::GameCompleted {
+            event: "game.completed",
+            game_id: response.game_id.clone(),
+            lobby_id: newlobby_id.clone(),
+            arena_id: payload.arena_id.clone(),
+            winner_id,
+            result: cached.result.clone(),
+            completed_at: bson::DateTime::now(),
+        };
+        audit::record(
+            &db,
+            AuditEvent::new(AuditKind::ResultCommitted, &newlobby_id)
+                .game(insert_result.inserted_id.as_object_id())
+                .detail(cached.result.clone()),
+        );
+        state.webhooks.game_completed(&db, completed);
+
+        if rated {
+            let change = match winner {
+                Some((winner_id, loser_id, _, _)) => {
+                    ratings::apply_result(&db, winner_id, loser_id).await?
+                }
+                None => ratings::apply_draw(&db, &payload.player_id, &payload.npc_id).await?,
+            };
+            // kept like a proven game's, so it's taken back if the original fails verification
+            games
+                .update_one(
+                    doc! { "_id": insert_result.inserted_id },
+                    doc! { "$set": { "rating_change": change } },
+                    None,
+                )
+                .await?;
+        }
+
+        return Ok((StatusCode::OK, response));
+    }
+
+    // a cached battle needs no proof, so only one that has to be proven waits out maintenance
+    maintenance::check(state).await?;
+    let insert_result = match games.insert_one(new_game.clone(), None).await {
+        Ok(insert_result) => insert_result,
+        Err(err) if db::is_duplicate_key(&err) => return Ok(npc_game_taken(response)),
+        Err(err) => return Err(err.into()),
+    };
+    metrics::increment_counter!("games_created_total", "kind" => "npc");
+    metrics::increment_counter!("proof_cache_misses_total", "arena_id" => payload.arena_id.clone());
+
+    // get inserted game
+    let game_id = insert_result
+        .inserted_id
+        .as_object_id()
+        .ok_or_else(|| AppError::Internal(String::from("Inserted game has no ObjectId")))?;
+    let game_doc = games
+        .find_one(
+            doc! {
+                "_id": game_id,
+            },
+            None,
+        )
+        .await?
+        .ok_or_else(|| AppError::NotFound(String::from("Game")))?;
+
+    let game = read_game(&game_doc)?;
+
+    response.game_id = game_id.to_string();
+    response.state = game.state.to_string();
+    spawn_battle(state.clone(), game);
+
+    Ok((StatusCode::OK, response))
+}
+
+// Proves a battle between two creations and hands back the result without storing anything, so
+// players can try a deck out without it counting
+pub async fn sandbox_battle(
+    State(state): State<AppState>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::SandboxBattleInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("sandbox_battle called");
+
+    auth.authorize(&payload.player_id)?;
+    maintenance::check(&state).await?;
+    state.limits.sandbox.check(&payload.player_id).map_err(AppError::RateLimited)?;
+
+    let mut response = games::SandboxBattleOutput {
+        result: None,
+        error: String::from(""),
+    };
+
+    let arena = arenas::get(&payload.arena_id);
+    if arena.is_none() {
+        response.error = String::from("Arena does not exist");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+    let arena = arena.unwrap();
+
+    for creation in [&payload.creation1, &payload.creation2] {
+        if let Err((status, _, err)) = check_creation(creation) {
+            response.error = err;
+            return Ok((status, Json(response)));
+        }
+    }
+
+    // never inserted, it only carries the battle through the prover
+    let game = games::Game {
+        id: None,
+        player1_id: payload.player_id.clone(),
+        player2_id: String::from("sandbox"),
+        lobby_id: String::from(""),
+        creation1: Some(payload.creation1),
+        creation1_hash: Some(tenet_core::hash_creation(&payload.creation1, "")),
+        creation2: Some(payload.creation2),
+        creation2_hash: Some(tenet_core::hash_creation(&payload.creation2, "")),
+        creation1_nonce: None,
+        creation2_nonce: None,
+        battle_seed: Some(payload.battle_seed.unwrap_or_else(rand::random::<u32>)),
+        seed_chosen: payload.battle_seed.is_some(),
+        first_attacker: None,
+        arena_id: payload.arena_id.clone(),
+        arena_hash: arena.hash(),
+        winner_creation_hash: None,
+        loser_creation_hash: None,
+        winner_id: None,
+        state: GameState::Playing,
+        progress: None,
+        result: None,
+        error: None,
+        guest_error: None,
+        created_at: None,
+        started_at: None,
+        updated_at: None,
+        is_npc_game: false,
+        npc_deck_hash: None,
+        rated: false,
+        timeline: Vec::new(),
+        journal: None,
+        cached_from: None,
+        series_id: None,
+        verified: false,
+        player1_ready: false,
+        player2_ready: false,
+    };
+
+    let span = tracing::info_span!(
+        "sandbox_battle",
+        player_id = %payload.player_id,
+        arena_id = %payload.arena_id,
+    );
+    let proven = async {
+        tracing::info!("Proving a throwaway sandbox battle, nothing will be stored");
+        let permit = state
+            .proof_slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Proof slots are never closed");
+        let (receipt, _) = match state.arena_methods.get(game.arena_id.as_str()) {
+            Some(arena_src) => {
+                let (arena_src, proof_timeout) = (arena_src.clone(), state.proof_timeout);
+                commence_battle(&game, arena_src, permit, proof_timeout, state.proof_mode).await?
+            }
+            None => return Err(BattleError::UnknownArena(game.arena_id.clone())),
+        };
+        check_receipt_blocking(&game, &receipt, state.proof_mode.verify()).await
+    }
+    .instrument(span)
+    .await;
+
+    match proven {
+        Ok(game_result) => {
+            response.result = Some(game_result);
+            Ok((StatusCode::OK, Json(response)))
+        }
+        Err(battle_error) => {
+            tracing::warn!("Sandbox battle failed: {}", battle_error);
+            response.error = battle_error.to_string();
+            Ok((StatusCode::INTERNAL_SERVER_ERROR, Json(response)))
+        }
+    }
+}
+
+pub async fn cancel_game(
+    // this argument tells axum to parse the request body
+    State(db): State<Database>,
+    State(notifier): State<Notifier>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::CancelGameInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("cancel_game called");
+
+    auth.authorize(&payload.player_id)?;
+
+    let mut response = games::CancelGameOutput {
+        error: String::from(""),
+    };
+
+    let games = db.collection::<Document>("game");
+    let game = games
+        .find_one(
+            doc! {
+                "lobby_id": payload.lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+    if game.is_none() {
+        response.error = String::from("Game does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
+
+    let game_doc = game.unwrap();
+    let game_id = game_doc.get_object_id("_id")?;
+    let game = read_game(&game_doc)?;
+
+    if game.player1_id != payload.player_id && game.player2_id != payload.player_id {
+        response.error = String::from("Player is not in this game");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    if !game.state.is_turn() {
+        response.error = String::from("Game can only be cancelled before the battle starts");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    // guarded on the state so a battle that started in the meantime isn't cancelled
+    let update_result = games
+        .update_one(
+            doc! {
+                "_id": game_id,
+                "state": game.state,
+            },
+            doc! {
+                "$set": {
+                    "state": "cancelled",
+                    "updated_at": bson::DateTime::now(),
+                },
+                "$unset": { "creation1": "", "creation2": "" }
+            },
+            None,
+        )
+        .await?;
+    if update_result.modified_count != 1 {
+        response.error = String::from("Game was updated by another request");
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    // free the lobby so both players can find another game
+    let lobbies = db.collection::<Document>("lobby");
+    lobbies
+        .delete_one(
+            doc! {
+                "lobby_id": payload.lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+
+    notifier.publish(GameEvent {
+        lobby_id: payload.lobby_id,
+        state: String::from("cancelled"),
+        winner_id: None,
+    });
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Cancels a battle still waiting on a proof slot. The worker checks the queue again once it has
+// a slot, so exactly one of the cancel and the proof goes ahead.
+pub async fn cancel_queued_battle(
+    State(state): State<AppState>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::CancelGameInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("cancel_queued_battle called");
+
+    auth.authorize(&payload.player_id)?;
+
+    let mut response = games::CancelGameOutput {
+        error: String::from(""),
+    };
+
+    let games = state.db.collection::<Document>("game");
+    let game = games
+        .find_one(
+            doc! {
+                "lobby_id": payload.lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+    if game.is_none() {
+        response.error = String::from("Game does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
+
+    let game_doc = game.unwrap();
+    let game_id = game_doc.get_object_id("_id")?;
+    let game = read_game(&game_doc)?;
+
+    if game.player1_id != payload.player_id && game.player2_id != payload.player_id {
+        response.error = String::from("Player is not in this game");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    if game.state != GameState::Playing {
+        response.error = String::from("Game has no battle waiting to be proven");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    if !state.proof_queue.cancel(game_id) {
+        response.error = String::from("Battle is already being proven");
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    // the battle is out of the queue for good now, nothing else moves the game on
+    let update_result = games
+        .update_one(
+            doc! {
+                "_id": game_id,
+                "state": "playing",
+            },
+            doc! {
+                "$set": {
+                    "state": "cancelled",
+                    "updated_at": bson::DateTime::now(),
+                },
+                "$unset": { "creation1": "", "creation2": "", "progress": "" }
+            },
+            None,
+        )
+        .await?;
+    if update_result.modified_count != 1 {
+        response.error = String::from("Game was updated by another request");
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    let lobbies = state.db.collection::<Document>("lobby");
+    lobbies
+        .delete_one(
+            doc! {
+                "lobby_id": payload.lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+
+    state.notifier.publish(GameEvent {
+        lobby_id: payload.lobby_id,
+        state: String::from("cancelled"),
+        winner_id: None,
+    });
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Gets a player out of a lobby whose opponent never showed up and back into matchmaking. Only
+// while nobody took the second slot, a lobby with an opponent is left to be played or cancelled.
+pub async fn requeue(
+    State(db): State<Database>,
+    State(matchmaking): State<MatchmakingConfig>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::RequeueInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("requeue called");
+
+    auth.authorize(&payload.player_id)?;
+
+    let mut response = games::RequeueOutput {
+        lobby_id: String::from(""),
+        error: String::from(""),
+    };
+
+    let lobbies = db.collection::<Document>("lobby");
+    let lobby = lobbies
+        .find_one(
+            doc! {
+                "lobby_id": payload.lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+    if lobby.is_none() {
+        response.error = String::from("Lobby does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
+    let lobby = bson::to_bson(&lobby.unwrap())?;
+    let lobby = bson::from_bson::<games::Lobby>(lobby)?;
+
+    if lobby.player1_id.as_ref() != Some(&payload.player_id)
+        && lobby.player2_id.as_ref() != Some(&payload.player_id)
+    {
+        response.error = String::from("Player is not in this lobby");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+    // a series or private lobby was set up with this opponent and an NPC lobby has its
+    // opponent, none of them is swapped for a matchmade one
+    if lobby.series_id.is_some() {
+        response.error = String::from("Series lobbies can't be requeued");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+    if lobby.private {
+        response.error = String::from("Private lobbies can't be requeued");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+    if lobby.npc_id.is_some() || lobby.player2_id.is_some() {
+        response.error = String::from("Opponent has already joined, the lobby can't be requeued");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    // guarded in case the opponent joins in the meantime
+    let delete_result = lobbies
+        .delete_one(
+            doc! {
+                "lobby_id": payload.lobby_id.clone(),
+                "player1_id": payload.player_id.clone(),
+                "player2_id": null,
+            },
+            None,
+        )
+        .await?;
+    if delete_result.deleted_count != 1 {
+        response.error = String::from("Lobby was joined by another player");
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    // without create_new a player at the lobby cap gets their own open lobby back, so it's
+    // never hit here
+    match matchmake(&db, &matchmaking, &payload.player_id, false).await? {
+        Matchmade::Lobby(lobby_id) => response.lobby_id = lobby_id,
+        Matchmade::Taken | Matchmade::AtLobbyCap => {
+            response.error = String::from("Lobby was taken by another player, try again");
+            return Ok((StatusCode::CONFLICT, Json(response)));
+        }
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Forfeits a game to the opponent. Only possible before the battle starts, once it's being
+// proven the proof decides the winner.
+pub async fn concede(
+    // this argument tells axum to parse the request body
+    State(state): State<AppState>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::ConcedeInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("concede called");
+
+    auth.authorize(&payload.player_id)?;
+
+    let db = state.db.clone();
+
+    let mut response = games::ConcedeOutput {
+        error: String::from(""),
+    };
+
+    let games = db.collection::<Document>("game");
+    let game = games
+        .find_one(
+            doc! {
+                "lobby_id": payload.lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+    if game.is_none() {
+        response.error = String::from("Game does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
+
+    let game = read_game(&game.unwrap())?;
+
+    if game.player1_id != payload.player_id && game.player2_id != payload.player_id {
+        response.error = String::from("Player is not in this game");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    if game.state == GameState::Playing {
+        response.error = String::from("The battle has started, its result stands");
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+    if !game.state.is_turn() {
+        response.error = String::from("Game is already finished");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    if !forfeit(&state, &game, &payload.player_id, GameState::Complete).await? {
+        response.error = String::from("Game was updated by another request");
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Ends a game still waiting on a turn with the other player as its winner, in `end_state`:
+// complete when the loser conceded, abandoned when they let their turn lapse. Guarded on the
+// state it was read in so a battle that started in the meantime keeps its result, false if it
+// did. Otherwise the game finishes like a proven one: rated, counted in its series, and
+// announced to subscribers and webhooks.
+pub(crate) async fn forfeit(
+    state: &AppState,
+    game: &games::Game,
+    loser_id: &str,
+    end_state: GameState,
+) -> Result<bool, AppError> {
+    let db = &state.db;
+    let games_ref = db.collection::<Document>("game");
+
+    let (winner_id, winner_creation_hash, loser_creation_hash, result) = if game.player1_id == loser_id {
+        (&game.player2_id, &game.creation2_hash, &game.creation1_hash, "PLAYER2_WINS")
+    } else {
+        (&game.player1_id, &game.creation1_hash, &game.creation2_hash, "PLAYER1_WINS")
+    };
+
+    let update_result = games_ref
+        .update_one(
+            doc! {
+                "_id": game.id,
+                "state": game.state,
+            },
+            doc! {
+                "$set": {
+                    "state": end_state,
+                    "result": result,
+                    "winner_id": winner_id.clone(),
+                    "winner_creation_hash": winner_creation_hash.clone(),
+                    "loser_creation_hash": loser_creation_hash.clone(),
+                    "updated_at": bson::DateTime::now(),
+                },
+                "$unset": { "creation1": "", "creation2": "" }
+            },
+            None,
+        )
+        .await?;
+    if update_result.modified_count != 1 {
+        return Ok(false);
+    }
+
+    state.notifier.publish(GameEvent {
+        lobby_id: game.lobby_id.clone(),
+        state: end_state.to_string(),
+        winner_id: Some(winner_id.clone()),
+    });
+    let completed = webhooks::GameCompleted {
+        event: "game.completed",
+        game_id: game.id.map(|id| id.to_string()).unwrap_or_default(),
+        lobby_id: game.lobby_id.clone(),
+        arena_id: game.arena_id.clone(),
+        winner_id: Some(winner_id.clone()),
+        result: String::from(result),
+        completed_at: bson::DateTime::now(),
+    };
+    state.webhooks.game_completed(db, completed);
+
+    if game.rated {
+        let change = ratings::apply_result(db, winner_id, loser_id).await?;
+        games_ref
+            .update_one(
+                doc! { "_id": game.id },
+                doc! { "$set": { "rating_change": change } },
+                None,
+            )
+            .await?;
+    }
+
+    if let Some(series_id) = &game.series_id {
+        series::record_game(db, series_id, &game.lobby_id, Some(winner_id.as_str())).await?;
+    }
+
+    Ok(true)
+}
+
+// Opens a new lobby for the players of a finished game. Both players get the same lobby back,
+// whoever asks first creates it.
+pub async fn rematch(
+    // this argument tells axum to parse the request body
+    State(state): State<AppState>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::RematchInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("rematch called");
+
+    auth.authorize(&payload.player_id)?;
+
+    let db = state.db.clone();
+
+    let mut response = games::RematchOutput {
+        lobby_id: String::from(""),
+        error: String::from(""),
+    };
+
+    let games = db.collection::<Document>("game");
+    let game = games
+        .find_one(
+            doc! {
+                "lobby_id": payload.lobby_id.clone(),
+            },
+            None,
+        )
+        .await?;
+    if game.is_none() {
+        response.error = String::from("Game does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
+
+    let game_doc = game.unwrap();
+    let game_id = game_doc.get_object_id("_id")?;
+    let game = read_game(&game_doc)?;
+
+    let is_player_1 = game.player1_id == payload.player_id;
+    if !is_player_1 && game.player2_id != payload.player_id {
+        response.error = String::from("Player is not in this game");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    if game.is_npc_game {
+        response.error = String::from("NPC games can't be rematched, start a new one instead");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    if game.state != GameState::Complete {
+        response.error = String::from("Only finished games can be rematched");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    // the creations are dropped once a game is complete, so the hash is all that's left to
+    // check a confirmed creation against
+    if let Some(creation) = &payload.creation {
+        let (previous_hash, previous_nonce) = if is_player_1 {
+            (&game.creation1_hash, &game.creation1_nonce)
+        } else {
+            (&game.creation2_hash, &game.creation2_nonce)
+        };
+        let previous_nonce = previous_nonce.as_deref().unwrap_or("");
+        let creation_hash = tenet_core::hash_creation(creation, previous_nonce);
+        if previous_hash.as_deref() != Some(creation_hash.as_str()) {
+            response.error = String::from("Creation doesn't match the one from the last game");
+            return Ok((StatusCode::BAD_REQUEST, Json(response)));
+        }
+    }
+
+    // the second player asking joins the lobby the first one opened
+    let lobby_id = match game_doc.get_str("rematch_lobby_id") {
+        Ok(rematch_lobby_id) => rematch_lobby_id.to_string(),
+        Err(_) => match open_rematch_lobby(&db, &game, game_id, &payload.lobby_id).await? {
+            Some(lobby_id) => lobby_id,
+            None => {
+                response.error = String::from("Rematch was created by another request");
+                return Ok((StatusCode::CONFLICT, Json(response)));
+            }
+        },
+    };
+    response.lobby_id = lobby_id.clone();
+
+    if let Some(creation) = &payload.creation {
+        if let Err((status, err)) =
+            submit_rematch_creation(&state, &game, &lobby_id, &payload.player_id, creation, payload.confirm)
+                .await?
+        {
+            response.error = err;
+            return Ok((status, Json(response)));
+        }
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Opens the lobby for a rematch of `game`, None if another request opened one first
+async fn open_rematch_lobby(
+    db: &Database,
+    game: &games::Game,
+    game_id: ObjectId,
+    rematch_of: &str,
+) -> Result<Option<String>, AppError> {
+    let games = db.collection::<Document>("game");
+    let new_lobby = doc! {
+        "player1_id": game.player1_id.clone(),
+        "player2_id": game.player2_id.clone(),
+        "rematch_of": rematch_of,
+    };
+    let lobby_id = create_lobby(db, new_lobby).await?;
+
+    // guarded so two players asking at once don't end up in different lobbies
+    let update_result = games
+        .update_one(
+            doc! {
+                "_id": game_id,
+                "rematch_lobby_id": { "$exists": false },
+            },
+            doc! {
+                "$set": {
+                    "rematch_lobby_id": lobby_id.clone(),
+                    "updated_at": bson::DateTime::now(),
+                }
+            },
+            None,
+        )
+        .await?;
+    if update_result.modified_count != 1 {
+        db.collection::<Document>("lobby")
+            .delete_one(doc! { "lobby_id": lobby_id }, None)
+            .await?;
+        return Ok(None);
+    }
+
+    Ok(Some(lobby_id))
+}
+
+// Plays the player's creation from the last game in its rematch, through the same submission
+// as /games/play, and marks them ready with it when they asked to confirm straight away. The
+// battle starts once the other player is ready too. A creation already in the rematch isn't
+// submitted again. Anything that turns it away comes back as the status and error.
+async fn submit_rematch_creation(
+    state: &AppState,
+    game: &games::Game,
+    lobby_id: &str,
+    player_id: &str,
+    creation: &Deck,
+    confirm: bool,
+) -> Result<Result<(), (StatusCode, String)>, AppError> {
+    let games = state.db.collection::<Document>("game");
+    let rematch = games.find_one(doc! { "lobby_id": lobby_id }, None).await?;
+    let rematch = rematch.as_ref().map(read_game).transpose()?;
+    let is_player_1 = game.player1_id == player_id;
+    let submitted = rematch.as_ref().is_some_and(|rematch| {
+        if is_player_1 {
+            rematch.creation1_hash.is_some()
+        } else {
+            rematch.creation2_hash.is_some()
+        }
+    });
+
+    if !submitted {
+        let submission = games::PlayGameInput {
+            lobby_id: lobby_id.to_string(),
+            player_id: player_id.to_string(),
+            creation: Some(*creation),
+            deck_id: None,
+            arena_id: game.arena_id.clone(),
+            idempotency_key: None,
+            claimed_hash: None,
+            confirm: false,
+        };
+        let (status, Json(submitted)) = submit_creation(state.clone(), submission).await?;
+        if status != StatusCode::OK {
+            return Ok(Err((status, submitted.error)));
+        }
+    }
+    if !confirm {
+        return Ok(Ok(()));
+    }
+
+    let rematch = games.find_one(doc! { "lobby_id": lobby_id }, None).await?;
+    let rematch = match rematch {
+        Some(rematch) => read_game(&rematch)?,
+        None => return Err(AppError::Internal(format!("Rematch game for {} is gone", lobby_id))),
+    };
+    if rematch.creation1_hash.is_some() && rematch.creation2_hash.is_some() {
+        let ready = games::ReadyInput {
+            player_id: player_id.to_string(),
+            lobby_id: lobby_id.to_string(),
+            ready: true,
+        };
+        let (status, Json(readied)) = mark_ready(state, ready).await?;
+        if status != StatusCode::OK {
+            return Ok(Err((status, readied.error)));
+        }
+        return Ok(Ok(()));
+    }
+
+    // the first creation in, so there's nothing to battle yet. The player is ready all the same,
+    // and the battle starts when the other one confirms theirs.
+    let ready_field = if is_player_1 { "player1_ready" } else { "player2_ready" };
+    let update_result = games
+        .update_one(
+            doc! {
+                "_id": rematch.id,
+                "state": rematch.state,
+                ready_field: { "$ne": true },
+            },
+            doc! {
+                "$set": {
+                    ready_field: true,
+                    "updated_at": bson::DateTime::now(),
+                }
+            },
+            None,
+        )
+        .await?;
+    if update_result.modified_count != 1 {
+        return Ok(Err((StatusCode::CONFLICT, String::from("Game was updated by another request"))));
+    }
+    Ok(Ok(()))
+}
+
+// The arena's inputs for a game being played, for a player who proves the battle themselves and
+// commits it through commit_outcome. Both creations are locked in by then, so the nonces and
+// seed give nothing away that the result won't.
+pub async fn get_battle_inputs(
+    State(db): State<Database>,
+    auth: AuthPlayer,
+    Path(lobby_id): Path<String>,
+    Query(query): Query<games::BattleInputsQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_battle_inputs called");
+
+    auth.authorize(&query.player_id)?;
+
+    let mut response = games::BattleInputsOutput {
+        game_id: String::from(""),
+        arena_id: String::from(""),
+        player1_id: String::from(""),
+        creation1: None,
+        creation1_nonce: String::from(""),
+        player2_id: String::from(""),
+        creation2: None,
+        creation2_nonce: String::from(""),
+        battle_seed: 0,
+        first_attacker: 0,
+        input: Vec::new(),
+        error: String::from(""),
+    };
+
+    let game = db
+        .collection::<Document>("game")
+        .find_one(doc! { "lobby_id": lobby_id }, None)
+        .await?;
+    let mut game_doc = match game {
+        Some(game_doc) => game_doc,
+        None => {
+            response.error = String::from("Game does not exist");
+            return Ok((StatusCode::NOT_FOUND, Json(response)));
+        }
+    };
+    creations::upgrade(&mut game_doc).map_err(|err| AppError::Internal(err.to_string()))?;
+    let game = read_game(&game_doc)?;
+
+    if game.player1_id != query.player_id && game.player2_id != query.player_id {
+        response.error = String::from("Player is not in this game");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+    if game.state != GameState::Playing {
+        response.error = format!("Inputs are only handed out while playing, game is {}", game.state);
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    response.input = guest_input(&game).map_err(|err| AppError::Internal(err.to_string()))?;
+    let battle_seed = game.battle_seed.unwrap_or(0);
+    response.game_id = game.id.map(|id| id.to_hex()).unwrap_or_default();
+    response.arena_id = game.arena_id;
+    response.player1_id = game.player1_id;
+    response.creation1 = game.creation1;
+    response.creation1_nonce = game.creation1_nonce.unwrap_or_default();
+    response.player2_id = game.player2_id;
+    response.creation2 = game.creation2;
+    response.creation2_nonce = game.creation2_nonce.unwrap_or_default();
+    response.battle_seed = battle_seed;
+    response.first_attacker = tenet_core::coin_flip(battle_seed);
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Commits a result proven by the client instead of the server, once its receipt verifies against
+// the game's arena
+pub async fn commit_outcome(
+    // this argument tells axum to parse the request body
+    State(state): State<AppState>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::CommitOutcomeInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("commit_outcome called");
+
+    auth.authorize(&payload.player_id)?;
+
+    let mut response = games::CommitOutcomeOutput {
+        error: String::from(""),
+    };
+
+    let game_id = ObjectId::parse_str(&payload.game_id);
+    if game_id.is_err() {
+        response.error = String::from("Invalid game id");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    let game = state
+        .db
+        .collection::<Document>("game")
+        .find_one(
+            doc! {
+                "_id": game_id.unwrap(),
+            },
+            None,
+        )
+        .await?;
+    if game.is_none() {
+        response.error = String::from("Game does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
+    let game = read_game(&game.unwrap())?;
+
+    if game.player1_id != payload.player_id && game.player2_id != payload.player_id {
+        response.error = String::from("Player is not in this game");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    // a result only means something once both creations are locked in, which is also when
+    // the inputs to prove it with are handed out
+    if game.state.is_turn() {
+        response.error = String::from("Game hasn't started, both players have to be ready");
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+    if game.state != GameState::Playing {
+        response.error = String::from("Game is already finished");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    let receipt = risc0_zkvm::Receipt::new(&payload.journal, &payload.seal);
+    let game_result = match check_receipt_blocking(&game, &receipt, true).await {
+        Ok(game_result) => game_result,
+        Err(err) => {
+            response.error = err.to_string();
+            return Ok((StatusCode::BAD_REQUEST, Json(response)));
+        }
+    };
+
+    let committed =
+        store_game_result(&state, &game, &receipt, &game_result, &["playing"], "client").await?;
+    if !committed {
+        response.error = String::from("Game result was already committed");
+        return Ok((StatusCode::CONFLICT, Json(response)));
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn all_games() -> Query<games::GetGamesQuery> {
+        Query(games::GetGamesQuery {
+            limit: None,
+            offset: None,
+            player_id: None,
+            exclude_npc_games: false,
+        })
+    }
+
+    fn play(lobby_id: &str, player_id: &str, creation: Deck, confirm: bool) -> games::PlayGameInput {
+        games::PlayGameInput {
+            lobby_id: lobby_id.to_string(),
+            player_id: player_id.to_string(),
+            creation: Some(creation),
+            deck_id: None,
+            arena_id: arenas::DEFAULT_ARENA_ID.to_string(),
+            idempotency_key: None,
+            claimed_hash: None,
+            confirm,
+        }
+    }
+
+    async fn games_in(db: &Database, lobby_id: &str) -> u64 {
+        db.collection::<Document>("game")
+            .count_documents(doc! { "lobby_id": lobby_id }, None)
+            .await
+            .unwrap()
+    }
+
+    // the audit write is in the background, so it's given a moment to land
+    async fn battles_started(db: &Database, lobby_id: &str) -> u64 {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        db.collection::<Document>("audit")
+            .count_documents(doc! { "lobby_id": lobby_id, "kind": "battle_started" }, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn racing_first_submissions_create_one_game() {
+        let state = testing::app_state(testing::db().await);
+        testing::full_lobby(&state.db, "race", "p1", "p2").await;
+
+        let (first, second) = tokio::join!(
+            submit_creation(state.clone(), play("race", "p1", testing::deck(1), false)),
+            submit_creation(state.clone(), play("race", "p2", testing::deck(2), false)),
+        );
+        let statuses = [first.unwrap().0, second.unwrap().0];
+        assert!(statuses.contains(&StatusCode::OK));
+        assert!(statuses.iter().all(|status| *status == StatusCode::OK || *status == StatusCode::CONFLICT));
+        assert_eq!(games_in(&state.db, "race").await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn racing_confirms_start_one_battle() {
+        let state = testing::app_state(testing::db().await);
+        testing::full_lobby(&state.db, "race", "p1", "p2").await;
+        for (player_id, strength, confirm) in [("p1", 1, false), ("p2", 2, false), ("p1", 1, true)] {
+            let (status, _) =
+                submit_creation(state.clone(), play("race", player_id, testing::deck(strength), confirm))
+                    .await
+                    .unwrap();
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        // player 1 is ready, so both of these would start the battle
+        let (first, second) = tokio::join!(
+            submit_creation(state.clone(), play("race", "p2", testing::deck(2), true)),
+            submit_creation(state.clone(), play("race", "p2", testing::deck(2), true)),
+        );
+        let started = [first.unwrap().0, second.unwrap().0]
+            .iter()
+            .filter(|status| **status == StatusCode::OK)
+            .count();
+        assert_eq!(started, 1);
+        assert_eq!(battles_started(&state.db, "race").await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn maintenance_only_turns_away_the_confirm_that_starts_the_battle() {
+        let state = testing::app_state(testing::db().await);
+        testing::full_lobby(&state.db, "paused", "p1", "p2").await;
+        maintenance::set(&state.db, true, None, "admin").await.unwrap();
+        for (player_id, strength, confirm) in [("p1", 1, false), ("p2", 2, false), ("p1", 1, true)] {
+            let (status, _) =
+                submit_creation(state.clone(), play("paused", player_id, testing::deck(strength), confirm))
+                    .await
+                    .unwrap();
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let last = submit_creation(state.clone(), play("paused", "p2", testing::deck(2), true)).await;
+        assert!(matches!(last, Err(AppError::Unavailable(_))));
+        let game = state.db.collection::<Document>("game").find_one(None, None).await.unwrap().unwrap();
+        assert_eq!(game.get_str("state").unwrap(), GameState::Player2Turn.as_str());
+        assert_eq!(battles_started(&state.db, "paused").await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn self_match_lobby_never_gets_a_game() {
+        let state = testing::app_state(testing::db().await);
+        testing::full_lobby(&state.db, "mirror", "p1", "p1").await;
+
+        let (status, Json(response)) =
+            submit_creation(state.clone(), play("mirror", "p1", testing::deck(1), false))
+                .await
+                .unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code, Some(ErrorCode::SelfMatch));
+        assert_eq!(games_in(&state.db, "mirror").await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn self_match_game_never_moves_on() {
+        let state = testing::app_state(testing::db().await);
+        testing::full_lobby(&state.db, "mirror", "p1", "p1").await;
+        // stored by hand, like one left over from before the check
+        let (game, _) = initial_game_doc(
+            "mirror",
+            "p1",
+            "p1",
+            arenas::DEFAULT_ARENA_ID,
+            &arenas::get(arenas::DEFAULT_ARENA_ID).unwrap(),
+            false,
+            &testing::deck(1),
+        )
+        .unwrap();
+        state.db.collection::<Document>("game").insert_one(game, None).await.unwrap();
+
+        let (status, Json(response)) =
+            submit_creation(state.clone(), play("mirror", "p1", testing::deck(2), false))
+                .await
+                .unwrap();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(response.error_code, Some(ErrorCode::Conflict));
+        let game = state.db.collection::<Document>("game").find_one(None, None).await.unwrap().unwrap();
+        assert_eq!(game.get_str("state").unwrap(), GameState::Player1Turn.as_str());
+    }
+
+    fn playing_game() -> games::Game {
+        read_game(&playing_game_doc()).unwrap()
+    }
+
+    fn playing_game_doc() -> Document {
+        let arena = arenas::get(arenas::DEFAULT_ARENA_ID).unwrap();
+        let (mut game, _) = initial_game_doc(
+            "lobby",
+            "p1",
+            "p2",
+            arenas::DEFAULT_ARENA_ID,
+            &arena,
+            true,
+            &testing::deck(1),
+        )
+        .unwrap();
+        let nonce = new_nonce();
+        game.insert("_id", ObjectId::new());
+        game.insert("creation2", bson::to_bson(&testing::deck(2)).unwrap());
+        game.insert("creation2_hash", tenet_core::hash_creation(&testing::deck(2), &nonce));
+        game.insert("creation2_nonce", nonce);
+        game.insert("state", GameState::Playing);
+        game
+    }
+
+    // The result a player would prove from the inputs they were handed, read the way the guest
+    // reads them. Player 1 wins, what the battle does doesn't matter to the checks.
+    fn self_proven(input: &[u32]) -> tenet_core::GameResult {
+        use serde::Deserialize;
+
+        let bytes: Vec<u8> = input.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut guest = risc0_zkvm::serde::Deserializer::new(&bytes);
+        let player1_id = String::deserialize(&mut guest).unwrap();
+        let creation1 = Deck::deserialize(&mut guest).unwrap();
+        let nonce1 = String::deserialize(&mut guest).unwrap();
+        let player2_id = String::deserialize(&mut guest).unwrap();
+        let creation2 = Deck::deserialize(&mut guest).unwrap();
+        let nonce2 = String::deserialize(&mut guest).unwrap();
+        let battle_seed = u32::deserialize(&mut guest).unwrap();
+        let first_attacker = u8::deserialize(&mut guest).unwrap();
+
+        let creation1_hash = tenet_core::hash_creation(&creation1, &nonce1);
+        tenet_core::GameResult {
+            winner_id: player1_id.clone(),
+            player1_id,
+            player2_id,
+            winner_creation_hash: creation1_hash.clone(),
+            creation1_hash,
+            creation2_hash: tenet_core::hash_creation(&creation2, &nonce2),
+            result: String::from("PLAYER1_WINS"),
+            error: None,
+            timeline: Vec::new(),
+            arena_id: arenas::DEFAULT_ARENA_ID.to_string(),
+            arena_version: arenas::get(arenas::DEFAULT_ARENA_ID).unwrap().version,
+            battle_seed,
+            first_attacker,
+        }
+    }
+
+    // without a seal, as a dev proof would come out
+    fn receipt_of(game_result: &tenet_core::GameResult) -> risc0_zkvm::Receipt {
+        let journal: Vec<u8> =
+            to_vec(game_result).unwrap().iter().flat_map(|word| word.to_le_bytes()).collect();
+        risc0_zkvm::Receipt::new(&journal, &[])
+    }
+
+    #[test]
+    fn receipt_proven_from_the_handed_out_inputs_is_accepted() {
+        let game = playing_game();
+        let game_result = self_proven(&guest_input(&game).unwrap());
+
+        let checked = check_receipt(&game, &receipt_of(&game_result), false).unwrap();
+        assert_eq!(checked.winner_id, "p1");
+        assert_eq!(checked.first_attacker, tenet_core::coin_flip(game.battle_seed.unwrap()));
+    }
+
+    // A playing game between two decks with no attack, nothing but the turn cap ends it
+    fn stalemate_game() -> games::Game {
+        read_game(&stalemate_game_doc()).unwrap()
+    }
+
+    fn stalemate_game_doc() -> Document {
+        mirror_game_doc(tenet_core::Card { health: tenet_core::CARD_STAT_TOTAL, attack: 0 })
+    }
+
+    // Both players on a deck of the same card, so only the coin flip sets them apart
+    fn mirror_game_doc(card: tenet_core::Card) -> Document {
+        let deck = Deck { cards: [card; 5] };
+        let mut game = playing_game_doc();
+        for (field, nonce_field, hash_field) in [
+            ("creation1", "creation1_nonce", "creation1_hash"),
+            ("creation2", "creation2_nonce", "creation2_hash"),
+        ] {
+            let nonce = game.get_str(nonce_field).unwrap().to_string();
+            game.insert(field, bson::to_bson(&deck).unwrap());
+            game.insert(hash_field, tenet_core::hash_creation(&deck, &nonce));
+        }
+        game
+    }
+
+    #[test]
+    fn draw_at_the_turn_cap_is_a_result_not_a_failure() {
+        let game = stalemate_game();
+        let mut game_result = self_proven(&guest_input(&game).unwrap());
+        game_result.winner_id = String::new();
+        game_result.winner_creation_hash = String::new();
+        game_result.result = String::from(tenet_core::RESULT_DRAW);
+
+        let checked = check_receipt(&game, &receipt_of(&game_result), false).unwrap();
+        assert!(checked.is_draw());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the arena methods built"]
+    async fn battle_that_hits_the_turn_cap_is_proven_as_a_draw() {
+        let arena_methods = arenas::load_methods().unwrap();
+        let arena_src = arena_methods.get(arenas::DEFAULT_ARENA_ID).unwrap().clone();
+        let game = stalemate_game();
+
+        let proof_timeout = Duration::from_secs(600);
+        let permit = Arc::new(tokio::sync::Semaphore::new(1)).acquire_owned().await.unwrap();
+        let (receipt, _) = commence_battle(&game, arena_src, permit, proof_timeout, ProofMode::Dev)
+            .await
+            .unwrap();
+        let game_result = check_receipt(&game, &receipt, false).unwrap();
+        assert!(game_result.is_draw());
+        assert_eq!(game_result.result, tenet_core::RESULT_DRAW);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the arena methods built"]
+    async fn coin_flip_decides_a_battle_between_the_same_decks() {
+        let arena_methods = arenas::load_methods().unwrap();
+        let arena_src = arena_methods.get(arenas::DEFAULT_ARENA_ID).unwrap().clone();
+        // every card dies to the first hit it takes, so whoever strikes first never loses one
+        let glass = tenet_core::Card { health: 5, attack: 5 };
+
+        let proof_timeout = Duration::from_secs(600);
+        for (battle_seed, result) in
+            [(0, tenet_core::RESULT_PLAYER1_WINS), (1, tenet_core::RESULT_PLAYER2_WINS)]
+        {
+            let mut game_doc = mirror_game_doc(glass);
+            game_doc.insert("battle_seed", battle_seed);
+            let game = read_game(&game_doc).unwrap();
+            let permit = Arc::new(tokio::sync::Semaphore::new(1)).acquire_owned().await.unwrap();
+            let (receipt, _) =
+                commence_battle(&game, arena_src.clone(), permit, proof_timeout, ProofMode::Dev)
+                    .await
+                    .unwrap();
+            let game_result = check_receipt(&game, &receipt, false).unwrap();
+            assert_eq!(game_result.first_attacker, tenet_core::coin_flip(battle_seed));
+            assert_eq!(game_result.result, result);
+        }
+    }
+
+    // the guest hashes the creations it's handed with the same function, so its journal has to
+    // carry the commitments the server stored
+    #[test]
+    fn guest_hashes_the_creations_as_the_server_committed_them() {
+        let game = playing_game();
+        let game_result = self_proven(&guest_input(&game).unwrap());
+
+        assert_eq!(Some(&game_result.creation1_hash), game.creation1_hash.as_ref());
+        assert_eq!(Some(&game_result.creation2_hash), game.creation2_hash.as_ref());
+    }
+
+    #[test]
+    fn tampered_receipt_is_rejected() {
+        let game = playing_game();
+        let input = guest_input(&game).unwrap();
+
+        let mut stolen_win = self_proven(&input);
+        stolen_win.winner_id = String::from("mallory");
+        let mut other_deck = self_proven(&input);
+        other_deck.creation2_hash = tenet_core::hash_creation(&testing::deck(3), "");
+        let mut rerolled = self_proven(&input);
+        rerolled.battle_seed ^= 1;
+        rerolled.first_attacker = tenet_core::coin_flip(rerolled.battle_seed);
+
+        for tampered in [stolen_win, other_deck, rerolled] {
+            let checked = check_receipt(&game, &receipt_of(&tampered), false);
+            assert!(matches!(checked, Err(BattleError::Mismatch(_))));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn outcome_waits_for_the_game_to_be_playing() {
+        let state = testing::app_state(testing::db().await);
+        testing::full_lobby(&state.db, "early", "p1", "p2").await;
+        submit_creation(state.clone(), play("early", "p1", testing::deck(1), false)).await.unwrap();
+        let game_doc = state.db.collection::<Document>("game").find_one(None, None).await.unwrap().unwrap();
+        let game_id = game_doc.get_object_id("_id").unwrap();
+
+        let query = Query(games::BattleInputsQuery { player_id: String::from("p1") });
+        let inputs = get_battle_inputs(
+            State(state.db.clone()),
+            AuthPlayer(None),
+            Path(String::from("early")),
+            query,
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(inputs.status(), StatusCode::CONFLICT);
+
+        let outcome = games::CommitOutcomeInput {
+            player_id: String::from("p1"),
+            game_id: game_id.to_hex(),
+            journal: Vec::new(),
+            seal: Vec::new(),
+        };
+        let committed = commit_outcome(State(state), AuthPlayer(None), AppJson(outcome))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(committed.status(), StatusCode::CONFLICT);
+    }
+
+    // a finished game between p1 and p2, who played decks 1 and 2
+    async fn finished_game(db: &Database, lobby_id: &str) {
+        let arena = arenas::get(arenas::DEFAULT_ARENA_ID).unwrap();
+        let (mut game, _) = initial_game_doc(
+            lobby_id,
+            "p1",
+            "p2",
+            arenas::DEFAULT_ARENA_ID,
+            &arena,
+            true,
+            &testing::deck(1),
+        )
+        .unwrap();
+        let nonce = new_nonce();
+        game.insert("creation2_hash", tenet_core::hash_creation(&testing::deck(2), &nonce));
+        game.insert("creation2_nonce", nonce);
+        game.insert("creation1", Bson::Null);
+        game.insert("state", GameState::Complete);
+        game.insert("winner_id", "p1");
+        db.collection::<Document>("game").insert_one(game, None).await.unwrap();
+    }
+
+    fn rematch_of(lobby_id: &str, player_id: &str, strength: u32, confirm: bool) -> games::RematchInput {
+        games::RematchInput {
+            player_id: player_id.to_string(),
+            lobby_id: lobby_id.to_string(),
+            creation: Some(testing::deck(strength)),
+            confirm,
+        }
+    }
+
+    async fn rematch_lobby(state: &AppState, input: games::RematchInput) -> String {
+        let response = rematch(State(state.clone()), AuthPlayer(None), AppJson(input))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        testing::body_json(response).await["lobby_id"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn rematch_takes_both_players_creations() {
+        let state = testing::app_state(testing::db().await);
+        finished_game(&state.db, "first").await;
+
+        let lobby_id = rematch_lobby(&state, rematch_of("first", "p1", 1, false)).await;
+        assert_eq!(rematch_lobby(&state, rematch_of("first", "p2", 2, false)).await, lobby_id);
+
+        let game = state.db.collection::<Document>("game").find_one(doc! { "lobby_id": &lobby_id }, None).await.unwrap();
+        let game = read_game(&game.unwrap()).unwrap();
+        assert!(game.creation1_hash.is_some() && game.creation2_hash.is_some());
+        assert!(game.state.is_turn());
+        assert!(!game.player1_ready && !game.player2_ready);
+        assert_eq!(battles_started(&state.db, &lobby_id).await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn rematch_confirmed_by_both_players_starts_the_battle() {
+        let state = testing::app_state(testing::db().await);
+        finished_game(&state.db, "first").await;
+
+        let lobby_id = rematch_lobby(&state, rematch_of("first", "p1", 1, true)).await;
+        let game = state.db.collection::<Document>("game").find_one(doc! { "lobby_id": &lobby_id }, None).await.unwrap();
+        let game = read_game(&game.unwrap()).unwrap();
+        assert!(game.player1_ready && !game.player2_ready);
+        assert!(game.state.is_turn());
+
+        assert_eq!(rematch_lobby(&state, rematch_of("first", "p2", 2, true)).await, lobby_id);
+        assert_eq!(games_in(&state.db, &lobby_id).await, 1);
+        assert_eq!(battles_started(&state.db, &lobby_id).await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn rematch_turns_away_a_different_creation() {
+        let state = testing::app_state(testing::db().await);
+        finished_game(&state.db, "first").await;
+
+        let response = rematch(State(state.clone()), AuthPlayer(None), AppJson(rematch_of("first", "p2", 3, true)))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn malformed_game_is_a_500_with_its_id() {
+        let game_id = ObjectId::new();
+        let err = read_game(&doc! { "_id": game_id, "lobby_id": "broken", "state": 3 }).unwrap_err();
+        assert!(matches!(&err, AppError::Internal(msg) if msg.contains(&game_id.to_hex())));
+        assert_eq!(err.into_response().status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn live_games_list_the_latest_started_first() {
+        let db = testing::db().await;
+        let now = bson::DateTime::now().timestamp_millis();
+        // the older lobby got to its battle last
+        for (lobby_id, created_ago, started_ago) in [("slow", 60_000, 1_000), ("quick", 30_000, 20_000)]
+        {
+            let mut game = playing_game_doc();
+            game.insert("lobby_id", lobby_id);
+            game.insert("created_at", bson::DateTime::from_millis(now - created_ago));
+            game.insert("started_at", bson::DateTime::from_millis(now - started_ago));
+            db.collection::<Document>("game").insert_one(game, None).await.unwrap();
+        }
+
+        let response = get_live_games(State(db), all_games()).await.unwrap().into_response();
+        let body = testing::body_json(response).await;
+        let lobbies: Vec<&str> = body["games"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|game| game["lobby_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(lobbies, ["slow", "quick"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn live_games_fail_on_a_malformed_game() {
+        let db = testing::db().await;
+        db.collection::<Document>("game")
+            .insert_one(doc! { "state": "playing", "lobby_id": "broken" }, None)
+            .await
+            .unwrap();
+
+        let response = match get_live_games(State(db), all_games()).await {
+            Ok(_) => panic!("listed a malformed game"),
+            Err(err) => err.into_response(),
+        };
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = testing::body_json(response).await;
+        assert!(body["error"].as_str().unwrap().contains("malformed"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn feed_fails_on_a_malformed_game() {
+        let db = testing::db().await;
+        db.collection::<Document>("game")
+            .insert_one(doc! { "state": "complete", "lobby_id": "broken", "player1_id": 7 }, None)
+            .await
+            .unwrap();
+
+        let err = get_all_games(State(db), HeaderMap::new(), all_games()).await.unwrap_err();
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = testing::body_json(response).await;
+        assert!(body["error"].as_str().unwrap().contains("malformed"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn only_public_lobbies_are_listed_as_open() {
+        let db = testing::db().await;
+        let now = bson::DateTime::now();
+        db.collection::<Document>("lobby")
+            .insert_many(
+                [
+                    doc! { "lobby_id": "public", "player1_id": "p1", "player2_id": null, "created_at": now },
+                    doc! { "lobby_id": "private", "player1_id": "p2", "player2_id": null, "private": true, "created_at": now },
+                    doc! { "lobby_id": "fallback", "player1_id": "p3", "player2_id": null, "bot_fallback": true, "created_at": now },
+                    doc! { "lobby_id": "npc", "player1_id": "p4", "player2_id": null, "npc_id": "npc1", "created_at": now },
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let query = games::OpenLobbiesQuery { limit: None, offset: None, player_id: None };
+        let response = get_open_lobbies(State(db), Query(query)).await.unwrap().into_response();
+        let body = testing::body_json(response).await;
+        assert_eq!(body["total"], 1);
+        let lobbies = body["lobbies"].as_array().unwrap();
+        assert_eq!(lobbies.len(), 1);
+        assert_eq!(lobbies[0]["lobby_id"], "public");
+    }
+
+    async fn npc(db: &Database) {
+        db.collection::<Document>("npc")
+            .insert_one(
+                doc! { "npc_id": "npc1", "creation": bson::to_bson(&testing::deck(9)).unwrap() },
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    fn npc_play(player_id: &str) -> games::PlayNPCGameInput {
+        games::PlayNPCGameInput {
+            player_id: player_id.to_string(),
+            creation: Some(testing::deck(1)),
+            deck_id: None,
+            npc_id: String::from("npc1"),
+            arena_id: arenas::DEFAULT_ARENA_ID.to_string(),
+            battle_seed: None,
+        }
+    }
+
+    // An NPC game player 1 won, still waiting on its sealed proof, with its battle in the cache
+    async fn unverified_npc_game(db: &Database) -> games::Game {
+        let arena = arenas::get(arenas::DEFAULT_ARENA_ID).unwrap();
+        let (mut game, _) = initial_game_doc(
+            "original",
+            "p0",
+            "npc1",
+            arenas::DEFAULT_ARENA_ID,
+            &arena,
+            true,
+            &testing::deck(1),
+        )
+        .unwrap();
+        let game_id = ObjectId::new();
+        game.insert("_id", game_id);
+        game.insert("creation2_hash", tenet_core::hash_creation(&testing::deck(9), ""));
+        game.insert("state", GameState::Complete);
+        game.insert("winner_id", "p0");
+        game.insert("is_npc_game", true);
+        game.insert("verified", false);
+        db.collection::<Document>("game").insert_one(game.clone(), None).await.unwrap();
+
+        let cached = games::CachedBattle {
+            arena_id: arenas::DEFAULT_ARENA_ID.to_string(),
+            arena_hash: arena.hash(),
+            creation1_hash: tenet_core::hash_creation(&testing::deck(1), ""),
+            creation2_hash: tenet_core::hash_creation(&testing::deck(9), ""),
+            game_id,
+            result: String::from("PLAYER1_WINS"),
+            winner: 1,
+            timeline: Vec::new(),
+            battle_seed: Some(7),
+            seed_chosen: false,
+            verified: false,
+        };
+        proof_cache::store(db, &cached).await.unwrap();
+        read_game(&game).unwrap()
+    }
+
+    async fn game_in(db: &Database, lobby_id: &str) -> Document {
+        db.collection::<Document>("game")
+            .find_one(doc! { "lobby_id": lobby_id }, None)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn npc_battle_proven_before_is_taken_from_the_cache() {
+        let mut state = testing::app_state(testing::db().await);
+        state.rating.rate_npc_games = true;
+        npc(&state.db).await;
+        let original = unverified_npc_game(&state.db).await;
+
+        let (status, response) = start_npc_game(&state, &npc_play("p1"), None).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.state, "complete");
+
+        let game = game_in(&state.db, &response.lobby_id).await;
+        assert_eq!(game.get_object_id("cached_from").unwrap(), original.id.unwrap());
+        assert_eq!(game.get_str("winner_id").unwrap(), "p1");
+        assert!(!game.get_bool("verified").unwrap());
+        let change = game.get_f64("rating_change").unwrap();
+        let rating = ratings::get_rating(&state.db, "p1").await.unwrap();
+        assert!((rating - (ratings::DEFAULT_RATING + change)).abs() < 1e-9);
+
+        // the audit write is in the background, so it's given a moment to land
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let audit = state.db.collection::<Document>("audit");
+        let committed = doc! { "lobby_id": &response.lobby_id, "kind": "result_committed" };
+        assert_eq!(audit.count_documents(committed, None).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn npc_battle_not_proven_before_is_proven() {
+        let state = testing::app_state(testing::db().await);
+        npc(&state.db).await;
+
+        let (status, response) = start_npc_game(&state, &npc_play("p1"), None).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.state, "playing");
+
+        let game = game_in(&state.db, &response.lobby_id).await;
+        assert!(!game.contains_key("cached_from"));
+        assert_eq!(battles_started(&state.db, &response.lobby_id).await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn failed_verification_reverts_the_games_that_reused_it() {
+        let mut state = testing::app_state(testing::db().await);
+        state.rating.rate_npc_games = true;
+        npc(&state.db).await;
+        let original = unverified_npc_game(&state.db).await;
+        let (_, response) = start_npc_game(&state, &npc_play("p1"), None).await.unwrap();
+
+        let battle_error = BattleError::Mismatch(String::from("sealed proof differs"));
+        revert_unverified(&state, &original, &battle_error).await.unwrap();
+
+        let copy = game_in(&state.db, &response.lobby_id).await;
+        assert_eq!(copy.get_str("state").unwrap(), "error");
+        assert!(!copy.contains_key("rating_change"));
+        let rating = ratings::get_rating(&state.db, "p1").await.unwrap();
+        assert!((rating - ratings::DEFAULT_RATING).abs() < 1e-9);
+        let (status, response) = start_npc_game(&state, &npc_play("p2"), None).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.state, "playing");
+    }
+
+    async fn requeue_from(state: &AppState, lobby: Document) -> (StatusCode, serde_json::Value) {
+        let lobby_id = lobby.get_str("lobby_id").unwrap().to_string();
+        state.db.collection::<Document>("lobby").insert_one(lobby, None).await.unwrap();
+        let input = games::RequeueInput { player_id: String::from("p1"), lobby_id };
+        let response = requeue(
+            State(state.db.clone()),
+            State(state.matchmaking),
+            AuthPlayer(None),
+            AppJson(input),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        (response.status(), testing::body_json(response).await)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn waiting_player_is_requeued_into_another_lobby() {
+        let state = testing::app_state(testing::db().await);
+        let lobby = doc! { "lobby_id": "waiting", "player1_id": "p1", "player2_id": null };
+
+        let (status, body) = requeue_from(&state, lobby).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(body["lobby_id"], "waiting");
+        let lobbies = state.db.collection::<Document>("lobby");
+        assert!(lobbies.find_one(doc! { "lobby_id": "waiting" }, None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn lobby_with_an_opponent_or_a_set_opponent_is_not_requeued() {
+        let state = testing::app_state(testing::db().await);
+        let lobbies = [
+            doc! { "lobby_id": "full", "player1_id": "p1", "player2_id": "p2" },
+            doc! { "lobby_id": "npc", "player1_id": "p1", "player2_id": "npc1", "npc_id": "npc1" },
+            doc! { "lobby_id": "series", "player1_id": "p1", "player2_id": null, "series_id": "s1" },
+            doc! { "lobby_id": "private", "player1_id": "p1", "player2_id": null, "private": true },
+        ];
+
+        for lobby in lobbies {
+            let lobby_id = lobby.get_str("lobby_id").unwrap().to_string();
+            let (status, _) = requeue_from(&state, lobby).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", lobby_id);
+            let kept = state.db.collection::<Document>("lobby");
+            assert!(kept.find_one(doc! { "lobby_id": lobby_id }, None).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn interrupted_battle_is_proven_again() {
+        let state = testing::app_state(testing::db().await);
+        let game = playing_game_doc();
+        state.db.collection::<Document>("game").insert_one(game, None).await.unwrap();
+
+        assert_eq!(resume_battles(&state).await.unwrap(), 1);
+        assert_eq!(battles_started(&state.db, "lobby").await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn interrupted_battle_with_a_receipt_is_committed() {
+        let state = testing::app_state(testing::db().await);
+        let game = playing_game_doc();
+        state.db.collection::<Document>("game").insert_one(game.clone(), None).await.unwrap();
+        let game = read_game(&game).unwrap();
+        let receipt = receipt_of(&self_proven(&guest_input(&game).unwrap()));
+        assert!(store_receipt(&state.db, &game, &receipt, "test").await.unwrap());
+
+        assert_eq!(resume_battles(&state).await.unwrap(), 1);
+        assert_eq!(battles_started(&state.db, "lobby").await, 0);
+        let resumed = game_in(&state.db, "lobby").await;
+        assert_eq!(resumed.get_str("state").unwrap(), "complete");
+        assert_eq!(resumed.get_str("winner_id").unwrap(), "p1");
+    }
+
+    async fn join(
+        state: &AppState,
+        player_id: &str,
+        lobby_id: &str,
+        create_new: bool,
+    ) -> (StatusCode, serde_json::Value) {
+        let input = games::JoinGameInput {
+            player_id: player_id.to_string(),
+            lobby_id: lobby_id.to_string(),
+            create_new,
+            bot_fallback: false,
+            join_code: None,
+        };
+        let response = join_game(
+            State(state.db.clone()),
+            State(state.matchmaking),
+            AuthPlayer(None),
+            AppJson(input),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        (response.status(), testing::body_json(response).await)
+    }
+
+    // An open lobby whose creator is rated `rating`, opened `minutes_ago`
+    async fn open_lobby(
+        db: &Database,
+        lobby_id: &str,
+        player1_id: &str,
+        rating: f64,
+        minutes_ago: i64,
+    ) {
+        let created_at = bson::DateTime::from_millis(
+            bson::DateTime::now().timestamp_millis() - minutes_ago * 60_000,
+        );
+        db.collection::<Document>("lobby")
+            .insert_one(
+                doc! {
+                    "lobby_id": lobby_id,
+                    "player1_id": player1_id,
+                    "player1_rating": rating,
+                    "player2_id": null,
+                    "created_at": created_at,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn joining_with_nobody_waiting_opens_a_lobby() {
+        let state = testing::app_state(testing::db().await);
+
+        let (status, body) = join(&state, "p1", "", false).await;
+        assert_eq!(status, StatusCode::OK);
+        let lobby = state
+            .db
+            .collection::<Document>("lobby")
+            .find_one(doc! { "lobby_id": body["lobby_id"].as_str().unwrap() }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(lobby.get_str("player1_id").unwrap(), "p1");
+        assert_eq!(lobby.get("player2_id"), Some(&Bson::Null));
+        assert_eq!(lobby.get_f64("player1_rating").unwrap(), ratings::DEFAULT_RATING);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn joining_prefers_a_lobby_within_the_rating_band() {
+        let state = testing::app_state(testing::db().await);
+        // older, but 800 points away is past even the widened band
+        open_lobby(&state.db, "far", "p2", 2000.0, 10).await;
+        open_lobby(&state.db, "near", "p3", 1210.0, 0).await;
+
+        let (status, body) = join(&state, "p1", "", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["lobby_id"], "near");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn rating_band_widens_the_longer_a_lobby_waits() {
+        let state = testing::app_state(testing::db().await);
+        // 200 points away is past the starting band of 100, but 10 minutes widen it to 350
+        open_lobby(&state.db, "widened", "p2", 1400.0, 10).await;
+        open_lobby(&state.db, "near", "p3", 1210.0, 0).await;
+
+        let (status, body) = join(&state, "p1", "", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["lobby_id"], "widened");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn player_at_the_lobby_cap_gets_their_own_lobby_back() {
+        let state = testing::app_state(testing::db().await);
+        for (lobby_id, minutes_ago) in [("first", 3), ("second", 2), ("third", 1)] {
+            open_lobby(&state.db, lobby_id, "p1", ratings::DEFAULT_RATING, minutes_ago).await;
+        }
+
+        let (status, body) = join(&state, "p1", "", true).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error_code"], "TOO_MANY_LOBBIES");
+        let (status, body) = join(&state, "p1", "", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["lobby_id"], "first");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn joining_a_named_lobby_tells_missing_full_and_joined_apart() {
+        let state = testing::app_state(testing::db().await);
+        testing::full_lobby(&state.db, "full", "p2", "p3").await;
+        open_lobby(&state.db, "open", "p2", ratings::DEFAULT_RATING, 0).await;
+
+        let (status, body) = join(&state, "p1", "missing", false).await;
+        assert_eq!((status, body["error_code"].clone()), (StatusCode::NOT_FOUND, "LOBBY_NOT_FOUND".into()));
+        let (status, body) = join(&state, "p1", "full", false).await;
+        assert_eq!((status, body["error_code"].clone()), (StatusCode::CONFLICT, "LOBBY_FULL".into()));
+        let (status, body) = join(&state, "p3", "full", false).await;
+        assert_eq!((status, body["lobby_id"].clone()), (StatusCode::OK, "full".into()));
+        let (status, body) = join(&state, "p1", "open", false).await;
+        assert_eq!((status, body["lobby_id"].clone()), (StatusCode::OK, "open".into()));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn drawn_battle_is_committed_complete_without_a_winner() {
+        let state = testing::app_state(testing::db().await);
+        let game_doc = stalemate_game_doc();
+        state.db.collection::<Document>("game").insert_one(game_doc.clone(), None).await.unwrap();
+        let game = read_game(&game_doc).unwrap();
+
+        let mut game_result = self_proven(&guest_input(&game).unwrap());
+        game_result.winner_id = String::new();
+        game_result.winner_creation_hash = String::new();
+        game_result.result = String::from(tenet_core::RESULT_DRAW);
+        commit_game_result(&state, &game, &receipt_of(&game_result)).await.unwrap();
+
+        let committed = game_in(&state.db, "lobby").await;
+        assert_eq!(committed.get_str("state").unwrap(), "complete");
+        assert_eq!(committed.get("winner_id"), Some(&Bson::Null));
+        assert_eq!(committed.get_str("result").unwrap(), tenet_core::RESULT_DRAW);
+        // evenly rated players draw without either rating moving
+        assert_eq!(committed.get_f64("rating_change").unwrap(), 0.0);
+        assert_eq!(ratings::get_rating(&state.db, "p1").await.unwrap(), ratings::DEFAULT_RATING);
+    }
+
+    // A deck that breaks the deck rules at card 2
+    fn deck_without_health() -> Deck {
+        let mut creation = testing::deck(1);
+        creation.cards[2] = tenet_core::Card { health: 0, attack: tenet_core::CARD_STAT_TOTAL };
+        creation
+    }
+
+    #[test]
+    fn creation_breaking_the_deck_rules_is_turned_away() {
+        let mut wrong_total = testing::deck(1);
+        wrong_total.cards[0].attack += 1;
+        // adding these unchecked would wrap around to a valid total
+        let mut out_of_range = testing::deck(1);
+        out_of_range.cards[4] = tenet_core::Card {
+            health: u32::MAX,
+            attack: tenet_core::CARD_STAT_TOTAL + 1,
+        };
+
+        for creation in [deck_without_health(), wrong_total, out_of_range] {
+            let (status, code, _) = check_creation(&creation).unwrap_err();
+            assert_eq!((status, code), (StatusCode::BAD_REQUEST, ErrorCode::InvalidCreation));
+        }
+    }
+
+    #[test]
+    fn creation_of_the_same_card_five_times_is_accepted() {
+        let card = testing::deck(1).cards[0];
+        assert_eq!(check_creation(&Deck { cards: [card; 5] }), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn creation_without_five_cards_is_a_400() {
+        use axum::extract::FromRequest;
+
+        for cards in [vec![], vec![serde_json::json!({ "health": 5, "attack": 5 }); 6]] {
+            let body = serde_json::json!({
+                "lobby_id": "lobby",
+                "player_id": "p1",
+                "creation": { "cards": cards },
+            });
+            let request = axum::http::Request::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap();
+            let rejection = AppJson::<games::PlayGameInput>::from_request(request, &())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(rejection.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn invalid_creation_is_turned_away_before_the_game_is_made() {
+        let state = testing::app_state(testing::db().await);
+        testing::full_lobby(&state.db, "lobby", "p1", "p2").await;
+
+        let input = play("lobby", "p1", deck_without_health(), false);
+        let (status, Json(response)) = submit_creation(state.clone(), input).await.unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code, Some(ErrorCode::InvalidCreation));
+        assert_eq!(games_in(&state.db, "lobby").await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn submission_to_a_game_that_has_ended_is_a_400() {
+        for ended in [GameState::Error, GameState::Abandoned, GameState::Cancelled] {
+            let state = testing::app_state(testing::db().await);
+            testing::full_lobby(&state.db, "lobby", "p1", "p2").await;
+            let mut game = playing_game_doc();
+            game.insert("state", ended);
+            state.db.collection::<Document>("game").insert_one(game, None).await.unwrap();
+
+            let input = play("lobby", "p1", testing::deck(1), false);
+            let (status, Json(response)) = submit_creation(state.clone(), input).await.unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", ended);
+            assert_eq!(response.error_code, Some(ErrorCode::GameEnded), "{}", ended);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn result_failing_verification_is_reverted_with_its_rating_change() {
+        let state = testing::app_state(testing::db().await);
+        let change = ratings::apply_result(&state.db, "p1", "p2").await.unwrap();
+        let mut game_doc = playing_game_doc();
+        game_doc.insert("state", GameState::Complete);
+        game_doc.insert("winner_id", "p1");
+        game_doc.insert("verified", false);
+        game_doc.insert("rating_change", change);
+        state.db.collection::<Document>("game").insert_one(game_doc.clone(), None).await.unwrap();
+
+        let battle_error = BattleError::Mismatch(String::from("sealed proof differs"));
+        let game = read_game(&game_doc).unwrap();
+        revert_unverified(&state, &game, &battle_error).await.unwrap();
+
+        let reverted = game_in(&state.db, "lobby").await;
+        assert_eq!(reverted.get_str("state").unwrap(), "error");
+        assert!(!reverted.contains_key("rating_change"));
+        for player_id in ["p1", "p2"] {
+            let rating = ratings::get_rating(&state.db, player_id).await.unwrap();
+            assert!((rating - ratings::DEFAULT_RATING).abs() < 1e-9, "{}", player_id);
+        }
+
+        // a second failure for the same game finds nothing left to take back
+        revert_unverified(&state, &game, &battle_error).await.unwrap();
+        let rating = ratings::get_rating(&state.db, "p1").await.unwrap();
+        assert!((rating - ratings::DEFAULT_RATING).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn second_submission_for_another_arena_is_a_400() {
+        let state = testing::app_state(testing::db().await);
+        testing::full_lobby(&state.db, "lobby", "p1", "p2").await;
+        // only one arena is registered, so player 1's game stands in for one created in another
+        let arena = arenas::get(arenas::DEFAULT_ARENA_ID).unwrap();
+        let (game, _) =
+            initial_game_doc("lobby", "p1", "p2", "tenet_arena_2", &arena, true, &testing::deck(1))
+                .unwrap();
+        state.db.collection::<Document>("game").insert_one(game, None).await.unwrap();
+
+        let input = play("lobby", "p2", testing::deck(2), false);
+        let (status, Json(response)) = submit_creation(state.clone(), input).await.unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code, Some(ErrorCode::ArenaMismatch));
+        let game = game_in(&state.db, "lobby").await;
+        assert_eq!(game.get_str("arena_id").unwrap(), "tenet_arena_2");
+        assert_eq!(game.get("creation2"), Some(&Bson::Null));
+    }
+
+    // An NPC battle whose roster deck was swapped for deck 2 after the game recorded deck 9
+    fn npc_game_with_a_swapped_deck_doc() -> Document {
+        let mut game = playing_game_doc();
+        game.insert("is_npc_game", true);
+        game.insert("npc_deck_hash", tenet_core::hash_creation(&testing::deck(9), ""));
+        game
+    }
+
+    #[test]
+    fn npc_result_for_another_deck_than_the_roster_one_is_rejected() {
+        let game = read_game(&npc_game_with_a_swapped_deck_doc()).unwrap();
+        let receipt = receipt_of(&self_proven(&guest_input(&game).unwrap()));
+        assert!(matches!(check_receipt(&game, &receipt, false), Err(BattleError::Mismatch(_))));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn npc_battle_fought_after_a_roster_edit_is_not_committed() {
+        let state = testing::app_state(testing::db().await);
+        npc(&state.db).await;
+        let game_doc = npc_game_with_a_swapped_deck_doc();
+        state.db.collection::<Document>("game").insert_one(game_doc.clone(), None).await.unwrap();
+        let game = read_game(&game_doc).unwrap();
+        // the roster now holds the deck the battle was fought with, not the one the game recorded
+        state
+            .db
+            .collection::<Document>("npc")
+            .update_one(
+                doc! { "npc_id": "npc1" },
+                doc! { "$set": { "creation": bson::to_bson(&testing::deck(2)).unwrap() } },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let receipt = receipt_of(&self_proven(&guest_input(&game).unwrap()));
+        commit_game_result(&state, &game, &receipt).await.unwrap();
+
+        let rejected = game_in(&state.db, "lobby").await;
+        assert_eq!(rejected.get_str("state").unwrap(), "error");
+        assert_eq!(rejected.get("winner_id"), Some(&Bson::Null));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn invalid_creation_against_an_npc_is_a_400() {
+        let state = testing::app_state(testing::db().await);
+        npc(&state.db).await;
+        let mut input = npc_play("p1");
+        input.creation = Some(deck_without_health());
+
+        let (status, response) = start_npc_game(&state, &input, None).await.unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code, Some(ErrorCode::InvalidCreation));
+        let lobbies = state.db.collection::<Document>("lobby");
+        assert_eq!(lobbies.count_documents(None, None).await.unwrap(), 0);
+        let games = state.db.collection::<Document>("game");
+        assert_eq!(games.count_documents(None, None).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn new_lobbies_are_opened_up_to_the_cap_and_no_further() {
+        let state = testing::app_state(testing::db().await);
+        let mut opened = Vec::new();
+        for _ in 0..state.matchmaking.max_open_lobbies {
+            let (status, body) = join(&state, "p1", "", true).await;
+            assert_eq!(status, StatusCode::OK);
+            opened.push(body["lobby_id"].as_str().unwrap().to_string());
+        }
+        opened.sort();
+        opened.dedup();
+        assert_eq!(opened.len() as u64, state.matchmaking.max_open_lobbies);
+
+        let (status, body) = join(&state, "p1", "", true).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error_code"], "TOO_MANY_LOBBIES");
+        let lobbies = state.db.collection::<Document>("lobby");
+        let count = lobbies.count_documents(doc! { "player1_id": "p1" }, None).await.unwrap();
+        assert_eq!(count, state.matchmaking.max_open_lobbies);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn racing_new_lobbies_never_go_over_the_cap() {
+        let state = testing::app_state(testing::db().await);
+        for _ in 1..state.matchmaking.max_open_lobbies {
+            join(&state, "p1", "", true).await;
+        }
+
+        // both pass the first count with one lobby left under the cap
+        let (first, second) =
+            tokio::join!(join(&state, "p1", "", true), join(&state, "p1", "", true));
+        assert!([first.0, second.0].contains(&StatusCode::CONFLICT));
+        let lobbies = state.db.collection::<Document>("lobby");
+        let count = lobbies.count_documents(doc! { "player1_id": "p1" }, None).await.unwrap();
+        assert!(count <= state.matchmaking.max_open_lobbies);
+    }
+
+    #[tokio::test]
+    async fn timed_out_proof_keeps_its_slot_until_the_prover_exits() {
+        let slots = Arc::new(tokio::sync::Semaphore::new(1));
+        let permit = slots.clone().acquire_owned().await.unwrap();
+        // a prover that never returns on its own, only once the test lets it go
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let proven = prove_in_slot(permit, Duration::from_millis(50), move || {
+            let _ = released.recv();
+            Ok(())
+        });
+
+        assert!(matches!(proven.await, Err(BattleError::TimedOut(_))));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(slots.available_permits(), 0);
+
+        drop(release);
+        let freed = tokio::time::timeout(Duration::from_secs(5), slots.acquire()).await;
+        assert!(freed.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI and the arena methods built"]
+    async fn timed_out_battle_keeps_its_proof_slot_until_its_prover_exits() {
+        let mut state = testing::app_state(testing::db().await);
+        state.arena_methods = arenas::load_methods().unwrap();
+        // a sealed proof takes far longer than this, so the battle always times out with its
+        // prover still running
+        state.proof_mode = ProofMode::Prod;
+        state.proof_timeout = Duration::from_millis(1);
+        let game = playing_game_doc();
+        state.db.collection::<Document>("game").insert_one(game.clone(), None).await.unwrap();
+
+        spawn_battle(state.clone(), read_game(&game).unwrap());
+        while game_in(&state.db, "lobby").await.get_str("state").unwrap() == "playing" {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let failed = game_in(&state.db, "lobby").await;
+        assert_eq!(failed.get_str("state").unwrap(), "error");
+        assert_eq!(state.proof_slots.available_permits(), 0);
+        let freed = tokio::time::timeout(Duration::from_secs(600), state.proof_slots.acquire());
+        assert!(freed.await.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn duplicate_commit_never_replaces_the_stored_receipt() {
+        let state = testing::app_state(testing::db().await);
+        let game_doc = playing_game_doc();
+        state.db.collection::<Document>("game").insert_one(game_doc.clone(), None).await.unwrap();
+        let game = read_game(&game_doc).unwrap();
+        // a sealed receipt, store_game_result trusts it was verified already
+        let sealed = |game_result: &tenet_core::GameResult| {
+            risc0_zkvm::Receipt::new(&receipt_of(game_result).journal, &[1])
+        };
+        let first = self_proven(&guest_input(&game).unwrap());
+        let mut other = self_proven(&guest_input(&game).unwrap());
+        other.winner_id = String::from("p2");
+        other.winner_creation_hash = other.creation2_hash.clone();
+        other.result = String::from(tenet_core::RESULT_PLAYER2_WINS);
+
+        let (first_receipt, other_receipt) = (sealed(&first), sealed(&other));
+
+        // the first commit got its receipt in, the duplicate for another result loses
+        assert!(store_receipt(&state.db, &game, &first_receipt, "test").await.unwrap());
+        let playing = &["playing"];
+        let stored = store_game_result(&state, &game, &other_receipt, &other, playing, "test");
+        assert!(!stored.await.unwrap());
+        let stored = store_game_result(&state, &game, &first_receipt, &first, playing, "test");
+        assert!(stored.await.unwrap());
+
+        let committed = game_in(&state.db, "lobby").await;
+        assert_eq!(committed.get_str("winner_id").unwrap(), "p1");
+        let receipts = state.db.collection::<Document>("receipt");
+        let receipt = receipts.find_one(doc! { "game_id": game.id }, None).await.unwrap().unwrap();
+        let receipt = bson::from_document::<games::Receipt>(receipt).unwrap();
+        assert_eq!(receipt.journal, first_receipt.journal);
+    }
 }
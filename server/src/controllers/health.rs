@@ -0,0 +1,59 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+
+// DB
+use mongodb::bson::doc;
+
+use crate::arenas;
+use crate::maintenance;
+use crate::state::AppState;
+
+// Liveness, the process is up and answering
+pub async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, Json(serde_json::json!({ "status": "ok" })))
+}
+
+// Readiness, the database answers and every arena method can be read, otherwise the first
+// battle would fail. Maintenance is reported but doesn't make the server unready, reads and
+// joins still need it in rotation.
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let mut errors: Vec<String> = Vec::new();
+
+    if let Err(err) = state.db.run_command(doc! { "ping": 1 }, None).await {
+        errors.push(format!("Database unreachable: {}", err));
+    }
+
+    let maintenance = match maintenance::current(&state).await {
+        Ok(maintenance) => Some(maintenance),
+        Err(err) => {
+            errors.push(format!("Maintenance setting can't be read: {}", err));
+            None
+        }
+    };
+
+    for (arena_id, arena) in arenas::registry() {
+        if let Err(err) = std::fs::File::open(arenas::method_path(arena_id, &arena)) {
+            errors.push(format!("Arena {} method can't be read: {}", arena_id, err));
+        }
+    }
+
+    if errors.is_empty() {
+        return (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "status": "ready",
+                "errors": errors,
+                "maintenance": maintenance,
+            })),
+        );
+    }
+
+    tracing::warn!("Not ready: {}", errors.join(", "));
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(serde_json::json!({
+            "status": "unavailable",
+            "errors": errors,
+            "maintenance": maintenance,
+        })),
+    )
+}
@@ -0,0 +1,67 @@
+use axum::{extract::Query, extract::State, http::StatusCode, response::IntoResponse, Json};
+
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::options::FindOptions;
+use mongodb::Database;
+
+use crate::errors::AppError;
+use crate::models::games;
+
+// Page size bounds for the roster
+const DEFAULT_NPC_LIMIT: i64 = 20;
+const MAX_NPC_LIMIT: i64 = 100;
+
+// The NPCs players can pick to battle, easiest first. Their decks are left out.
+pub async fn get_npcs(
+    State(db): State<Database>,
+    Query(query): Query<games::NpcsQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_npcs called");
+
+    let limit = query.limit.unwrap_or(DEFAULT_NPC_LIMIT).clamp(1, MAX_NPC_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+
+    let mut filter = Document::new();
+    if let Some(arena_id) = &query.arena_id {
+        filter.insert("arena_id", arena_id.clone());
+    }
+
+    let npcs_ref = db.collection::<Document>("npc");
+    let total = npcs_ref.count_documents(filter.clone(), None).await?;
+    let easiest_first = FindOptions::builder()
+        .sort(doc! { "difficulty": 1, "npc_id": 1 })
+        .skip(offset)
+        .limit(limit)
+        .build();
+    let mut cursor = npcs_ref.find(filter, easiest_first).await?;
+    let mut npcs: Vec<games::NpcEntry> = Vec::new();
+
+    while cursor.advance().await? {
+        let npc = bson::to_bson(&cursor.deserialize_current()?)?;
+        let npc = match bson::from_bson::<games::Npc>(npc) {
+            Ok(npc) => npc,
+            Err(err) => {
+                tracing::warn!("Skipping malformed NPC document: {}", err);
+                continue;
+            }
+        };
+        npcs.push(games::NpcEntry {
+            name: npc.name.unwrap_or_else(|| npc.npc_id.clone()),
+            npc_id: npc.npc_id,
+            difficulty: npc.difficulty,
+            arena_id: npc.arena_id,
+        });
+    }
+
+    let next_offset = offset + limit as u64;
+    let response = games::GetNpcsOutput {
+        npcs,
+        total,
+        next_offset: if next_offset < total { Some(next_offset) } else { None },
+        error: String::from(""),
+    };
+
+    Ok((StatusCode::OK, Json(response)))
+}
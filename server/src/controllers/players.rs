@@ -1,14 +1,30 @@
-use axum::{extract::State, extract::Query, http::StatusCode, response::IntoResponse, Json};
+use axum::{extract::Path, extract::State, extract::Query, http::StatusCode, response::IntoResponse, Json};
 
 // DB
 use mongodb::bson::doc;
 use mongodb::bson::Document;
-use mongodb::{Collection, Database};
+use mongodb::options::FindOptions;
+use mongodb::Database;
 
+use crate::auth::AuthPlayer;
+use crate::controllers::games::read_game;
+use crate::errors::AppError;
 use crate::models::games;
+use crate::ratings;
 
-pub async fn get_player_games(State(db): State<Database>, player_info: Query<games::PlayerInfo>) -> impl IntoResponse {
-    tracing::info!("get_player_games called");
+// Page size bounds for the leaderboard and a player's history
+const DEFAULT_LEADERBOARD_LIMIT: i64 = 20;
+const MAX_LEADERBOARD_LIMIT: i64 = 100;
+const DEFAULT_HISTORY_LIMIT: i64 = 20;
+const MAX_HISTORY_LIMIT: i64 = 100;
+// How many finished games a profile shows
+const PROFILE_RECENT_GAMES: i64 = 10;
+
+// Every lobby the player is in, with its game or a placeholder if no game has started yet
+pub async fn get_player_lobbies(State(db): State<Database>, auth: AuthPlayer, player_info: Query<games::PlayerInfo>) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_player_lobbies called");
+
+    auth.authorize(&player_info.player_id)?;
 
     // get lobbies the player is in, either player1_id field is player_id or player2_id field is player_id
     let lobbies = db.collection::<Document>("lobby");
@@ -22,69 +38,357 @@ pub async fn get_player_games(State(db): State<Database>, player_info: Query<gam
             },
             None,
         )
-        .await
-        .unwrap();
+        .await?;
 
-    let mut games: Vec<games::Game> = Vec::new();
+    let mut games: Vec<games::PlayerLobby> = Vec::new();
 
       // go through each document
-      while cursor.advance().await.unwrap() {
-        let lobby = bson::to_bson(&cursor.deserialize_current().unwrap()).unwrap();
-        let mut lobby = bson::from_bson::<games::Lobby>(lobby).unwrap();
+      while cursor.advance().await? {
+        let lobby = bson::to_bson(&cursor.deserialize_current()?)?;
+        let lobby = bson::from_bson::<games::Lobby>(lobby)?;
         // get single game object for this lobby if it exists
         let games_ref = db.collection::<Document>("game");
-        let mut game_for_lobby = games_ref
+        let game_for_lobby = games_ref
             .find_one(
                 doc! {
                     "lobby_id": lobby.lobby_id.clone()
                 },
                 None,
             )
-            .await
-            .unwrap();
+            .await?;
 
-        if game_for_lobby.is_some() {
-            let game = bson::to_bson(&game_for_lobby.unwrap()).unwrap();
-            let mut game = bson::from_bson::<games::Game>(game).unwrap();
+        if let Some(game_doc) = game_for_lobby {
+            let mut game = read_game(&game_doc)?;
             game.id = None;
             game.creation1 = None;
             game.creation2 = None;
-            games.push(game);
+            game.timeline = Vec::new();
+            game.journal = None;
+            games.push(games::PlayerLobby::Game(Box::new(game)));
         } else {
-            // make game for lobby
-            let mut game = games::Game {
-                id: None,
+            // stands in for the game until one starts
+            let mut pending = games::PendingLobby {
                 lobby_id: lobby.lobby_id.clone(),
-                creation1: None,
-                creation1_hash: None,
-                creation2: None,
-                creation2_hash: None,
-                player1_id: "".to_string(),
-                player2_id: "".to_string(),
-                arena_hash: "".to_string(),
-                winner_creation_hash: None,
-                winner_id: None,
-                state: "lobby".to_string(),
-                result: None,
+                player1_id: lobby.player1_id.unwrap_or_default(),
+                player2_id: lobby.player2_id.unwrap_or_default(),
+                state: games::LobbyState::Lobby,
             };
-            if lobby.player1_id.is_some() {
-                game.player1_id = lobby.player1_id.unwrap().clone();
+            if !pending.player1_id.is_empty() && !pending.player2_id.is_empty() {
+                pending.state = games::LobbyState::Setup;
+            }
+
+            games.push(games::PlayerLobby::Pending(pending));
+        }
+    }
+
+    let response = games::PlayerGamesOutput {
+        games,
+        error: String::from(""),
+    };
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// The player's finished games, newest first
+pub async fn get_player_games(
+    State(db): State<Database>,
+    auth: AuthPlayer,
+    Path(player_id): Path<String>,
+    Query(query): Query<games::PlayerGamesQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_player_games called");
+
+    auth.authorize(&player_id)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, MAX_HISTORY_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+
+    let filter = doc! {
+        "state": "complete",
+        "$or": [
+            { "player1_id": player_id.clone() },
+            { "player2_id": player_id.clone() },
+        ],
+    };
+
+    let games_ref = db.collection::<Document>("game");
+    let total = games_ref.count_documents(filter.clone(), None).await?;
+    let newest_first = FindOptions::builder()
+        .sort(doc! { "_id": -1 })
+        .skip(offset)
+        .limit(limit)
+        .build();
+    let mut cursor = games_ref.find(filter, newest_first).await?;
+    let mut games: Vec<games::Game> = Vec::new();
+
+    while cursor.advance().await? {
+        let mut game = read_game(&cursor.deserialize_current()?)?;
+        game.id = None;
+        // only ever hand back the player's own deck, never the opponent's
+        if game.player1_id == player_id {
+            game.creation2 = None;
+        } else {
+            game.creation1 = None;
+        }
+        game.timeline = Vec::new();
+        game.journal = None;
+        games.push(game);
+    }
+
+    let next_offset = offset + limit as u64;
+    let response = games::GetGamesOutput {
+        games,
+        total,
+        next_offset: if next_offset < total { Some(next_offset) } else { None },
+        error: String::from(""),
+        error_code: None,
+    };
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// A player's rating, record and latest games for their profile. A player who never finished a
+// game gets an empty profile rather than a 404.
+pub async fn get_player(
+    State(db): State<Database>,
+    Path(player_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_player called");
+
+    let player = db
+        .collection::<Document>("player")
+        .find_one(doc! { "player_id": player_id.clone() }, None)
+        .await?;
+    let rating = player.and_then(|player| player.get_f64("rating").ok());
+
+    let is_player_1 = doc! { "$eq": ["$player1_id", player_id.clone()] };
+    let pipeline = vec![
+        doc! {
+            "$match": {
+                "state": "complete",
+                "$or": [
+                    { "player1_id": player_id.clone() },
+                    { "player2_id": player_id.clone() },
+                ],
             }
-            if lobby.player2_id.is_some() {
-                game.player2_id = lobby.player2_id.unwrap().clone();
+        },
+        doc! {
+            "$project": {
+                "lobby_id": 1,
+                "arena_id": 1,
+                "is_npc_game": { "$ifNull": ["$is_npc_game", false] },
+                "finished_at": "$updated_at",
+                "opponent_id": { "$cond": [is_player_1, "$player2_id", "$player1_id"] },
+                "outcome": {
+                    "$switch": {
+                        "branches": [
+                            {
+                                "case": { "$eq": [{ "$ifNull": ["$winner_id", null] }, null] },
+                                "then": "draw",
+                            },
+                            {
+                                "case": { "$eq": ["$winner_id", player_id.clone()] },
+                                "then": "win",
+                            },
+                        ],
+                        "default": "loss",
+                    }
+                },
             }
-            if !game.player1_id.is_empty() && !game.player2_id.is_empty() {
-                game.state = "setup".to_string();
+        },
+        doc! {
+            "$facet": {
+                "record": [{
+                    "$group": {
+                        "_id": null,
+                        "games": { "$sum": 1 },
+                        "wins": { "$sum": { "$cond": [{ "$eq": ["$outcome", "win"] }, 1, 0] } },
+                        "draws": { "$sum": { "$cond": [{ "$eq": ["$outcome", "draw"] }, 1, 0] } },
+                    }
+                }],
+                "recent": [
+                    { "$sort": { "_id": -1 } },
+                    { "$limit": PROFILE_RECENT_GAMES },
+                    { "$project": { "_id": 0 } },
+                ],
             }
+        },
+    ];
 
-            games.push(game);
+    let games_ref = db.collection::<Document>("game");
+    let mut cursor = games_ref.aggregate(pipeline, None).await?;
+    let page = if cursor.advance().await? {
+        cursor.deserialize_current()?
+    } else {
+        Document::new()
+    };
+
+    // a player with no finished games has no record document at all
+    let record = page
+        .get_array("record")
+        .ok()
+        .and_then(|record| record.first())
+        .and_then(|record| record.as_document())
+        .cloned()
+        .unwrap_or_default();
+    let count = |field: &str| record.get_i32(field).unwrap_or(0) as i64;
+    let (games, wins, draws) = (count("games"), count("wins"), count("draws"));
+
+    let mut recent_games = Vec::new();
+    if let Ok(entries) = page.get_array("recent") {
+        for entry in entries {
+            recent_games.push(bson::from_bson::<games::GameSummary>(entry.clone())?);
         }
     }
 
-    let response = games::PlayerGamesOutput {
-        games: games,
+    let response = games::PlayerProfileOutput {
+        player_id,
+        rating,
+        games,
+        wins,
+        losses: games - wins - draws,
+        draws,
+        recent_games,
         error: String::from(""),
     };
 
-    (StatusCode::OK, Json(response))
-}
\ No newline at end of file
+    Ok((StatusCode::OK, Json(response)))
+}
+
+pub async fn get_leaderboard(
+    State(db): State<Database>,
+    Query(query): Query<games::LeaderboardQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_leaderboard called");
+
+    let limit = query.limit.unwrap_or(DEFAULT_LEADERBOARD_LIMIT).clamp(1, MAX_LEADERBOARD_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+
+    let mut filter = doc! {
+        "state": "complete"
+    };
+    if query.exclude_npc_games {
+        filter.insert("is_npc_game", doc! { "$ne": true });
+    }
+
+    let sort = match query.sort {
+        games::LeaderboardSort::Wins => doc! { "wins": -1, "win_rate": -1, "player_id": 1 },
+        games::LeaderboardSort::WinRate => doc! { "win_rate": -1, "wins": -1, "player_id": 1 },
+        games::LeaderboardSort::Rating => doc! { "rating": -1, "player_id": 1 },
+    };
+
+    // tally games and wins per player in the database, NPCs don't get a place on the board
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! {
+            "$project": {
+                "winner_id": 1,
+                "players": {
+                    "$cond": [
+                        { "$eq": ["$is_npc_game", true] },
+                        ["$player1_id"],
+                        ["$player1_id", "$player2_id"],
+                    ]
+                },
+            }
+        },
+        doc! { "$unwind": "$players" },
+        doc! {
+            "$group": {
+                "_id": "$players",
+                "games": { "$sum": 1 },
+                "wins": { "$sum": { "$cond": [{ "$eq": ["$players", "$winner_id"] }, 1, 0] } },
+            }
+        },
+        doc! {
+            "$lookup": {
+                "from": "player",
+                "localField": "_id",
+                "foreignField": "player_id",
+                "as": "player",
+            }
+        },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "player_id": "$_id",
+                "games": 1,
+                "wins": 1,
+                "win_rate": { "$divide": ["$wins", "$games"] },
+                "rating": {
+                    "$ifNull": [{ "$arrayElemAt": ["$player.rating", 0] }, ratings::DEFAULT_RATING]
+                },
+            }
+        },
+        doc! { "$sort": sort },
+        doc! {
+            "$facet": {
+                "total": [{ "$count": "count" }],
+                "players": [{ "$skip": offset as i64 }, { "$limit": limit }],
+            }
+        },
+    ];
+
+    let games_ref = db.collection::<Document>("game");
+    let mut cursor = games_ref.aggregate(pipeline, None).await?;
+    let page = if cursor.advance().await? {
+        cursor.deserialize_current()?
+    } else {
+        Document::new()
+    };
+
+    let total = page
+        .get_array("total")
+        .ok()
+        .and_then(|total| total.first())
+        .and_then(|total| total.as_document())
+        .and_then(|total| total.get_i32("count").ok())
+        .unwrap_or(0) as u64;
+    let mut players = Vec::new();
+    if let Ok(entries) = page.get_array("players") {
+        for entry in entries {
+            players.push(bson::from_bson::<games::LeaderboardEntry>(entry.clone())?);
+        }
+    }
+
+    let next_offset = offset + limit as u64;
+    let response = games::LeaderboardOutput {
+        players,
+        total,
+        next_offset: if next_offset < total { Some(next_offset) } else { None },
+        error: String::from(""),
+    };
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn history_fails_on_a_malformed_game() {
+        let db = testing::db().await;
+        db.collection::<Document>("game")
+            .insert_one(
+                doc! { "state": "complete", "lobby_id": "broken", "player1_id": "p1" },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let query = Query(games::PlayerGamesQuery { limit: None, offset: None });
+        let response =
+            match get_player_games(State(db), AuthPlayer(None), Path(String::from("p1")), query)
+                .await
+            {
+                Ok(_) => panic!("listed a malformed game"),
+                Err(err) => err.into_response(),
+            };
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = testing::body_json(response).await;
+        assert!(body["error"].as_str().unwrap().contains("malformed"));
+    }
+}
@@ -0,0 +1,226 @@
+use axum::{extract::Path, extract::State, http::StatusCode, response::IntoResponse, Json};
+
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb::Database;
+
+use crate::auth::AuthPlayer;
+use crate::controllers::games::create_lobby;
+use crate::errors::AppError;
+use crate::extract::AppJson;
+use crate::models::games;
+
+// Longest series that can be asked for, every game of it gets proven
+const MAX_SERIES_LENGTH: u32 = 9;
+
+// Starts a best-of-N between two players along with the lobby for its first game
+pub async fn create_series(
+    State(db): State<Database>,
+    auth: AuthPlayer,
+    AppJson(payload): AppJson<games::SeriesInput>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("create_series called");
+
+    auth.authorize(&payload.player_id)?;
+
+    let mut response = games::SeriesOutput {
+        series_id: String::from(""),
+        lobby_id: String::from(""),
+        error: String::from(""),
+    };
+
+    if payload.best_of % 2 == 0 || payload.best_of < 3 || payload.best_of > MAX_SERIES_LENGTH {
+        response.error = format!(
+            "A series has to be best of an odd number of games from 3 to {}",
+            MAX_SERIES_LENGTH
+        );
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    if payload.player_id == payload.opponent_id {
+        response.error = String::from("A player can't play against themselves");
+        return Ok((StatusCode::BAD_REQUEST, Json(response)));
+    }
+
+    let series_ref = db.collection::<Document>("series");
+    let insert_result = series_ref
+        .insert_one(
+            doc! {
+                "player1_id": payload.player_id.clone(),
+                "player2_id": payload.opponent_id.clone(),
+                "best_of": payload.best_of,
+                "player1_wins": 0,
+                "player2_wins": 0,
+                "state": "active",
+                "winner_id": null,
+                "lobby_ids": [],
+                "counted_lobby_ids": [],
+                "created_at": bson::DateTime::now(),
+                "updated_at": bson::DateTime::now(),
+            },
+            None,
+        )
+        .await?;
+    let series_id = insert_result
+        .inserted_id
+        .as_object_id()
+        .ok_or_else(|| AppError::Internal(String::from("Inserted series has no ObjectId")))?;
+
+    let lobby_id = create_lobby(
+        &db,
+        doc! {
+            "player1_id": payload.player_id,
+            "player2_id": payload.opponent_id,
+            "series_id": series_id.to_string(),
+        },
+    )
+    .await?;
+
+    series_ref
+        .update_one(
+            doc! { "_id": series_id },
+            doc! {
+                "$set": {
+                    "series_id": series_id.to_string(),
+                    "current_lobby_id": lobby_id.clone(),
+                },
+                "$push": { "lobby_ids": lobby_id.clone() },
+            },
+            None,
+        )
+        .await?;
+
+    response.series_id = series_id.to_string();
+    response.lobby_id = lobby_id;
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+pub async fn get_series(
+    State(db): State<Database>,
+    Path(series_id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("get_series called");
+
+    let mut response = games::GetSeriesOutput {
+        series: None,
+        error: String::from(""),
+    };
+
+    let series = db
+        .collection::<Document>("series")
+        .find_one(doc! { "series_id": series_id }, None)
+        .await?;
+    if series.is_none() {
+        response.error = String::from("Series does not exist");
+        return Ok((StatusCode::NOT_FOUND, Json(response)));
+    }
+
+    let series = bson::to_bson(&series.unwrap())?;
+    response.series = Some(bson::from_bson::<games::Series>(series)?);
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+// Counts a finished game towards its series. Once a player has won more than half the series it
+// is complete, otherwise the lobby for the next game is opened. Each game is only ever counted
+// once, so calling this again for the same game does nothing.
+pub async fn record_game(
+    db: &Database,
+    series_id: &str,
+    lobby_id: &str,
+    winner_id: Option<&str>,
+) -> Result<(), AppError> {
+    let series_ref = db.collection::<Document>("series");
+
+    let series = series_ref
+        .find_one(doc! { "series_id": series_id }, None)
+        .await?
+        .ok_or_else(|| AppError::NotFound(String::from("Series")))?;
+    let series = bson::from_bson::<games::Series>(bson::to_bson(&series)?)?;
+
+    // a draw doesn't score, it only uses up a game
+    let mut score = Document::new();
+    if winner_id == Some(series.player1_id.as_str()) {
+        score.insert("player1_wins", 1);
+    } else if winner_id == Some(series.player2_id.as_str()) {
+        score.insert("player2_wins", 1);
+    }
+
+    let after = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let series = series_ref
+        .find_one_and_update(
+            doc! {
+                "series_id": series_id,
+                "state": "active",
+                "counted_lobby_ids": { "$ne": lobby_id },
+            },
+            doc! {
+                "$inc": score,
+                "$push": { "counted_lobby_ids": lobby_id },
+                "$set": { "updated_at": bson::DateTime::now() },
+            },
+            after,
+        )
+        .await?;
+    let series = match series {
+        Some(series) => bson::from_bson::<games::Series>(bson::to_bson(&series)?)?,
+        None => return Ok(()),
+    };
+
+    let needed = series.best_of / 2 + 1;
+    let winner = if series.player1_wins >= needed {
+        Some(&series.player1_id)
+    } else if series.player2_wins >= needed {
+        Some(&series.player2_id)
+    } else {
+        None
+    };
+
+    if let Some(winner) = winner {
+        series_ref
+            .update_one(
+                doc! { "series_id": series_id },
+                doc! {
+                    "$set": {
+                        "state": "complete",
+                        "winner_id": winner.clone(),
+                        "updated_at": bson::DateTime::now(),
+                    },
+                    "$unset": { "current_lobby_id": "" },
+                },
+                None,
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let next_lobby_id = create_lobby(
+        db,
+        doc! {
+            "player1_id": series.player1_id.clone(),
+            "player2_id": series.player2_id.clone(),
+            "series_id": series_id,
+        },
+    )
+    .await?;
+    series_ref
+        .update_one(
+            doc! { "series_id": series_id },
+            doc! {
+                "$set": {
+                    "current_lobby_id": next_lobby_id.clone(),
+                    "updated_at": bson::DateTime::now(),
+                },
+                "$push": { "lobby_ids": next_lobby_id },
+            },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,237 @@
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Database;
+use tenet_core::Deck;
+
+use crate::errors::AppError;
+
+// The schema version creations are stored with. Bump it whenever Deck changes shape and teach
+// `migrate` how to bring the previous version up to date, so games stored mid-flight by the last
+// deploy can still be proven.
+pub const CREATION_VERSION: i32 = 1;
+
+// Creations stored before versioning have no version field, they're version 1
+const UNVERSIONED: i32 = 1;
+
+// The creation fields of a game and the fields holding their versions
+const CREATION_FIELDS: [(&str, &str); 2] = [
+    ("creation1", "creation1_version"),
+    ("creation2", "creation2_version"),
+];
+
+// The fields each side's commitment is made from and stored in
+const COMMITMENT_FIELDS: [(&str, &str, &str); 2] = [
+    ("creation1", "creation1_nonce", "creation1_hash"),
+    ("creation2", "creation2_nonce", "creation2_hash"),
+];
+
+// Commitments from before hash_creation moved to SHA-256 are a u64 in decimal, the SHA-256 ones
+// are 64 hex digits
+const LEGACY_HASH: &str = "^[0-9]{1,20}$";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationError {
+    UnknownVersion { field: &'static str, version: i32 },
+    Malformed { field: &'static str, reason: String },
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::UnknownVersion { field, version } => {
+                write!(f, "{} has unknown creation version {}", field, version)
+            }
+            MigrationError::Malformed { field, reason } => {
+                write!(f, "{} can't be upgraded: {}", field, reason)
+            }
+        }
+    }
+}
+
+// Brings the creations stored on a game document up to the current version in place, before
+// the document is read as a Game. A game without creations is left as it is.
+pub fn upgrade(game: &mut Document) -> Result<(), MigrationError> {
+    for (field, version_field) in CREATION_FIELDS {
+        let creation = match game.get(field) {
+            Some(Bson::Null) | None => continue,
+            Some(creation) => creation.clone(),
+        };
+        let version = match game.get(version_field) {
+            Some(Bson::Int32(version)) => *version,
+            Some(Bson::Int64(version)) => *version as i32,
+            _ => UNVERSIONED,
+        };
+        if version == CREATION_VERSION {
+            continue;
+        }
+
+        let deck = migrate(field, version, creation)?;
+        let deck = bson::to_bson(&deck).map_err(|err| MigrationError::Malformed {
+            field,
+            reason: err.to_string(),
+        })?;
+        game.insert(field, deck);
+        game.insert(version_field, CREATION_VERSION);
+    }
+
+    Ok(())
+}
+
+// One step per version that came before the current one
+fn migrate(field: &'static str, version: i32, creation: Bson) -> Result<Deck, MigrationError> {
+    match version {
+        1 => bson::from_bson(creation).map_err(|err| MigrationError::Malformed {
+            field,
+            reason: err.to_string(),
+        }),
+        _ => Err(MigrationError::UnknownVersion { field, version }),
+    }
+}
+
+// Recomputes the commitments of unfinished games still holding a hash from before
+// hash_creation moved to SHA-256, from the creation and nonce stored with them, so they can
+// still be played and proven. Finished games keep the hashes they were proven with. Cached NPC
+// battles under the old hashes would never be hit again, so they're dropped. Run at startup,
+// before interrupted battles are resumed.
+pub async fn rehash_commitments(db: &Database) -> Result<u64, AppError> {
+    let games = db.collection::<Document>("game");
+    let legacy = doc! {
+        "state": { "$in": ["player1Turn", "player2Turn", "playing"] },
+        "$or": [
+            { "creation1_hash": { "$regex": LEGACY_HASH } },
+            { "creation2_hash": { "$regex": LEGACY_HASH } },
+        ],
+    };
+    let mut cursor = games.find(legacy, None).await?;
+    let mut rehashed = 0;
+    while cursor.advance().await? {
+        let mut game = cursor.deserialize_current()?;
+        // a game that can't be upgraded is moved to "error" by whichever reader gets to it
+        if upgrade(&mut game).is_err() {
+            continue;
+        }
+
+        let mut hashes = Document::new();
+        for (field, nonce_field, hash_field) in COMMITMENT_FIELDS {
+            let deck = match game.get(field) {
+                Some(Bson::Null) | None => continue,
+                Some(creation) => bson::from_bson::<Deck>(creation.clone()),
+            };
+            if let Ok(deck) = deck {
+                let nonce = game.get_str(nonce_field).unwrap_or("");
+                hashes.insert(hash_field, tenet_core::hash_creation(&deck, nonce));
+                // an NPC game's roster deck is checked against the NPC's creation hash
+                if field == "creation2" && game.get_bool("is_npc_game") == Ok(true) {
+                    hashes.insert("npc_deck_hash", tenet_core::hash_creation(&deck, ""));
+                }
+            }
+        }
+        if hashes.is_empty() {
+            continue;
+        }
+        hashes.insert("updated_at", bson::DateTime::now());
+
+        let update_result = games
+            .update_one(
+                doc! {
+                    "_id": game.get_object_id("_id")?,
+                    "state": game.get_str("state")?,
+                },
+                doc! { "$set": hashes },
+                None,
+            )
+            .await?;
+        rehashed += update_result.modified_count;
+    }
+
+    db.collection::<Document>("proof_cache")
+        .delete_many(doc! { "creation1_hash": { "$regex": LEGACY_HASH } }, None)
+        .await?;
+
+    Ok(rehashed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn game(creation: Bson) -> Document {
+        doc! { "lobby_id": "lobby", "creation1": creation, "creation2": Bson::Null }
+    }
+
+    fn read_creation(game: &Document) -> Deck {
+        bson::from_bson(game.get("creation1").unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn current_creation_round_trips_unchanged() {
+        let mut stored = game(bson::to_bson(&testing::deck(1)).unwrap());
+        stored.insert("creation1_version", CREATION_VERSION);
+        let before = stored.clone();
+
+        upgrade(&mut stored).unwrap();
+        assert_eq!(stored, before);
+        let read = read_creation(&stored);
+        assert_eq!(read.canonical_bytes(), testing::deck(1).canonical_bytes());
+    }
+
+    #[test]
+    fn unversioned_creation_reads_as_version_1() {
+        let mut stored = game(bson::to_bson(&testing::deck(1)).unwrap());
+
+        upgrade(&mut stored).unwrap();
+        let read = read_creation(&stored);
+        assert_eq!(read.canonical_bytes(), testing::deck(1).canonical_bytes());
+        // read back into a document, the creation is stored just as it was
+        assert_eq!(stored.get("creation1"), Some(&bson::to_bson(&read).unwrap()));
+    }
+
+    #[test]
+    fn unknown_creation_version_is_refused() {
+        let mut stored = game(bson::to_bson(&testing::deck(1)).unwrap());
+        stored.insert("creation1_version", CREATION_VERSION + 1);
+
+        let err = upgrade(&mut stored).unwrap_err();
+        let version = CREATION_VERSION + 1;
+        assert_eq!(err, MigrationError::UnknownVersion { field: "creation1", version });
+    }
+
+    #[test]
+    fn malformed_creation_is_refused() {
+        let creation = bson::to_bson(&doc! { "cards": "none" }).unwrap();
+        let err = migrate("creation1", UNVERSIONED, creation).unwrap_err();
+        assert!(matches!(err, MigrationError::Malformed { field: "creation1", .. }));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn unfinished_games_get_their_commitments_rehashed() {
+        let db = testing::db().await;
+        let creation = bson::to_bson(&testing::deck(1)).unwrap();
+        db.collection::<Document>("game")
+            .insert_many(
+                [
+                    doc! {
+                        "lobby_id": "waiting",
+                        "state": "player2Turn",
+                        "creation1": creation.clone(),
+                        "creation1_nonce": "nonce",
+                        "creation1_hash": "1234567890",
+                    },
+                    doc! { "lobby_id": "finished", "state": "complete", "creation1_hash": "1234567890" },
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(rehash_commitments(&db).await.unwrap(), 1);
+        let games = db.collection::<Document>("game");
+        let waiting = games.find_one(doc! { "lobby_id": "waiting" }, None).await.unwrap().unwrap();
+        let expected = tenet_core::hash_creation(&testing::deck(1), "nonce");
+        assert_eq!(waiting.get_str("creation1_hash").unwrap(), expected);
+        let finished = games.find_one(doc! { "lobby_id": "finished" }, None).await.unwrap().unwrap();
+        assert_eq!(finished.get_str("creation1_hash").unwrap(), "1234567890");
+        assert_eq!(rehash_commitments(&db).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,107 @@
+use std::future::Future;
+use std::time::Duration;
+
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::error::{ErrorKind, WriteFailure, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR};
+use mongodb::options::{Acknowledgment, IndexOptions, WriteConcern};
+use mongodb::{Database, IndexModel};
+
+// How many times a write is tried before its error is handed back, and the first wait between
+// tries. The wait doubles every try.
+const WRITE_ATTEMPTS: u32 = 3;
+const WRITE_BACKOFF: Duration = Duration::from_millis(100);
+
+// The server error code for a write that broke a unique index
+const DUPLICATE_KEY: i32 = 11000;
+
+// The write concern every write goes out with, from MONGODB_WRITE_CONCERN: "majority", a number
+// of nodes or a custom tag. Writes are always journaled, a game that moved to "complete" in
+// memory only can be rolled back by a failover.
+pub fn write_concern(w: &str) -> WriteConcern {
+    let w = match w.parse::<u32>() {
+        Ok(nodes) => Acknowledgment::Nodes(nodes),
+        Err(_) => Acknowledgment::from(w.to_string()),
+    };
+    WriteConcern::builder().w(w).journal(true).build()
+}
+
+// The indexes the handlers count on, created at startup. A lobby has at most one game, so two
+// first submissions racing each other can't both insert one and start two battles.
+pub async fn ensure_indexes(db: &Database) -> mongodb::error::Result<()> {
+    let one_game_per_lobby = IndexModel::builder()
+        .keys(doc! { "lobby_id": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    db.collection::<Document>("game").create_index(one_game_per_lobby, None).await?;
+    // a game's receipt is the one behind its committed result, a second is never stored
+    let one_receipt_per_game = IndexModel::builder()
+        .keys(doc! { "game_id": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    db.collection::<Document>("receipt").create_index(one_receipt_per_game, None).await?;
+    // every authenticated request looks its player up by token, players only ever rated have none
+    let by_token = IndexModel::builder()
+        .keys(doc! { "token_hash": 1 })
+        .options(IndexOptions::builder().unique(true).sparse(true).build())
+        .build();
+    db.collection::<Document>("player").create_index(by_token, None).await?;
+    Ok(())
+}
+
+// Whether the write was turned away by a unique index, like a second game for a lobby
+pub fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match &*err.kind {
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) => write_error.code == DUPLICATE_KEY,
+        ErrorKind::Command(command_error) => command_error.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+// Errors that say nothing about the write itself: the connection dropped, no server could be
+// picked during an election, or the server labelled it safe to try again
+fn is_transient(err: &mongodb::error::Error) -> bool {
+    err.contains_label(RETRYABLE_WRITE_ERROR)
+        || err.contains_label(TRANSIENT_TRANSACTION_ERROR)
+        || matches!(
+            *err.kind,
+            ErrorKind::Io(_)
+                | ErrorKind::ServerSelection { .. }
+                | ErrorKind::ConnectionPoolCleared { .. }
+        )
+}
+
+// Runs a write, trying it again with backoff while it fails on a transient error. Only for
+// writes that are safe to repeat, like a `$set` guarded on the state it moves the game out of.
+// If a try landed but its reply was lost, the next one no longer matches, so the caller sees
+// the same thing as losing the race to another request.
+pub async fn retry_write<T, F, Fut>(label: &str, mut write: F) -> mongodb::error::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = mongodb::error::Result<T>>,
+{
+    let mut backoff = WRITE_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match write().await {
+            Err(err) if attempt < WRITE_ATTEMPTS && is_transient(&err) => {
+                // jitter so the writes that failed together don't all come back together
+                let wait = backoff.mul_f64(rand::random::<f64>() + 0.5);
+                tracing::warn!(
+                    "{} failed on try {} of {}, trying again in {:?}: {}",
+                    label,
+                    attempt,
+                    WRITE_ATTEMPTS,
+                    wait,
+                    err
+                );
+                metrics::increment_counter!("db_write_retries_total", "write" => label.to_string());
+                tokio::time::sleep(wait).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            written => return written,
+        }
+    }
+}
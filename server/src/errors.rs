@@ -0,0 +1,166 @@
+use axum::{http::header::RETRY_AFTER, http::StatusCode, response::IntoResponse, response::Response, Json};
+use rand::Rng;
+
+// How long a client that hit a conflict is told to wait before trying again
+const CONFLICT_RETRY_SECS: u64 = 1;
+// Up to this many seconds are added to every Retry-After, so clients that were turned away
+// together don't all come back together
+const RETRY_AFTER_JITTER_SECS: u64 = 2;
+
+// Errors that can bubble out of a handler. Anything the caller did wrong is still answered
+// through the handler's own output struct; this covers the failures that used to panic.
+#[derive(Debug)]
+pub enum AppError {
+    Database(mongodb::error::Error),
+    Serialization(bson::ser::Error),
+    Deserialization(bson::de::Error),
+    NotFound(String),
+    Unauthorized(String),
+    // authenticated, but not allowed to do this
+    Forbidden(String),
+    // a request body that isn't valid JSON for the handler's input, and the field at fault
+    InvalidBody { field: Option<String>, reason: String },
+    // how long until the player can try again
+    RateLimited(std::time::Duration),
+    // turned away while the server is in maintenance, and why
+    Unavailable(String),
+    Internal(String),
+}
+
+impl AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::InvalidBody { .. } => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(_)
+            | AppError::Serialization(_)
+            | AppError::Deserialization(_)
+            | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Database(err) => write!(f, "Database error: {}", err),
+            AppError::Serialization(err) => write!(f, "Failed to serialize document: {}", err),
+            AppError::Deserialization(err) => write!(f, "Failed to deserialize document: {}", err),
+            AppError::NotFound(what) => write!(f, "{} not found", what),
+            AppError::Unauthorized(reason) => write!(f, "Unauthorized: {}", reason),
+            AppError::Forbidden(reason) => write!(f, "Forbidden: {}", reason),
+            AppError::InvalidBody { reason, .. } => write!(f, "Invalid request body: {}", reason),
+            AppError::RateLimited(_) => write!(f, "Too many requests, slow down"),
+            AppError::Unavailable(reason) => write!(f, "Unavailable: {}", reason),
+            AppError::Internal(msg) => write!(f, "Internal error: {}", msg),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for AppError {
+    fn from(err: mongodb::error::Error) -> Self {
+        AppError::Database(err)
+    }
+}
+
+impl From<bson::ser::Error> for AppError {
+    fn from(err: bson::ser::Error) -> Self {
+        AppError::Serialization(err)
+    }
+}
+
+impl From<bson::de::Error> for AppError {
+    fn from(err: bson::de::Error) -> Self {
+        AppError::Deserialization(err)
+    }
+}
+
+impl From<bson::document::ValueAccessError> for AppError {
+    fn from(err: bson::document::ValueAccessError) -> Self {
+        AppError::Internal(format!("Unexpected document shape: {}", err))
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
+        if status.is_server_error() {
+            tracing::error!("{}", self);
+        }
+
+        let mut body = serde_json::json!({
+            "error": self.to_string(),
+        });
+        if let AppError::InvalidBody { field: Some(field), .. } = &self {
+            body["field"] = serde_json::Value::from(field.clone());
+        }
+        let body = Json(body);
+
+        if let AppError::RateLimited(retry_after) = self {
+            // whole seconds, rounded up so a client waiting that long is let through
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            return (status, [retry_after_header(seconds)], body).into_response();
+        }
+
+        (status, body).into_response()
+    }
+}
+
+fn retry_after_header(seconds: u64) -> (axum::http::HeaderName, String) {
+    let jitter = rand::thread_rng().gen_range(0..=RETRY_AFTER_JITTER_SECS);
+    (RETRY_AFTER, (seconds + jitter).to_string())
+}
+
+// A handler's answer, with a Retry-After on a conflict so clients polling a game that is busy
+// (not their turn, a battle being proven) back off instead of retrying straight away
+pub fn with_backoff(status: StatusCode, body: impl IntoResponse) -> Response {
+    if status == StatusCode::CONFLICT {
+        return (status, [retry_after_header(CONFLICT_RETRY_SECS)], body).into_response();
+    }
+    (status, body).into_response()
+}
+
+// Reasons a battle could not be proven. These end up on the game's error field.
+#[derive(Debug)]
+pub enum BattleError {
+    UnknownArena(String),
+    MissingCreation,
+    Input(String),
+    Prover(String),
+    Panicked(String),
+    TimedOut(std::time::Duration),
+    Journal(String),
+    Verification(String),
+    Mismatch(String),
+}
+
+impl BattleError {
+    // Failures of the machine rather than the battle, which a second try can get past. Anything
+    // the guest or its inputs decided would fail the same way again. A timed out proof isn't
+    // tried again either, its blocking thread is still running and would be competed with.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, BattleError::Panicked(_))
+    }
+}
+
+impl std::fmt::Display for BattleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BattleError::UnknownArena(arena_id) => write!(f, "Unknown arena {}", arena_id),
+            BattleError::MissingCreation => write!(f, "Both creations are needed to start the battle"),
+            BattleError::Input(err) => write!(f, "Failed to send input to the arena: {}", err),
+            BattleError::Prover(err) => write!(f, "Proving failed: {}", err),
+            BattleError::Panicked(reason) => write!(f, "Prover panicked: {}", reason),
+            BattleError::TimedOut(limit) => {
+                write!(f, "Battle exceeded proving time of {}s", limit.as_secs())
+            }
+            BattleError::Journal(err) => write!(f, "Failed to decode journal: {}", err),
+            BattleError::Verification(err) => write!(f, "Receipt failed verification: {}", err),
+            BattleError::Mismatch(what) => write!(f, "Journal doesn't match the game: {}", what),
+        }
+    }
+}
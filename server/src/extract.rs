@@ -0,0 +1,60 @@
+use axum::{
+    async_trait,
+    body::{Bytes, HttpBody},
+    extract::FromRequest,
+    http::{header::CONTENT_TYPE, Request},
+    BoxError,
+};
+use serde::de::DeserializeOwned;
+
+use crate::errors::AppError;
+
+// A JSON request body. Unlike axum's Json, a body that doesn't fit the input struct is a 400
+// saying which field was wrong, e.g. `creation.cards[2].attack`, rather than a bare 422.
+pub struct AppJson<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for AppJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.starts_with("application/json"))
+            .unwrap_or(false);
+        if !is_json {
+            return Err(AppError::InvalidBody {
+                field: None,
+                reason: String::from("Expected Content-Type: application/json"),
+            });
+        }
+
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|rejection| AppError::InvalidBody {
+                field: None,
+                reason: rejection.body_text(),
+            })?;
+
+        let deserializer = &mut serde_json::Deserializer::from_slice(&body);
+        serde_path_to_error::deserialize(deserializer)
+            .map(AppJson)
+            .map_err(|err| {
+                // the path is just "." when the body itself is the problem, e.g. a missing field
+                let field = err.path().to_string();
+                AppError::InvalidBody {
+                    field: Some(field).filter(|field| field != "."),
+                    reason: err.into_inner().to_string(),
+                }
+            })
+    }
+}
@@ -0,0 +1,45 @@
+use serde::{de::Error, Deserialize, Deserializer};
+
+// The longest player or lobby id a request may carry. Ids handed out by the server are 24
+// character ObjectIds, this leaves room for made up ones like NPC ids.
+pub const MAX_ID_LENGTH: usize = 64;
+
+// Ids go into queries, documents and logs as they were sent, so only what the server itself
+// could have handed out is accepted: letters, digits, '-' and '_'
+pub fn check(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err(String::from("id is empty"));
+    }
+    if id.len() > MAX_ID_LENGTH {
+        return Err(format!("id is longer than {} characters", MAX_ID_LENGTH));
+    }
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(String::from("id may only contain letters, digits, '-' and '_'"));
+    }
+    Ok(())
+}
+
+// For `#[serde(deserialize_with)]`, so a bad id is a 400 naming the field
+pub fn checked<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let id = String::deserialize(deserializer)?;
+    check(&id).map_err(D::Error::custom)?;
+    Ok(id)
+}
+
+// Like `checked`, for optional ids. Needs `#[serde(default)]` alongside so a missing one is None
+pub fn checked_opt<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let id = Option::<String>::deserialize(deserializer)?;
+    if let Some(id) = &id {
+        check(id).map_err(D::Error::custom)?;
+    }
+    Ok(id)
+}
+
+// Like `checked`, for ids where empty means none was given
+pub fn checked_or_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let id = String::deserialize(deserializer)?;
+    if !id.is_empty() {
+        check(&id).map_err(D::Error::custom)?;
+    }
+    Ok(id)
+}
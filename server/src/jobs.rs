@@ -0,0 +1,429 @@
+use std::time::Duration;
+
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::Database;
+
+use crate::audit::{self, AuditEvent, AuditKind};
+use crate::controllers::games::{forfeit, read_game, spawn_battle};
+use crate::creations;
+use crate::errors::AppError;
+use crate::models::games;
+use crate::notifications::GameEvent;
+use crate::state::AppState;
+
+// Moves games that have been waiting on a player's turn since before the deadline to
+// "abandoned", crediting the player who was left waiting. They're finished through the same
+// forfeit as a concession, so ratings, series, subscribers and webhooks all hear about it. `now`
+// is passed in so the transition doesn't depend on the wall clock.
+pub async fn abandon_stale_games(
+    state: &AppState,
+    now: chrono::DateTime<chrono::Utc>,
+    deadline: Duration,
+) -> Result<u64, AppError> {
+    let games_ref = state.db.collection::<Document>("game");
+
+    let deadline = chrono::Duration::from_std(deadline)
+        .map_err(|err| AppError::Internal(format!("Invalid turn deadline: {}", err)))?;
+    let cutoff = bson::DateTime::from_chrono(now - deadline);
+
+    let mut cursor = games_ref
+        .find(
+            doc! {
+                "state": { "$in": ["player1Turn", "player2Turn"] },
+                "updated_at": { "$lt": cutoff },
+            },
+            None,
+        )
+        .await?;
+
+    let mut abandoned = 0;
+    while cursor.advance().await? {
+        let game_doc = cursor.deserialize_current()?;
+        let game = match read_game(&game_doc) {
+            Ok(game) => game,
+            // nobody can play it on, so it's ended without a winner rather than left to lapse
+            // again every scan
+            Err(err) => {
+                let update_result = games_ref
+                    .update_one(
+                        doc! {
+                            "_id": game_doc.get_object_id("_id")?,
+                            "state": game_doc.get_str("state")?,
+                        },
+                        doc! {
+                            "$set": {
+                                "state": "error",
+                                "error": err.to_string(),
+                                "updated_at": bson::DateTime::from_chrono(now),
+                            },
+                        },
+                        None,
+                    )
+                    .await?;
+                abandoned += update_result.modified_count;
+                continue;
+            }
+        };
+
+        // the player whose turn lapsed loses
+        let loser_id = match game.state {
+            games::GameState::Player1Turn => &game.player1_id,
+            _ => &game.player2_id,
+        };
+        if forfeit(state, &game, loser_id, games::GameState::Abandoned).await? {
+            abandoned += 1;
+        }
+    }
+
+    Ok(abandoned)
+}
+
+// Deletes lobbies that were opened before the TTL and never got an opponent or a game. Each
+// delete is guarded on the open slot, so a join landing at the same time either fills the lobby
+// first and keeps it, or finds it gone and is told to try again.
+pub async fn reap_orphaned_lobbies(
+    db: &Database,
+    now: chrono::DateTime<chrono::Utc>,
+    ttl: Duration,
+) -> Result<u64, AppError> {
+    let lobbies = db.collection::<Document>("lobby");
+    let games = db.collection::<Document>("game");
+
+    let ttl = chrono::Duration::from_std(ttl)
+        .map_err(|err| AppError::Internal(format!("Invalid lobby TTL: {}", err)))?;
+    let cutoff = bson::DateTime::from_chrono(now - ttl);
+
+    let mut cursor = lobbies
+        .find(
+            doc! {
+                "player2_id": null,
+                "created_at": { "$lt": cutoff },
+            },
+            None,
+        )
+        .await?;
+
+    let mut reaped = 0;
+    while cursor.advance().await? {
+        let lobby = cursor.deserialize_current()?;
+        let id = lobby.get_object_id("_id")?;
+
+        // a lobby that never got its lobby_id can't have a game
+        if let Ok(lobby_id) = lobby.get_str("lobby_id") {
+            let game_count = games.count_documents(doc! { "lobby_id": lobby_id }, None).await?;
+            if game_count > 0 {
+                continue;
+            }
+        }
+
+        let delete_result = lobbies
+            .delete_one(
+                doc! {
+                    "_id": id,
+                    "player2_id": null,
+                },
+                None,
+            )
+            .await?;
+        reaped += delete_result.deleted_count;
+    }
+
+    Ok(reaped)
+}
+
+// Picks up "playing" games whose battle went quiet past the deadline without a receipt, as when
+// the task proving it died without a trace. Each is proven again while it has retries left, and
+// moved to "error" after that. Queued games are left alone: they're waiting on a proof slot in a
+// live process, and a dead one's are resumed when it starts again.
+pub async fn retry_stalled_battles(
+    state: &AppState,
+    now: chrono::DateTime<chrono::Utc>,
+    deadline: Duration,
+    max_retries: i32,
+) -> Result<u64, AppError> {
+    let games_ref = state.db.collection::<Document>("game");
+    let receipts = state.db.collection::<Document>("receipt");
+
+    let deadline = chrono::Duration::from_std(deadline)
+        .map_err(|err| AppError::Internal(format!("Invalid proof deadline: {}", err)))?;
+    let cutoff = bson::DateTime::from_chrono(now - deadline);
+
+    let mut cursor = games_ref
+        .find(
+            doc! {
+                "state": "playing",
+                "progress": { "$ne": "queued" },
+                "updated_at": { "$lt": cutoff },
+            },
+            None,
+        )
+        .await?;
+
+    let mut handled = 0;
+    while cursor.advance().await? {
+        let mut game_doc = cursor.deserialize_current()?;
+        let id = game_doc.get_object_id("_id")?;
+        let updated_at = *game_doc.get_datetime("updated_at")?;
+        let lobby_id = game_doc.get_str("lobby_id").unwrap_or_default().to_string();
+
+        // a proven battle only has its commit left, that's for resume_battles to finish
+        if receipts.count_documents(doc! { "game_id": id }, None).await? > 0 {
+            continue;
+        }
+
+        // guarded on the update we read, so a battle that moved on since isn't touched
+        let stalled = doc! {
+            "_id": id,
+            "state": "playing",
+            "updated_at": updated_at,
+        };
+        let retries = game_doc.get_i32("proof_retries").unwrap_or(0);
+        // a game that can't be upgraded or read could never be proven either
+        let read = if retries >= max_retries {
+            Err(String::from("Proof timed out"))
+        } else {
+            creations::upgrade(&mut game_doc)
+                .map_err(|err| err.to_string())
+                .and_then(|()| read_game(&game_doc).map_err(|err| err.to_string()))
+        };
+        let game = match read {
+            Ok(game) => game,
+            Err(failure) => {
+                let update_result = games_ref
+                    .update_one(
+                        stalled,
+                        doc! {
+                            "$set": {
+                                "state": "error",
+                                "error": failure.clone(),
+                                "updated_at": bson::DateTime::from_chrono(now),
+                            },
+                            "$unset": { "progress": "" },
+                        },
+                        None,
+                    )
+                    .await?;
+                if update_result.modified_count == 1 {
+                    tracing::warn!("Gave up on stalled battle for lobby {}: {}", lobby_id, failure);
+                    state.notifier.publish(GameEvent {
+                        lobby_id,
+                        state: String::from("error"),
+                        winner_id: None,
+                    });
+                    handled += 1;
+                }
+                continue;
+            }
+        };
+        let update_result = games_ref
+            .update_one(
+                stalled,
+                doc! {
+                    "$inc": { "proof_retries": 1 },
+                    "$set": { "updated_at": bson::DateTime::from_chrono(now) },
+                },
+                None,
+            )
+            .await?;
+        if update_result.modified_count == 1 {
+            tracing::warn!(
+                "Battle for lobby {} stalled, proving it again (retry {})",
+                lobby_id,
+                retries + 1
+            );
+            spawn_battle(state.clone(), game);
+            handled += 1;
+        }
+    }
+
+    Ok(handled)
+}
+
+// Gives the open lobbies of players who opted into a bot fallback an NPC opponent once they've
+// waited past `wait`. The player's next creation is then battled against the NPC in that lobby.
+pub async fn match_lonely_lobbies(
+    state: &AppState,
+    now: chrono::DateTime<chrono::Utc>,
+    wait: Duration,
+) -> Result<u64, AppError> {
+    let lobbies = state.db.collection::<Document>("lobby");
+    let npcs = state.db.collection::<Document>("npc");
+
+    let wait = chrono::Duration::from_std(wait)
+        .map_err(|err| AppError::Internal(format!("Invalid bot fallback wait: {}", err)))?;
+    let cutoff = bson::DateTime::from_chrono(now - wait);
+
+    let mut cursor = lobbies
+        .find(
+            doc! {
+                "player2_id": null,
+                "bot_fallback": true,
+                "lobby_id": { "$ne": null },
+                "created_at": { "$lt": cutoff },
+            },
+            None,
+        )
+        .await?;
+
+    let mut matched = 0;
+    while cursor.advance().await? {
+        let lobby = cursor.deserialize_current()?;
+        let id = lobby.get_object_id("_id")?;
+        let lobby_id = lobby.get_str("lobby_id")?.to_string();
+
+        // any NPC on the roster will do, the player asked for a match rather than a challenge
+        let mut sample = npcs.aggregate(vec![doc! { "$sample": { "size": 1 } }], None).await?;
+        let npc_id = if sample.advance().await? {
+            sample.deserialize_current()?.get_str("npc_id")?.to_string()
+        } else {
+            tracing::warn!("No NPCs on the roster to fall back to for lobby {}", lobby_id);
+            return Ok(matched);
+        };
+
+        // guarded on the open slot, a player who joined since keeps it
+        let update_result = lobbies
+            .update_one(
+                doc! {
+                    "_id": id,
+                    "player2_id": null,
+                },
+                doc! {
+                    "$set": { "player2_id": npc_id.clone(), "npc_id": npc_id.clone() }
+                },
+                None,
+            )
+            .await?;
+        if update_result.modified_count != 1 {
+            continue;
+        }
+        tracing::info!("Lobby {} waited too long, NPC {} took the open slot", lobby_id, npc_id);
+        let joined = AuditEvent::new(AuditKind::PlayerJoined, &lobby_id).player(&npc_id);
+        audit::record(&state.db, joined);
+        state.notifier.publish(GameEvent {
+            lobby_id,
+            state: games::LobbyState::Setup.as_str().to_string(),
+            winner_id: None,
+        });
+        matched += 1;
+    }
+
+    Ok(matched)
+}
+
+pub async fn run_bot_fallback(state: AppState, interval: Duration, wait: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match match_lonely_lobbies(&state, chrono::Utc::now(), wait).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Matched {} waiting lobbies with NPCs", count),
+            Err(err) => tracing::error!("Failed to match waiting lobbies with NPCs: {}", err),
+        }
+    }
+}
+
+pub async fn run_proof_watchdog(
+    state: AppState,
+    interval: Duration,
+    deadline: Duration,
+    max_retries: i32,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match retry_stalled_battles(&state, chrono::Utc::now(), deadline, max_retries).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Handled {} stalled battles", count),
+            Err(err) => tracing::error!("Failed to check for stalled battles: {}", err),
+        }
+    }
+}
+
+pub async fn run_lobby_reaper(db: Database, interval: Duration, ttl: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match reap_orphaned_lobbies(&db, chrono::Utc::now(), ttl).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Removed {} orphaned lobbies", count),
+            Err(err) => tracing::error!("Failed to remove orphaned lobbies: {}", err),
+        }
+    }
+}
+
+pub async fn run_turn_timeouts(state: AppState, interval: Duration, deadline: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match abandon_stale_games(&state, chrono::Utc::now(), deadline).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Abandoned {} games past the turn deadline", count),
+            Err(err) => tracing::error!("Failed to abandon stale games: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratings;
+    use crate::testing;
+
+    // a game waiting on player 2 since `since`, player 1 has submitted
+    fn waiting_on_player2(lobby_id: &str, since: chrono::DateTime<chrono::Utc>) -> Document {
+        doc! {
+            "lobby_id": lobby_id,
+            "player1_id": "p1",
+            "player2_id": "p2",
+            "creation1": null,
+            "creation1_hash": "hash1",
+            "creation2": null,
+            "creation2_hash": null,
+            "arena_hash": "arena",
+            "winner_creation_hash": null,
+            "winner_id": null,
+            "state": "player2Turn",
+            "result": null,
+            "error": null,
+            "rated": true,
+            "created_at": bson::DateTime::from_chrono(since),
+            "updated_at": bson::DateTime::from_chrono(since),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn lapsed_turn_is_abandoned_once_the_clock_passes_the_deadline() {
+        let state = testing::app_state(testing::db().await);
+        let games_ref = state.db.collection::<Document>("game");
+        let started = chrono::Utc::now();
+        games_ref.insert_one(waiting_on_player2("lapsed", started), None).await.unwrap();
+        let mut events = state.notifier.subscribe("lapsed");
+        let deadline = Duration::from_secs(60 * 60);
+
+        let early = started + chrono::Duration::minutes(59);
+        assert_eq!(abandon_stale_games(&state, early, deadline).await.unwrap(), 0);
+        let game = read_game(&games_ref.find_one(None, None).await.unwrap().unwrap()).unwrap();
+        assert_eq!(game.state, games::GameState::Player2Turn);
+
+        let late = started + chrono::Duration::minutes(61);
+        assert_eq!(abandon_stale_games(&state, late, deadline).await.unwrap(), 1);
+        let game = read_game(&games_ref.find_one(None, None).await.unwrap().unwrap()).unwrap();
+        assert_eq!(game.state, games::GameState::Abandoned);
+        assert_eq!(game.winner_id.as_deref(), Some("p1"));
+        assert_eq!(game.winner_creation_hash.as_deref(), Some("hash1"));
+        assert_eq!(game.result.as_deref(), Some("PLAYER1_WINS"));
+
+        // finished like any other game: rated and announced
+        assert_eq!(ratings::get_rating(&state.db, "p1").await.unwrap(), 1216.0);
+        assert_eq!(ratings::get_rating(&state.db, "p2").await.unwrap(), 1184.0);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.state, "abandoned");
+        assert_eq!(event.winner_id.as_deref(), Some("p1"));
+
+        // and only once
+        assert_eq!(abandon_stale_games(&state, late, deadline).await.unwrap(), 0);
+    }
+}
@@ -1,31 +1,53 @@
 // Utils
 use dotenv::dotenv;
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 // Web Server
 use axum::{
-    extract::State,
-    http::Method,
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
 
 // DB
 use mongodb::{bson::doc, options::ClientOptions, Client};
 
 // Custom Modules
+mod arenas;
+mod audit;
+mod auth;
 mod controllers;
+mod creations;
+mod db;
+mod errors;
+mod extract;
+mod ids;
+mod jobs;
+mod maintenance;
 mod models;
+mod notifications;
+mod proof_cache;
+mod ratelimit;
+mod ratings;
+mod state;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod timelines;
+mod webhooks;
 
-async fn connect_db(mongodb_uri: String) -> Client {
+async fn connect_db(mongodb_uri: String, write_concern: String) -> Client {
     // Parse your connection string into an options struct
     let mut client_options = ClientOptions::parse(mongodb_uri).await.unwrap();
 
     // Manually set an option
     client_options.app_name = Some("Tenet DB".to_string());
+    client_options.write_concern = Some(db::write_concern(&write_concern));
 
     // Get a handle to the cluster
     let client = Client::with_options(client_options).unwrap();
@@ -39,7 +61,7 @@ async fn connect_db(mongodb_uri: String) -> Client {
 
     tracing::info!("Database connected successfully.");
 
-    return client;
+    client
 }
 
 #[tokio::main]
@@ -54,37 +76,254 @@ async fn main() {
 
     // initialize db
     let mongodb_uri = std::env::var("MONGODB_URI").expect("MONGODB_URI must be set.");
-    let client = connect_db(mongodb_uri).await;
+    let write_concern = env_or("MONGODB_WRITE_CONCERN", String::from("majority"));
+    let client = connect_db(mongodb_uri, write_concern).await;
     let db = client.database("Cluster0");
+    // fail fast, without its indexes two requests racing each other can both start a game
+    db::ensure_indexes(&db).await.unwrap_or_else(|err| panic!("Failed to create indexes: {}", err));
+    let hashed = auth::hash_stored_tokens(&db)
+        .await
+        .unwrap_or_else(|err| panic!("Failed to hash stored tokens: {}", err));
+    if hashed > 0 {
+        tracing::info!("Hashed {} bearer tokens stored in plain", hashed);
+    }
+
+    // clear out lobbies nobody ever joined
+    let lobby_ttl = env_secs("LOBBY_TTL_SECS", 60 * 60 * 24);
+    let lobby_reap_scan = env_secs("LOBBY_REAP_SCAN_SECS", 60 * 5);
+    tokio::spawn(jobs::run_lobby_reaper(db.clone(), lobby_reap_scan, lobby_ttl));
+
+    let metrics = telemetry::install_recorder();
+
+    // fail fast, a server that can't read its arenas can't finish a single game
+    let arena_methods = arenas::load_methods().unwrap_or_else(|err| panic!("{}", err));
+
+    let proof_mode: state::ProofMode = env_or("PROOF_MODE", state::ProofMode::Prod);
+    if proof_mode == state::ProofMode::Dev {
+        tracing::warn!("**************************************************************");
+        tracing::warn!("PROOF_MODE=dev: seals are skipped and battle results are NEVER");
+        tracing::warn!("verified. Do not run this in production.");
+        tracing::warn!("**************************************************************");
+    }
+
+    // on unless turned off by hand, for local clients and tests that don't bother with tokens
+    let auth_required = env_or("AUTH_REQUIRED", true);
+    if !auth_required {
+        tracing::warn!("AUTH_REQUIRED=false: any request can act as any player");
+    }
+
+    let proof_concurrency: usize = env_or("MAX_CONCURRENT_PROOFS", 2).max(1);
+
+    let state = state::AppState {
+        db: db.clone(),
+        matchmaking: state::MatchmakingConfig {
+            rating_band: env_or("MATCHMAKING_RATING_BAND", 100.0),
+            band_growth_per_minute: env_or("MATCHMAKING_BAND_GROWTH_PER_MINUTE", 25.0),
+            max_open_lobbies: env_or("MAX_OPEN_LOBBIES_PER_PLAYER", 3),
+            max_active_games: env_or("MAX_ACTIVE_GAMES_PER_PLAYER", 5),
+            join_code_ttl: env_secs("JOIN_CODE_TTL_SECS", 60 * 30),
+        },
+        rating: state::RatingConfig {
+            rate_npc_games: env_or("RATE_NPC_GAMES", false),
+        },
+        auth: state::AuthConfig {
+            required: auth_required,
+        },
+        limits: ratelimit::RateLimits {
+            play: ratelimit::RateLimiter::new(
+                env_or("PLAY_RATE_PER_MINUTE", 30.0),
+                env_or("PLAY_RATE_BURST", 10.0),
+            ),
+            npc: ratelimit::RateLimiter::new(
+                env_or("NPC_RATE_PER_MINUTE", 5.0),
+                env_or("NPC_RATE_BURST", 2.0),
+            ),
+            sandbox: ratelimit::RateLimiter::new(
+                env_or("SANDBOX_RATE_PER_MINUTE", 2.0),
+                env_or("SANDBOX_RATE_BURST", 1.0),
+            ),
+        },
+        notifier: notifications::Notifier::default(),
+        proof_slots: Arc::new(tokio::sync::Semaphore::new(proof_concurrency)),
+        proof_concurrency,
+        proof_queue: state::ProofQueue::default(),
+        proof_durations: telemetry::ProofDurations::default(),
+        proof_timeout: env_secs("PROOF_TIMEOUT_SECS", 60 * 30),
+        proof_mode,
+        maintenance_forced: env_or("MAINTENANCE_MODE", false),
+        proof_max_retries: env_or("PROOF_MAX_RETRIES", 2),
+        prover_host: std::env::var("PROVER_HOST")
+            .or_else(|_| std::env::var("HOSTNAME"))
+            .unwrap_or_else(|_| String::from("unknown")),
+        battles: state::BattleTasks::default(),
+        webhooks: webhooks::Webhooks::new(),
+        arena_methods,
+        metrics,
+    };
+
+    // before the battles pick up again, their commitments may predate the current hash
+    match creations::rehash_commitments(&db).await {
+        Ok(0) => {}
+        Ok(count) => tracing::info!("Rehashed the commitments of {} unfinished games", count),
+        Err(err) => tracing::error!("Failed to rehash commitments: {}", err),
+    }
+
+    // and their arena hashes may predate the stable one
+    match arenas::rehash_arenas(&db).await {
+        Ok(0) => {}
+        Ok(count) => tracing::info!("Rehashed the arena of {} games", count),
+        Err(err) => tracing::error!("Failed to rehash arenas: {}", err),
+    }
+
+    // pick up battles that were cut off by the last shutdown
+    match controllers::games::resume_battles(&state).await {
+        Ok(0) => {}
+        Ok(count) => tracing::info!("Resumed {} interrupted battles", count),
+        Err(err) => tracing::error!("Failed to resume interrupted battles: {}", err),
+    }
+    let battles = state.battles.clone();
 
-    let cors = CorsLayer::new().allow_methods(Any).allow_origin(Any);
+    // abandon games where a player never took their turn
+    let turn_timeout = env_secs("TURN_TIMEOUT_SECS", 60 * 60 * 24);
+    let turn_timeout_scan = env_secs("TURN_TIMEOUT_SCAN_SECS", 60);
+    tokio::spawn(jobs::run_turn_timeouts(state.clone(), turn_timeout_scan, turn_timeout));
+
+    // catch battles whose proof died without a trace, past the proof timeout that would have
+    // ended a live one
+    let proof_deadline = env_secs("PROOF_STALL_SECS", state.proof_timeout.as_secs() + 60 * 10);
+    tokio::spawn(jobs::run_proof_watchdog(
+        state.clone(),
+        env_secs("PROOF_STALL_SCAN_SECS", 60),
+        proof_deadline,
+        state.proof_max_retries,
+    ));
+
+    // players who opted in get an NPC opponent once they've waited this long in an open lobby
+    tokio::spawn(jobs::run_bot_fallback(
+        state.clone(),
+        env_secs("BOT_FALLBACK_SCAN_SECS", 15),
+        env_secs("BOT_FALLBACK_WAIT_SECS", 60 * 2),
+    ));
 
     let games_routes = Router::new()
         .route("/", get(controllers::games::get_all_games))
         .route("/join", post(controllers::games::join_game))
+        .route("/private", post(controllers::games::create_private_lobby))
+        .route("/lobbies", get(controllers::games::get_open_lobbies))
+        .route("/live", get(controllers::games::get_live_games))
+        .route("/lobbies/:lobby_id", get(controllers::games::get_lobby))
+        .route("/decks/stats", get(controllers::games::get_deck_stats))
+        .route("/:lobby_id", get(controllers::games::get_game))
+        .route("/:lobby_id/ws", get(notifications::subscribe_game))
+        .route("/:lobby_id/spectate", get(controllers::games::spectate_game))
+        .route("/:lobby_id/inputs", get(controllers::games::get_battle_inputs))
+        .route("/verify/:game_id", get(controllers::games::verify_game_receipt))
+        .route("/receipt/:game_id", get(controllers::games::get_game_receipt))
         .route("/play", post(controllers::games::play_game))
         .route("/play/npc", post(controllers::games::play_npc_game))
+        .route("/play/npc/gauntlet", post(controllers::games::play_npc_gauntlet))
+        .route("/sandbox", post(controllers::games::sandbox_battle))
+        .route("/validate", post(controllers::games::validate_creation_endpoint))
+        .route("/ready", post(controllers::games::set_ready))
+        .route("/cancel", post(controllers::games::cancel_game))
+        .route("/cancel/queued", post(controllers::games::cancel_queued_battle))
+        .route("/requeue", post(controllers::games::requeue))
+        .route("/concede", post(controllers::games::concede))
+        .route("/rematch", post(controllers::games::rematch))
         .route("/commit", post(controllers::games::commit_outcome));
     // .route("/commit", post(commit_outcome));
     // .route("/play", post(play_game));
 
-    let player_routes = Router::new().route("/games", get(controllers::players::get_player_games));
+    let player_routes = Router::new()
+        .route("/new", post(auth::new_player))
+        .route("/games", get(controllers::players::get_player_lobbies))
+        .route("/:player_id", get(controllers::players::get_player))
+        .route("/:player_id/games", get(controllers::players::get_player_games))
+        .route(
+            "/:player_id/decks",
+            get(controllers::decks::get_decks).post(controllers::decks::save_deck),
+        )
+        .route("/leaderboard", get(controllers::players::get_leaderboard));
+
+    let series_routes = Router::new()
+        .route("/", post(controllers::series::create_series))
+        .route("/:series_id", get(controllers::series::get_series));
+
+    let admin_routes = Router::new()
+        .route("/games/force_resolve", post(controllers::admin::force_resolve))
+        .route("/maintenance", post(controllers::admin::set_maintenance))
+        .route("/webhooks", post(controllers::admin::register_webhook))
+        .route("/webhooks/:webhook_id", delete(controllers::admin::delete_webhook));
 
     let app = Router::new()
         .route("/", get(root))
+        .route("/healthz", get(controllers::health::healthz))
+        .route("/readyz", get(controllers::health::readyz))
+        .route("/metrics", get(telemetry::render_metrics))
+        .route("/arenas", get(controllers::arenas::get_arenas))
+        .route("/npcs", get(controllers::npcs::get_npcs))
         .nest("/games", games_routes)
         .nest("/player", player_routes)
+        .nest("/series", series_routes)
+        .nest("/admin", admin_routes)
+        .route_layer(axum::middleware::from_fn(telemetry::track_requests))
         // TODO: Replace with a real CORS policy
         .layer(CorsLayer::permissive())
         .layer(TraceLayer::new_for_http())
-        .with_state(db);
+        .with_state(state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 8000));
     tracing::info!("listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .expect("Failed to start server");
+
+    // let running proofs finish rather than leaving their games stuck in "playing"
+    tracing::info!("Waiting on {} running battles", battles.running());
+    if !battles.drain(env_secs("SHUTDOWN_DRAIN_SECS", 60 * 5)).await {
+        tracing::warn!(
+            "Gave up waiting on {} battles, they will be resumed on the next start",
+            battles.running()
+        );
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for ctrl-c");
+    };
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("Shutting down");
+}
+
+// Reads a config value from the environment, falling back to the default if unset
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} has an invalid value.", key))
+        })
+        .unwrap_or(default)
+}
+
+fn env_secs(key: &str, default: u64) -> Duration {
+    Duration::from_secs(env_or(key, default))
 }
 
 // basic handler that responds with a static string
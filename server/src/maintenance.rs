@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::options::UpdateOptions;
+use mongodb::Database;
+
+use crate::errors::AppError;
+use crate::state::AppState;
+
+// The document in the "setting" collection admins flip maintenance with, shared by every server
+const SETTING_ID: &str = "maintenance";
+
+// The kill switch for new battles during an incident, like the prover host being down or a bad
+// arena deploy. While it's on no battle is started, but reads, joins and the proofs already
+// running carry on. MAINTENANCE_MODE holds it on for a server whatever the setting says.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Maintenance {
+    pub enabled: bool,
+    // shown to players turned away, so they know why
+    #[serde(default)]
+    pub reason: Option<String>,
+    // on because of MAINTENANCE_MODE rather than the setting, admins can't turn it off
+    #[serde(default, skip_deserializing)]
+    pub forced: bool,
+    #[serde(default)]
+    pub set_by: Option<String>,
+    #[serde(default)]
+    pub updated_at: Option<bson::DateTime>,
+}
+
+pub async fn current(state: &AppState) -> Result<Maintenance, AppError> {
+    let setting = state
+        .db
+        .collection::<Document>("setting")
+        .find_one(doc! { "_id": SETTING_ID }, None)
+        .await?;
+    let mut maintenance = match setting {
+        Some(setting) => bson::from_document::<Maintenance>(setting)?,
+        None => Maintenance::default(),
+    };
+    if state.maintenance_forced {
+        maintenance.enabled = true;
+        maintenance.forced = true;
+    }
+    Ok(maintenance)
+}
+
+pub async fn set(
+    db: &Database,
+    enabled: bool,
+    reason: Option<String>,
+    set_by: &str,
+) -> Result<(), AppError> {
+    let upsert = UpdateOptions::builder().upsert(true).build();
+    db.collection::<Document>("setting")
+        .update_one(
+            doc! { "_id": SETTING_ID },
+            doc! {
+                "$set": {
+                    "enabled": enabled,
+                    "reason": reason,
+                    "set_by": set_by,
+                    "updated_at": bson::DateTime::now(),
+                }
+            },
+            upsert,
+        )
+        .await?;
+    Ok(())
+}
+
+// Turns a new battle away with a 503 while maintenance is on
+pub async fn check(state: &AppState) -> Result<(), AppError> {
+    let maintenance = current(state).await?;
+    if !maintenance.enabled {
+        return Ok(());
+    }
+    let reason = maintenance
+        .reason
+        .unwrap_or_else(|| String::from("New battles are paused for maintenance"));
+    Err(AppError::Unavailable(reason))
+}
@@ -1,59 +1,591 @@
 use bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
-use tenet_core::Deck;
+use tenet_core::{BattleEvent, Deck, GameResult, GuestError};
 
-// the input to our `create_user` handler
-#[derive(Deserialize)]
-pub struct FactorsInput {
-    pub a: u64,
-    pub b: u64,
-}
+use crate::maintenance::Maintenance;
 
 #[derive(Deserialize)]
 pub struct JoinGameInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
     pub player_id: String,
+    #[serde(deserialize_with = "crate::ids::checked_or_empty")]
     pub lobby_id: String, // TODO: Make this optional
     pub create_new: bool,
+    // matchmade players who'd rather play an NPC than keep waiting on an opponent
+    #[serde(default)]
+    pub bot_fallback: bool,
+    // joins the private lobby the code was handed out for, instead of matchmaking
+    #[serde(default)]
+    pub join_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreatePrivateLobbyInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct GetGamesQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<u64>,
+    pub player_id: Option<String>,
+    // leave out games against NPCs, every game says whether it was one in is_npc_game
+    #[serde(default)]
+    pub exclude_npc_games: bool,
+}
+
+// What went wrong, for clients to act on without parsing `error`. Serialized as a stable upper
+// snake case name, so new codes can be added but existing ones must never be renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    ArenaNotFound,
+    ArenaMismatch,
+    InvalidCreation,
+    CreationHashMismatch,
+    LobbyNotFound,
+    LobbyFull,
+    LobbyNotFull,
+    LobbyTaken,
+    NotInLobby,
+    SelfMatch,
+    NotYourTurn,
+    NothingToConfirm,
+    ConfirmMismatch,
+    GameInProgress,
+    GameFinished,
+    GameEnded,
+    Conflict,
+    NpcNotFound,
+    AlreadyPlayedNpc,
+    BattleInProgress,
+    TooManyLobbies,
+    UnmigratableCreation,
+    PrivateLobby,
+    JoinCodeNotFound,
+    JoinCodeExpired,
+    JoinCodeUsed,
+    TooManyGames,
+    MissingCreation,
+    DeckNotFound,
+    DeckNotOwned,
+    TooManyDecks,
+    CreationsPending,
+    PlayerReady,
 }
 
 #[derive(Serialize)]
 pub struct GetGamesOutput {
     pub games: Vec<Game>,
+    pub total: u64,
+    pub next_offset: Option<u64>,
+    pub error: String,
+    pub error_code: Option<ErrorCode>,
+}
+
+#[derive(Serialize)]
+pub struct GetGameOutput {
+    pub game: Option<Game>,
+    // for a battle queued for a proof slot on the server that answered: its place in line, 1 for
+    // next, and roughly how long until its proof starts. No estimate before the server has
+    // finished a proof.
+    pub queue_position: Option<usize>,
+    pub estimated_wait_secs: Option<u64>,
+    pub error: String,
+}
+
+
+// What a replay viewer needs to rebuild a finished fight, without the creations
+#[derive(Serialize)]
+pub struct SpectateOutput {
+    pub lobby_id: String,
+    pub player1_id: String,
+    pub player2_id: String,
+    pub result: Option<String>,
+    pub winner_id: Option<String>,
+    pub timeline: Vec<BattleEvent>,
     pub error: String,
 }
 
+#[derive(Deserialize)]
+pub struct OpenLobbiesQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<u64>,
+    // leaves out the lobbies this player opened
+    pub player_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OpenLobby {
+    pub lobby_id: String,
+    pub player1_id: String,
+    // the creator's rating when they opened the lobby
+    pub player1_rating: Option<f64>,
+    pub created_at: Option<bson::DateTime>,
+}
+
+#[derive(Serialize)]
+pub struct OpenLobbiesOutput {
+    pub lobbies: Vec<OpenLobby>,
+    pub total: u64,
+    pub next_offset: Option<u64>,
+    pub error: String,
+}
+
+// The deck building rules of an arena, everything check_creation holds a creation to
+#[derive(Serialize)]
+pub struct ArenaRules {
+    pub cards_per_deck: usize,
+    // every card's health and attack add up to this, and health is at least 1
+    pub card_stat_total: u32,
+    pub min_health: u32,
+}
+
+#[derive(Serialize)]
+pub struct ArenaInfo {
+    pub arena_id: String,
+    pub name: String,
+    pub version: u32,
+    // the hash stored on games played here
+    pub arena_hash: String,
+    pub rules: ArenaRules,
+}
+
+#[derive(Deserialize)]
+pub struct ValidateCreationInput {
+    pub creation: Deck,
+    #[serde(default = "crate::arenas::default_arena_id")]
+    pub arena_id: String,
+}
+
+// One rule a creation breaks, with the card it's about for the per card rules
+#[derive(Serialize)]
+pub struct CreationViolation {
+    pub code: ErrorCode,
+    pub card: Option<usize>,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct ValidateCreationOutput {
+    pub valid: bool,
+    pub violations: Vec<CreationViolation>,
+    pub error: String,
+    pub error_code: Option<ErrorCode>,
+}
+
+#[derive(Serialize)]
+pub struct GetArenasOutput {
+    pub arenas: Vec<ArenaInfo>,
+    pub error: String,
+}
+
+// Who's in a lobby, without saying who they are
+#[derive(Serialize)]
+pub struct GetLobbyOutput {
+    pub lobby_id: String,
+    pub has_player1: bool,
+    pub has_player2: bool,
+    pub full: bool,
+    pub game_started: bool,
+    // the game's state once it has started
+    pub state: Option<String>,
+    pub error: String,
+}
+
+#[derive(Serialize)]
+pub struct CreatePrivateLobbyOutput {
+    pub lobby_id: String,
+    pub join_code: String,
+    pub expires_at: Option<bson::DateTime>,
+    pub error: String,
+    pub error_code: Option<ErrorCode>,
+}
 
 #[derive(Serialize)]
 pub struct JoinGameOutput {
     pub lobby_id: String,
-    pub error: String
+    pub error: String,
+    pub error_code: Option<ErrorCode>,
 }
 
 #[derive(Deserialize)]
 pub struct PlayGameInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
     pub lobby_id: String,
+    #[serde(deserialize_with = "crate::ids::checked")]
     pub player_id: String,
-    pub creation: Deck,
+    // the deck to play, or left out for the saved deck in deck_id
+    pub creation: Option<Deck>,
+    #[serde(default, deserialize_with = "crate::ids::checked_opt")]
+    pub deck_id: Option<String>,
+    #[serde(default = "crate::arenas::default_arena_id")]
+    pub arena_id: String,
+    // lets clients safely retry, a replayed key gets the previous response back
+    pub idempotency_key: Option<String>,
+    // the hash the client committed to for this creation, checked against the server's
+    pub claimed_hash: Option<String>,
+    // marks this player ready with the creation they already submitted, the battle starts once
+    // both players are
+    #[serde(default)]
+    pub confirm: bool,
 }
 
 #[derive(Deserialize)]
 pub struct PlayNPCGameInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
     pub player_id: String,
-    pub creation: Deck,
+    // the deck to play, or left out for the saved deck in deck_id
+    pub creation: Option<Deck>,
+    #[serde(default, deserialize_with = "crate::ids::checked_opt")]
+    pub deck_id: Option<String>,
+    #[serde(deserialize_with = "crate::ids::checked")]
     pub npc_id: String,
-    pub npc_creation: Deck,
+    #[serde(default = "crate::arenas::default_arena_id")]
+    pub arena_id: String,
+    // replays a battle with a known seed, a random one is picked otherwise
+    pub battle_seed: Option<u32>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct PlayGameOutput {
+    // defaulted for responses stored by idempotency keys before these were added
+    #[serde(default)]
+    pub lobby_id: String,
+    // the game's state after the submission
+    #[serde(default)]
+    pub state: String,
     pub error: String,
+    #[serde(default)]
+    pub error_code: Option<ErrorCode>,
 }
 
 #[derive(Serialize)]
 pub struct PlayNPCGameOutput {
+    pub lobby_id: String,
+    pub game_id: String,
+    pub state: String,
+    pub error: String,
+    pub error_code: Option<ErrorCode>,
+}
+
+#[derive(Deserialize)]
+pub struct PlayNPCGauntletInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+    pub creation: Deck,
+    // played in this order, each one its own game
+    pub npc_ids: Vec<String>,
+    #[serde(default = "crate::arenas::default_arena_id")]
+    pub arena_id: String,
+}
+
+#[derive(Serialize)]
+pub struct PlayNPCGauntletOutput {
+    // one per NPC, in the order asked for, with the error for any that couldn't be started
+    pub games: Vec<PlayNPCGameOutput>,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct CancelGameInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub lobby_id: String,
+}
+
+#[derive(Serialize)]
+pub struct CancelGameOutput {
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReadyInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub lobby_id: String,
+    // false takes back a ready, so the player can swap their creation
+    #[serde(default = "default_ready")]
+    pub ready: bool,
+}
+
+fn default_ready() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+pub struct ReadyOutput {
+    pub lobby_id: String,
+    pub state: String,
+    pub player1_ready: bool,
+    pub player2_ready: bool,
+    pub error: String,
+    pub error_code: Option<ErrorCode>,
+}
+
+// What an admin settles a stuck game with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForcedOutcome {
+    Error,
+    Player1Wins,
+    Player2Wins,
+}
+
+#[derive(Deserialize)]
+pub struct ForceResolveInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub game_id: String,
+    pub outcome: ForcedOutcome,
+    // why, kept in the audit log and on an errored game
+    pub reason: String,
+    // resolve a game that isn't "playing", even one that has already ended
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Serialize)]
+pub struct ForceResolveOutput {
+    pub lobby_id: String,
+    pub state: String,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterWebhookInput {
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct RegisterWebhookOutput {
+    pub webhook_id: String,
+    // the key deliveries are signed with, only ever handed out here
+    pub secret: String,
+    pub error: String,
+}
+
+#[derive(Serialize)]
+pub struct DeleteWebhookOutput {
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetMaintenanceInput {
+    pub enabled: bool,
+    // told to players whose battles are turned away
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SetMaintenanceOutput {
+    pub maintenance: Option<Maintenance>,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct SandboxBattleInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+    pub creation1: Deck,
+    pub creation2: Deck,
+    #[serde(default = "crate::arenas::default_arena_id")]
+    pub arena_id: String,
+    pub battle_seed: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct SandboxBattleOutput {
+    pub result: Option<GameResult>,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct SeriesInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub opponent_id: String,
+    pub best_of: u32,
+}
+
+#[derive(Serialize)]
+pub struct SeriesOutput {
+    pub series_id: String,
+    // the lobby for the first game
+    pub lobby_id: String,
     pub error: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct Series {
+    pub series_id: String,
+    pub player1_id: String,
+    pub player2_id: String,
+    pub best_of: u32,
+    pub player1_wins: u32,
+    pub player2_wins: u32,
+    // "active" until a player wins more than half of best_of, then "complete"
+    pub state: String,
+    pub winner_id: Option<String>,
+    // every game's lobby in the order they were played, the last one is the game to play next
+    pub lobby_ids: Vec<String>,
+    pub current_lobby_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetSeriesOutput {
+    pub series: Option<Series>,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct RequeueInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub lobby_id: String,
+}
+
+#[derive(Serialize)]
+pub struct RequeueOutput {
+    pub lobby_id: String,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConcedeInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub lobby_id: String,
+}
+
+#[derive(Serialize)]
+pub struct ConcedeOutput {
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct RematchInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+    // lobby of the finished game
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub lobby_id: String,
+    // submits the creation straight away, it has to match the last game's
+    pub creation: Option<Deck>,
+    // marks the player ready with that creation too, so they don't have to confirm it
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+#[derive(Serialize)]
+pub struct RematchOutput {
+    pub lobby_id: String,
+    pub error: String,
+}
+
+// A creation a player saved under a name, to play again by its deck_id
+#[derive(Serialize, Deserialize)]
+pub struct SavedDeck {
+    pub deck_id: String,
+    pub player_id: String,
+    pub name: String,
+    pub creation: Deck,
+    pub created_at: Option<bson::DateTime>,
+    pub updated_at: Option<bson::DateTime>,
+}
+
+#[derive(Deserialize)]
+pub struct SaveDeckInput {
+    pub name: String,
+    pub creation: Deck,
+}
+
+#[derive(Serialize)]
+pub struct SaveDeckOutput {
+    pub deck_id: String,
+    pub error: String,
+    pub error_code: Option<ErrorCode>,
+}
+
+#[derive(Serialize)]
+pub struct GetDecksOutput {
+    pub decks: Vec<SavedDeck>,
+    pub error: String,
+}
+
+// An entry in the NPC roster
+#[derive(Serialize, Deserialize)]
+pub struct Npc {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    pub npc_id: String,
+    pub creation: Deck,
+    // shown on the roster, the npc_id when there's none
+    pub name: Option<String>,
+    // 1 is the easiest
+    #[serde(default = "default_npc_difficulty")]
+    pub difficulty: u32,
+    // the arena the NPC's deck was built for
+    #[serde(default = "crate::arenas::default_arena_id")]
+    pub arena_id: String,
+}
+
+fn default_npc_difficulty() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+pub struct NpcsQuery {
+    pub arena_id: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<u64>,
+}
+
+// An NPC on the roster, without its deck so beating it is still a challenge
+#[derive(Serialize)]
+pub struct NpcEntry {
+    pub npc_id: String,
+    pub name: String,
+    pub difficulty: u32,
+    pub arena_id: String,
+}
+
+#[derive(Serialize)]
+pub struct GetNpcsOutput {
+    pub npcs: Vec<NpcEntry>,
+    pub total: u64,
+    pub next_offset: Option<u64>,
+    pub error: String,
+}
+
+// The outcome of a proven NPC battle, reused for later battles between the same creations
+#[derive(Serialize, Deserialize)]
+pub struct CachedBattle {
+    pub arena_id: String,
+    pub arena_hash: String,
+    pub creation1_hash: String,
+    pub creation2_hash: String,
+    // the game the battle was proven for
+    pub game_id: ObjectId,
+    pub result: String,
+    // which side won, 1 or 2, or 0 for a tie
+    pub winner: i32,
+    pub timeline: Vec<BattleEvent>,
+    #[serde(default)]
+    pub battle_seed: Option<u32>,
+    // the player asked for the seed, and with it picked the coin flip
+    #[serde(default)]
+    pub seed_chosen: bool,
+    // whether the battle's receipt carries a verified seal
+    #[serde(default)]
+    pub verified: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Lobby {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
@@ -61,6 +593,63 @@ pub struct Lobby {
     pub player1_id: Option<String>,
     pub player2_id: Option<String>,
     pub lobby_id: String,
+    #[serde(default)]
+    pub series_id: Option<String>,
+    // the NPC that took the open slot once the player waited too long, also in player2_id
+    #[serde(default)]
+    pub npc_id: Option<String>,
+    // only joined through its join code, never matchmade into
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub join_code: Option<String>,
+    #[serde(default)]
+    pub join_code_expires_at: Option<bson::DateTime>,
+}
+
+// Where a game is in its life, stored as the camelCase name. The transitions are described on
+// Game::state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GameState {
+    Player1Turn,
+    Player2Turn,
+    Playing,
+    Complete,
+    Error,
+    Abandoned,
+    Cancelled,
+}
+
+impl GameState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GameState::Player1Turn => "player1Turn",
+            GameState::Player2Turn => "player2Turn",
+            GameState::Playing => "playing",
+            GameState::Complete => "complete",
+            GameState::Error => "error",
+            GameState::Abandoned => "abandoned",
+            GameState::Cancelled => "cancelled",
+        }
+    }
+
+    // waiting on one of the players to submit or confirm a creation
+    pub fn is_turn(self) -> bool {
+        matches!(self, GameState::Player1Turn | GameState::Player2Turn)
+    }
+}
+
+impl std::fmt::Display for GameState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<GameState> for bson::Bson {
+    fn from(state: GameState) -> Self {
+        bson::Bson::String(state.as_str().to_string())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -74,22 +663,273 @@ pub struct Game {
     pub creation1_hash: Option<String>,
     pub creation2: Option<Deck>,
     pub creation2_hash: Option<String>,
+    // the nonces mixed into the creation hashes. Never sent to clients, they're all that stops
+    // the opponent brute forcing the deck from its hash.
+    #[serde(default, skip_serializing)]
+    pub creation1_nonce: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub creation2_nonce: Option<String>,
+    // the seed the battle's random effects are drawn from. Hidden like the nonces, it would let
+    // a player build their deck around the rolls; the journal records it once the game is proven.
+    #[serde(default, skip_serializing)]
+    pub battle_seed: Option<u32>,
+    // the player asked for the seed, which picks the coin flip as well. Such a game is never
+    // rated, and its battle is only reused for a request naming the same seed.
+    #[serde(default)]
+    pub seed_chosen: bool,
+    // which player's cards struck first, 1 or 2, from the coin flip drawn from the seed. Stored
+    // with the result, before that it would give away as much as the seed.
+    #[serde(default)]
+    pub first_attacker: Option<u8>,
+    // set when the game is created and never changed, every later submission must name it
+    #[serde(default = "crate::arenas::default_arena_id")]
+    pub arena_id: String,
     pub arena_hash: String,
     pub winner_creation_hash: Option<String>,
+    // the losing side's hash, kept with both creation hashes once the creations themselves are
+    // dropped so deck win rates can be worked out. None for ties.
+    #[serde(default)]
+    pub loser_creation_hash: Option<String>,
     pub winner_id: Option<String>,
-    pub state: String,
+    // A game starts when the first creation is submitted and is waiting on the other player:
+    //   player2Turn / player1Turn: the named player submits their creation, the turn passes back
+    //     to the other one. On their next turn a player sends `confirm` with the same creation to
+    //     mark it ready, or a different creation to replace theirs. The battle starts once both
+    //     players are ready.
+    //   playing: the battle is being proven
+    //   complete: the result is committed
+    //   error: the battle couldn't be proven or the guest rejected a creation
+    //   abandoned: a player let their turn time out, the other one wins
+    //   cancelled: a player backed out before the battle started
+    pub state: GameState,
+    // whether each player is ready to battle with the creations as they are. Once both are in,
+    // the battle only starts when both players are ready, and a ready player has to take it back
+    // before swapping their creation.
+    #[serde(default)]
+    pub player1_ready: bool,
+    #[serde(default)]
+    pub player2_ready: bool,
+    // which phase of the battle a "playing" game is in: queued, proving, verifying or
+    // committing
+    pub progress: Option<String>,
     pub result: Option<String>,
+    pub error: Option<String>,
+    // set alongside error when the guest itself rejected the battle, e.g. which deck was invalid
+    #[serde(default)]
+    pub guest_error: Option<GuestError>,
+    pub created_at: Option<bson::DateTime>,
+    // when the game moved to "playing". Games that got there before it was kept have none.
+    #[serde(default)]
+    pub started_at: Option<bson::DateTime>,
+    pub updated_at: Option<bson::DateTime>,
+    #[serde(default)]
+    pub is_npc_game: bool,
+    // the hash of the NPC's roster deck when the game was started. The proven creation2_hash has
+    // to match it, so a result for any other deck can't be committed.
+    #[serde(default)]
+    pub npc_deck_hash: Option<String>,
+    // whether the result moves the players' ratings
+    #[serde(default = "default_rated")]
+    pub rated: bool,
+    // turn by turn record of the battle, for replaying it on the client
+    #[serde(default)]
+    pub timeline: Vec<BattleEvent>,
+    // the journal the guest committed, so a client can check the result against the proof
+    // itself. Only served for a single game, like the timeline.
+    #[serde(default)]
+    pub journal: Option<Vec<u8>>,
+    // set when the outcome was reused from the proof of an identical earlier battle
+    pub cached_from: Option<ObjectId>,
+    // the best-of-N series this game is part of, if any
+    #[serde(default)]
+    pub series_id: Option<String>,
+    // whether the result is backed by a verified seal. With optimistic proving a result is shown
+    // unverified until its sealed proof is done, and moves to "error" if that fails.
+    #[serde(default)]
+    pub verified: bool,
+}
+
+fn default_rated() -> bool {
+    true
 }
 
+// A battle proven off the server, submitted by one of its players
 #[derive(Deserialize)]
 pub struct CommitOutcomeInput {
+    #[serde(deserialize_with = "crate::ids::checked")]
     pub player_id: String,
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub game_id: String,
+    pub journal: Vec<u8>,
+    pub seal: Vec<u32>,
+}
+
+#[derive(Serialize)]
+pub struct CommitOutcomeOutput {
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct BattleInputsQuery {
+    #[serde(deserialize_with = "crate::ids::checked")]
+    pub player_id: String,
+}
+
+// What the arena is fed for a game being played, so a player can prove the battle themselves.
+// `input` is the exact words the guest reads, the fields are the same values spelled out.
+#[derive(Serialize)]
+pub struct BattleInputsOutput {
+    pub game_id: String,
+    pub arena_id: String,
+    pub player1_id: String,
+    pub creation1: Option<Deck>,
+    pub creation1_nonce: String,
+    pub player2_id: String,
+    pub creation2: Option<Deck>,
+    pub creation2_nonce: String,
+    pub battle_seed: u32,
+    pub first_attacker: u8,
+    pub input: Vec<u32>,
+    pub error: String,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct Receipt {
+    pub game_id: ObjectId,
     pub journal: Vec<u8>,
     pub seal: Vec<u32>,
+    // what the receipt was proven with. Receipts stored before these were kept have none.
+    #[serde(default)]
+    pub method_id: Option<[u32; 8]>,
+    #[serde(default)]
+    pub risc0_version: Option<String>,
+    // the server that proved it, or "client" for a receipt a player committed
+    #[serde(default)]
+    pub prover_host: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct VerifyGameOutput {
+    pub verified: bool,
+    // set when the receipt was proven with a different method than the arena has now
+    pub warning: String,
+    pub error: String,
+}
+
+// Everything a client needs to run the receipt's verification itself
+#[derive(Serialize)]
+pub struct GetReceiptOutput {
+    pub game_id: String,
+    pub arena_id: String,
+    // the method ID of the arena's guest, the image the receipt has to verify against
+    pub method_id: [u32; 8],
+    pub journal: Vec<u8>,
+    pub seal: Vec<u32>,
+    pub error: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardSort {
+    #[default]
+    Wins,
+    WinRate,
+    Rating,
+}
+
+#[derive(Deserialize)]
+pub struct LeaderboardQuery {
+    #[serde(default)]
+    pub sort: LeaderboardSort,
+    pub limit: Option<i64>,
+    pub offset: Option<u64>,
+    // only count games between two players
+    #[serde(default)]
+    pub exclude_npc_games: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub player_id: String,
+    pub games: i64,
+    pub wins: i64,
+    pub win_rate: f64,
+    pub rating: f64,
+}
+
+#[derive(Serialize)]
+pub struct LeaderboardOutput {
+    pub players: Vec<LeaderboardEntry>,
+    pub total: u64,
+    pub next_offset: Option<u64>,
+    pub error: String,
+}
+
+// One of the player's finished games, from their side of the board
+#[derive(Serialize, Deserialize)]
+pub struct GameSummary {
+    pub lobby_id: String,
+    #[serde(default = "crate::arenas::default_arena_id")]
+    pub arena_id: String,
+    pub opponent_id: String,
+    // "win", "loss" or "draw"
+    pub outcome: String,
+    pub is_npc_game: bool,
+    pub finished_at: Option<bson::DateTime>,
+}
+
+#[derive(Serialize)]
+pub struct PlayerProfileOutput {
+    pub player_id: String,
+    // none until the player finishes a rated game
+    pub rating: Option<f64>,
+    pub games: i64,
+    pub wins: i64,
+    pub losses: i64,
+    pub draws: i64,
+    // newest first
+    pub recent_games: Vec<GameSummary>,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeckStatsQuery {
+    pub arena_id: Option<String>,
+    // decks played fewer times than this are left out, one lucky win isn't a 100% deck
+    pub min_games: Option<i64>,
+    pub limit: Option<i64>,
+    pub offset: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeckStatsEntry {
+    pub deck_hash: String,
+    pub games: i64,
+    pub wins: i64,
+    pub losses: i64,
+    pub win_rate: f64,
+}
+
+#[derive(Serialize)]
+pub struct DeckStatsOutput {
+    pub decks: Vec<DeckStatsEntry>,
+    pub total: u64,
+    pub next_offset: Option<u64>,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct PlayerGamesQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct NewPlayerOutput {
+    pub player_id: String,
+    // sent back as `Authorization: Bearer <token>` on player requests
+    pub token: String,
+    pub error: String,
 }
 
 #[derive(Deserialize)]
@@ -99,6 +939,42 @@ pub struct PlayerInfo {
 
 #[derive(Serialize)]
 pub struct PlayerGamesOutput {
-    pub games: Vec<Game>,
+    pub games: Vec<PlayerLobby>,
     pub error: String,
 }
+
+// An entry in a player's lobby list: the lobby's game, or the lobby itself until one starts
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum PlayerLobby {
+    Game(Box<Game>),
+    Pending(PendingLobby),
+}
+
+// A lobby without a game, with the players and state a game would show. A missing player is
+// an empty string.
+#[derive(Serialize)]
+pub struct PendingLobby {
+    pub lobby_id: String,
+    pub player1_id: String,
+    pub player2_id: String,
+    pub state: LobbyState,
+}
+
+// Where a lobby without a game is: "lobby" while it waits on an opponent, "setup" once both
+// players are in and neither has submitted a creation. Never stored, a game has a GameState.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LobbyState {
+    Lobby,
+    Setup,
+}
+
+impl LobbyState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LobbyState::Lobby => "lobby",
+            LobbyState::Setup => "setup",
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::response::IntoResponse;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+// How many unread events a slow subscriber can fall behind by before it starts missing some
+const EVENT_BUFFER: usize = 16;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GameEvent {
+    pub lobby_id: String,
+    pub state: String,
+    pub winner_id: Option<String>,
+}
+
+// Fans game state changes out to the websocket subscribers of each lobby
+#[derive(Clone, Default)]
+pub struct Notifier {
+    channels: Arc<Mutex<HashMap<String, broadcast::Sender<GameEvent>>>>,
+}
+
+impl Notifier {
+    pub fn subscribe(&self, lobby_id: &str) -> broadcast::Receiver<GameEvent> {
+        let mut channels = self.channels.lock().unwrap();
+        channels
+            .entry(lobby_id.to_string())
+            .or_insert_with(|| broadcast::channel(EVENT_BUFFER).0)
+            .subscribe()
+    }
+
+    pub fn publish(&self, event: GameEvent) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(&event.lobby_id) {
+            let lobby_id = event.lobby_id.clone();
+            // sending only fails when nobody is listening anymore
+            if sender.send(event).is_err() {
+                channels.remove(&lobby_id);
+            }
+        }
+    }
+
+    // Drops the lobby's channel once its last subscriber has gone
+    fn prune(&self, lobby_id: &str) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(lobby_id) {
+            if sender.receiver_count() == 0 {
+                channels.remove(lobby_id);
+            }
+        }
+    }
+}
+
+pub async fn subscribe_game(
+    ws: WebSocketUpgrade,
+    State(notifier): State<Notifier>,
+    Path(lobby_id): Path<String>,
+) -> impl IntoResponse {
+    tracing::info!("subscribe_game called");
+
+    ws.on_upgrade(move |socket| stream_events(socket, notifier, lobby_id))
+}
+
+async fn stream_events(mut socket: WebSocket, notifier: Notifier, lobby_id: String) {
+    let mut events = notifier.subscribe(&lobby_id);
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let message = serde_json::to_string(&event).unwrap();
+                    if socket.send(Message::Text(message)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Subscriber for lobby {} missed {} events", lobby_id, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                // we don't expect anything from the client, just watch for it going away
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    drop(events);
+    notifier.prune(&lobby_id);
+}
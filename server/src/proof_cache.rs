@@ -0,0 +1,60 @@
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::options::ReplaceOptions;
+use mongodb::Database;
+
+use crate::errors::AppError;
+use crate::models::games;
+
+// A battle between the same two creations in the same arena plays out the same way for the same
+// coin flip, so NPC battles are remembered by arena and creation hashes along with their seed.
+// The player ids are part of the proof, which is why a hit reuses the outcome and points at the
+// game holding the original proof. Asking for a seed only hits a battle played with that seed,
+// otherwise any seed will do but one a player picked, since they picked who went first with it.
+pub async fn lookup(
+    db: &Database,
+    arena_id: &str,
+    arena_hash: &str,
+    creation1_hash: &str,
+    creation2_hash: &str,
+    battle_seed: Option<u32>,
+) -> Result<Option<games::CachedBattle>, AppError> {
+    let mut filter = doc! {
+        "arena_id": arena_id,
+        "arena_hash": arena_hash,
+        "creation1_hash": creation1_hash,
+        "creation2_hash": creation2_hash,
+    };
+    match battle_seed {
+        Some(battle_seed) => filter.insert("battle_seed", battle_seed),
+        None => filter.insert("seed_chosen", doc! { "$ne": true }),
+    };
+    let cached = db
+        .collection::<Document>("proof_cache")
+        .find_one(filter, None)
+        .await?;
+
+    match cached {
+        Some(cached) => Ok(Some(bson::from_bson(bson::to_bson(&cached)?)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn store(db: &Database, cached: &games::CachedBattle) -> Result<(), AppError> {
+    let upsert = ReplaceOptions::builder().upsert(true).build();
+    db.collection::<Document>("proof_cache")
+        .replace_one(
+            doc! {
+                "arena_id": cached.arena_id.clone(),
+                "arena_hash": cached.arena_hash.clone(),
+                "creation1_hash": cached.creation1_hash.clone(),
+                "creation2_hash": cached.creation2_hash.clone(),
+            },
+            bson::to_document(cached)?,
+            upsert,
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Past this many tracked players, buckets that have refilled are dropped
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+// Token bucket per player. Each request takes a token; tokens come back at a steady rate up to
+// the burst size.
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    burst: f64,
+    per_second: f64,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: f64, burst: f64) -> RateLimiter {
+        RateLimiter {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            burst: burst.max(1.0),
+            per_second: per_minute.max(f64::MIN_POSITIVE) / 60.0,
+        }
+    }
+
+    // Takes a token for the player, or says how long until one is available
+    pub fn check(&self, player_id: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_TRACKED_BUCKETS {
+            let (burst, per_second) = (self.burst, self.per_second);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * per_second
+                    < burst
+            });
+        }
+
+        let bucket = buckets.entry(player_id.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            refilled_at: now,
+        });
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second))
+        }
+    }
+}
+
+// The limits on the requests that start proofs
+#[derive(Clone)]
+pub struct RateLimits {
+    pub play: RateLimiter,
+    // NPC games are proven as soon as they're created, so these get the tighter limit
+    pub npc: RateLimiter,
+    // sandbox battles count for nothing but still cost a proof each
+    pub sandbox: RateLimiter,
+}
@@ -0,0 +1,175 @@
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::options::UpdateOptions;
+use mongodb::Database;
+
+use crate::errors::AppError;
+
+// Rating given to players that haven't finished a rated game yet
+pub const DEFAULT_RATING: f64 = 1200.0;
+
+// How far a single game can move a rating
+const K_FACTOR: f64 = 32.0;
+
+// Chance of a player rated `rating` beating one rated `opponent_rating`
+pub fn expected_score(rating: f64, opponent_rating: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent_rating - rating) / 400.0))
+}
+
+// New (winner, loser) ratings after a decisive game
+pub fn elo_update(winner_rating: f64, loser_rating: f64) -> (f64, f64) {
+    let change = K_FACTOR * (1.0 - expected_score(winner_rating, loser_rating));
+    (winner_rating + change, loser_rating - change)
+}
+
+// New (player1, player2) ratings after a draw, the lower rated player gains
+pub fn elo_draw(player1_rating: f64, player2_rating: f64) -> (f64, f64) {
+    let change = K_FACTOR * (0.5 - expected_score(player1_rating, player2_rating));
+    (player1_rating + change, player2_rating - change)
+}
+
+pub async fn get_rating(db: &Database, player_id: &str) -> Result<f64, AppError> {
+    let players = db.collection::<Document>("player");
+    let player = players
+        .find_one(
+            doc! {
+                "player_id": player_id,
+            },
+            None,
+        )
+        .await?;
+
+    Ok(player
+        .and_then(|player| player.get_f64("rating").ok())
+        .unwrap_or(DEFAULT_RATING))
+}
+
+// Moves a player's rating by `change` in a single update, starting a player without one at the
+// default rating. Adding to whatever is stored rather than writing back a rating read earlier
+// means two games finishing at once both count.
+async fn add_rating(db: &Database, player_id: &str, change: f64) -> Result<(), AppError> {
+    let players = db.collection::<Document>("player");
+    let upsert = UpdateOptions::builder().upsert(true).build();
+    let add = vec![doc! {
+        "$set": {
+            "rating": { "$add": [{ "$ifNull": ["$rating", DEFAULT_RATING] }, change] }
+        }
+    }];
+    players
+        .update_one(
+            doc! {
+                "player_id": player_id,
+            },
+            add,
+            upsert,
+        )
+        .await?;
+
+    Ok(())
+}
+
+// Applies the Elo update for a finished game, creating either player at the default rating.
+// Returns how many points moved from the loser to the winner.
+pub async fn apply_result(db: &Database, winner_id: &str, loser_id: &str) -> Result<f64, AppError> {
+    let winner_rating = get_rating(db, winner_id).await?;
+    let loser_rating = get_rating(db, loser_id).await?;
+    let (new_winner_rating, _) = elo_update(winner_rating, loser_rating);
+    let change = new_winner_rating - winner_rating;
+
+    add_rating(db, winner_id, change).await?;
+    add_rating(db, loser_id, -change).await?;
+
+    Ok(change)
+}
+
+// Applies the Elo update for a drawn game. Returns how many points moved to player 1, negative
+// if they went to player 2, so it can be taken back like a decisive game's.
+pub async fn apply_draw(db: &Database, player1_id: &str, player2_id: &str) -> Result<f64, AppError> {
+    let player1_rating = get_rating(db, player1_id).await?;
+    let player2_rating = get_rating(db, player2_id).await?;
+    let (new_player1_rating, _) = elo_draw(player1_rating, player2_rating);
+    let change = new_player1_rating - player1_rating;
+
+    add_rating(db, player1_id, change).await?;
+    add_rating(db, player2_id, -change).await?;
+
+    Ok(change)
+}
+
+// Takes back the points a game moved, leaving whatever later games did to the ratings in place
+pub async fn revert_result(
+    db: &Database,
+    winner_id: &str,
+    loser_id: &str,
+    change: f64,
+) -> Result<(), AppError> {
+    let players = db.collection::<Document>("player");
+    players
+        .update_one(doc! { "player_id": winner_id }, doc! { "$inc": { "rating": -change } }, None)
+        .await?;
+    players
+        .update_one(doc! { "player_id": loser_id }, doc! { "$inc": { "rating": change } }, None)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn assert_close(actual: (f64, f64), expected: (f64, f64)) {
+        assert!((actual.0 - expected.0).abs() < 1e-3, "{:?} != {:?}", actual, expected);
+        assert!((actual.1 - expected.1).abs() < 1e-3, "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn even_game_moves_half_the_k_factor() {
+        assert_close(elo_update(1200.0, 1200.0), (1216.0, 1184.0));
+    }
+
+    #[test]
+    fn favourite_winning_moves_less() {
+        assert_close(elo_update(1400.0, 1200.0), (1407.688, 1192.312));
+        assert_close(elo_update(1200.0, 1400.0), (1224.312, 1375.688));
+    }
+
+    #[test]
+    fn even_draw_moves_nothing() {
+        assert_close(elo_draw(1200.0, 1200.0), (1200.0, 1200.0));
+    }
+
+    #[test]
+    fn draw_moves_points_to_the_lower_rated() {
+        assert_close(elo_draw(1200.0, 1400.0), (1208.312, 1391.688));
+        assert_close(elo_draw(1400.0, 1200.0), (1391.688, 1208.312));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn games_finishing_at_once_both_count() {
+        let db = testing::db().await;
+
+        let (first, second) =
+            tokio::join!(apply_result(&db, "p1", "p2"), apply_result(&db, "p1", "p3"));
+        let change = first.unwrap() + second.unwrap();
+
+        let p1 = get_rating(&db, "p1").await.unwrap();
+        assert!((p1 - (DEFAULT_RATING + change)).abs() < 1e-9);
+        assert!(p1 > DEFAULT_RATING + 16.0);
+        let others = get_rating(&db, "p2").await.unwrap() + get_rating(&db, "p3").await.unwrap();
+        assert!((p1 + others - 3.0 * DEFAULT_RATING).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGODB_URI"]
+    async fn draw_between_new_players_leaves_the_default() {
+        let db = testing::db().await;
+
+        assert_eq!(apply_draw(&db, "p1", "p2").await.unwrap(), 0.0);
+        assert_eq!(get_rating(&db, "p1").await.unwrap(), DEFAULT_RATING);
+        assert_eq!(get_rating(&db, "p2").await.unwrap(), DEFAULT_RATING);
+    }
+}
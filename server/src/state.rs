@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::FromRef;
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::sync::{oneshot, Notify, Semaphore};
+
+use crate::telemetry::ProofDurations;
+
+// DB
+use mongodb::bson::oid::ObjectId;
+use mongodb::Database;
+
+use crate::arenas::ArenaMethods;
+use crate::notifications::Notifier;
+use crate::ratelimit::RateLimits;
+use crate::webhooks::Webhooks;
+
+// Tuning for rating-based matchmaking in join_game
+#[derive(Debug, Clone, Copy)]
+pub struct MatchmakingConfig {
+    // how far apart two players' ratings can be to be matched straight away
+    pub rating_band: f64,
+    // how much the band widens for every minute an open lobby has been waiting
+    pub band_growth_per_minute: f64,
+    // how many lobbies a player can have waiting on an opponent at once
+    pub max_open_lobbies: u64,
+    // how many games a player can have going at once, PvP and NPC alike
+    pub max_active_games: u64,
+    // how long the join code of a private lobby can be used
+    pub join_code_ttl: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RatingConfig {
+    // whether games against NPCs move the player's rating
+    pub rate_npc_games: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AuthConfig {
+    // turn away player requests without a bearer token, only ever off for local testing
+    pub required: bool,
+}
+
+// How our own battles are proven. Dev skips the seal, which makes proofs quick but means the
+// results are never verified, so it must never run in production. Optimistic commits the quick
+// result straight away, then proves the battle again with a seal in the background and takes
+// the result back if that doesn't verify.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProofMode {
+    Dev,
+    Optimistic,
+    Prod,
+}
+
+impl ProofMode {
+    pub fn skip_seal(self) -> bool {
+        self != ProofMode::Prod
+    }
+
+    // receipts without a seal have nothing to verify
+    pub fn verify(self) -> bool {
+        self == ProofMode::Prod
+    }
+
+    pub fn verify_later(self) -> bool {
+        self == ProofMode::Optimistic
+    }
+}
+
+impl FromStr for ProofMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<ProofMode, String> {
+        match mode.to_ascii_lowercase().as_str() {
+            "dev" => Ok(ProofMode::Dev),
+            "optimistic" => Ok(ProofMode::Optimistic),
+            "prod" => Ok(ProofMode::Prod),
+            _ => Err(format!("Unknown proof mode {}", mode)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    pub db: Database,
+    pub matchmaking: MatchmakingConfig,
+    pub rating: RatingConfig,
+    pub auth: AuthConfig,
+    pub limits: RateLimits,
+    pub notifier: Notifier,
+    // one permit per proof allowed to run at once, battles past that wait their turn
+    pub proof_slots: Arc<Semaphore>,
+    // how many permits proof_slots was made with
+    pub proof_concurrency: usize,
+    pub proof_queue: ProofQueue,
+    pub proof_durations: ProofDurations,
+    // how long a proof gets before its game is moved to "error"
+    pub proof_timeout: Duration,
+    pub proof_mode: ProofMode,
+    // holds maintenance on whatever admins set, from MAINTENANCE_MODE
+    pub maintenance_forced: bool,
+    // how many more times a battle is proven after its proof failed on the host or stalled
+    pub proof_max_retries: i32,
+    // stamped on the receipts proven here, so a bad proof can be traced to the machine
+    pub prover_host: String,
+    pub battles: BattleTasks,
+    pub webhooks: Webhooks,
+    pub arena_methods: ArenaMethods,
+    pub metrics: PrometheusHandle,
+}
+
+// Keeps count of the battle tasks still running so shutdown can wait for them
+#[derive(Clone, Default)]
+pub struct BattleTasks {
+    running: Arc<AtomicUsize>,
+    finished: Arc<Notify>,
+}
+
+// Held by a battle task for as long as it runs
+pub struct BattleGuard {
+    tasks: BattleTasks,
+}
+
+impl BattleTasks {
+    pub fn start(&self) -> BattleGuard {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        BattleGuard { tasks: self.clone() }
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    // Waits for every running battle to finish, false if the timeout ran out first
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let drained = async {
+            loop {
+                // registered before checking so a battle finishing in between isn't missed
+                let finished = self.finished.notified();
+                if self.running() == 0 {
+                    return;
+                }
+                finished.await;
+            }
+        };
+        tokio::time::timeout(timeout, drained).await.is_ok()
+    }
+}
+
+impl Drop for BattleGuard {
+    fn drop(&mut self) {
+        if self.tasks.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.tasks.finished.notify_waiters();
+        }
+    }
+}
+
+// Each waiting game's ticket, with the sender that tells its battle it was cancelled
+type Waiting = HashMap<ObjectId, (u64, oneshot::Sender<()>)>;
+
+// The battles of this process waiting on a proof slot, by game. Whichever of the battle taking
+// its slot and a cancel gets the game out of here first wins, the other backs off.
+#[derive(Clone, Default)]
+pub struct ProofQueue {
+    waiting: Arc<Mutex<Waiting>>,
+    // handed out in the order games are queued, so a game's place in line is how many of the
+    // games waiting got an earlier ticket
+    tickets: Arc<AtomicU64>,
+}
+
+impl ProofQueue {
+    // Queues the game, the receiver hears about it if the game is cancelled before its proof
+    pub fn enqueue(&self, game_id: Option<ObjectId>) -> oneshot::Receiver<()> {
+        let (cancel, cancelled) = oneshot::channel();
+        if let Some(game_id) = game_id {
+            let ticket = self.tickets.fetch_add(1, Ordering::SeqCst);
+            self.waiting.lock().unwrap().insert(game_id, (ticket, cancel));
+        }
+        cancelled
+    }
+
+    // Takes the game out once it has a slot, false if it was cancelled first
+    pub fn dequeue(&self, game_id: Option<ObjectId>) -> bool {
+        match game_id {
+            Some(game_id) => self.waiting.lock().unwrap().remove(&game_id).is_some(),
+            None => true,
+        }
+    }
+
+    // False if the game isn't waiting here: its proof already began, or it's queued on another
+    // instance
+    pub fn cancel(&self, game_id: ObjectId) -> bool {
+        match self.waiting.lock().unwrap().remove(&game_id) {
+            Some((_, cancel)) => {
+                // the battle may have stopped listening, the removal is what counts
+                let _ = cancel.send(());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Where the game is in line, 1 for the next one to get a slot. None if it isn't waiting
+    // here.
+    pub fn position(&self, game_id: ObjectId) -> Option<usize> {
+        let waiting = self.waiting.lock().unwrap();
+        let (ticket, _) = waiting.get(&game_id)?;
+        Some(waiting.values().filter(|(other, _)| other <= ticket).count())
+    }
+}
+
+impl FromRef<AppState> for Database {
+    fn from_ref(state: &AppState) -> Database {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for MatchmakingConfig {
+    fn from_ref(state: &AppState) -> MatchmakingConfig {
+        state.matchmaking
+    }
+}
+
+impl FromRef<AppState> for RatingConfig {
+    fn from_ref(state: &AppState) -> RatingConfig {
+        state.rating
+    }
+}
+
+impl FromRef<AppState> for AuthConfig {
+    fn from_ref(state: &AppState) -> AuthConfig {
+        state.auth
+    }
+}
+
+impl FromRef<AppState> for PrometheusHandle {
+    fn from_ref(state: &AppState) -> PrometheusHandle {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Notifier {
+    fn from_ref(state: &AppState) -> Notifier {
+        state.notifier.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A battle taking its slot and a cancel for it landing at the same moment, many times over.
+    // Exactly one of them gets the game, and the battle hears about the cancel only if it won.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn dequeue_and_cancel_never_both_win() {
+        let queue = ProofQueue::default();
+        for _ in 0..500 {
+            let game_id = ObjectId::new();
+            let mut cancelled = queue.enqueue(Some(game_id));
+            let start = Arc::new(tokio::sync::Barrier::new(2));
+
+            let (taker, canceller) = (queue.clone(), queue.clone());
+            let (taker_start, canceller_start) = (start.clone(), start);
+            let dequeued = tokio::spawn(async move {
+                taker_start.wait().await;
+                taker.dequeue(Some(game_id))
+            });
+            let cancel = tokio::spawn(async move {
+                canceller_start.wait().await;
+                canceller.cancel(game_id)
+            });
+            let (dequeued, cancel) = (dequeued.await.unwrap(), cancel.await.unwrap());
+
+            assert!(dequeued != cancel, "dequeued {} and cancelled {}", dequeued, cancel);
+            assert_eq!(cancelled.try_recv().is_ok(), cancel);
+            assert_eq!(queue.position(game_id), None);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::IntoResponse,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+// Proofs take anywhere from seconds to many minutes, requests are much quicker
+const PROOF_BUCKETS: &[f64] = &[1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0];
+const REQUEST_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+// How many of the latest proofs the queue wait estimate averages over
+const PROOF_DURATION_WINDOW: usize = 20;
+
+pub fn install_recorder() -> PrometheusHandle {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(String::from("proof_duration_seconds")), PROOF_BUCKETS)
+        .and_then(|builder| {
+            builder.set_buckets_for_metric(
+                Matcher::Full(String::from("commit_duration_seconds")),
+                REQUEST_BUCKETS,
+            )
+        })
+        .and_then(|builder| {
+            builder.set_buckets_for_metric(
+                Matcher::Full(String::from("http_request_duration_seconds")),
+                REQUEST_BUCKETS,
+            )
+        })
+        .expect("Metric buckets are never empty")
+        .install_recorder()
+        .expect("Failed to install the metrics recorder")
+}
+
+// The durations of this server's latest proofs, to estimate how long a queued battle waits.
+// Kept next to the histogram, which can't be read back.
+#[derive(Clone, Default)]
+pub struct ProofDurations {
+    recent: Arc<Mutex<VecDeque<Duration>>>,
+}
+
+impl ProofDurations {
+    pub fn record(&self, duration: Duration) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == PROOF_DURATION_WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(duration);
+    }
+
+    // None until a proof has finished here
+    pub fn average(&self) -> Option<Duration> {
+        let recent = self.recent.lock().unwrap();
+        if recent.is_empty() {
+            return None;
+        }
+        Some(recent.iter().sum::<Duration>() / recent.len() as u32)
+    }
+}
+
+// Counts and times every request by its route, not the raw path, so ids don't blow up the labels
+pub async fn track_requests<B>(request: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let started = Instant::now();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| String::from("unmatched"));
+    let method = request.method().to_string();
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    metrics::increment_counter!(
+        "http_requests_total",
+        "method" => method.clone(),
+        "path" => path.clone(),
+        "status" => status
+    );
+    metrics::histogram!(
+        "http_request_duration_seconds",
+        started.elapsed().as_secs_f64(),
+        "method" => method,
+        "path" => path
+    );
+
+    response
+}
+
+pub async fn render_metrics(State(handle): State<PrometheusHandle>) -> impl IntoResponse {
+    handle.render()
+}
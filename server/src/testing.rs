@@ -0,0 +1,115 @@
+// Shared setup for the tests that need a database. Those are #[ignore]d since most machines
+// don't have a MongoDB running, `cargo test -- --ignored` runs them against TEST_MONGODB_URI.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::response::Response;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::{Client, Database};
+use rand::{distributions::Alphanumeric, Rng};
+use tenet_core::{Card, Deck, CARD_STAT_TOTAL};
+
+use crate::notifications::Notifier;
+use crate::ratelimit::{RateLimiter, RateLimits};
+use crate::state::{self, AppState, ProofMode};
+use crate::telemetry::ProofDurations;
+use crate::webhooks::Webhooks;
+
+// A database of its own for every test, so tests running side by side never see each other's
+// games
+pub async fn db() -> Database {
+    let uri = std::env::var("TEST_MONGODB_URI")
+        .unwrap_or_else(|_| String::from("mongodb://localhost:27017"));
+    let client = Client::with_uri_str(uri).await.expect("TEST_MONGODB_URI must be reachable");
+    let name: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    let db = client.database(&format!("test_{}", name));
+    crate::db::ensure_indexes(&db).await.expect("Failed to create indexes");
+    db
+}
+
+// The server's state on top of the database, with the defaults main falls back to except that
+// auth isn't required. No arena methods are loaded, so a battle started on it never gets proven.
+pub fn app_state(db: Database) -> AppState {
+    AppState {
+        db,
+        matchmaking: state::MatchmakingConfig {
+            rating_band: 100.0,
+            band_growth_per_minute: 25.0,
+            max_open_lobbies: 3,
+            max_active_games: 5,
+            join_code_ttl: Duration::from_secs(60 * 30),
+        },
+        rating: state::RatingConfig { rate_npc_games: false },
+        auth: state::AuthConfig { required: false },
+        limits: RateLimits {
+            play: RateLimiter::new(6000.0, 1000.0),
+            npc: RateLimiter::new(6000.0, 1000.0),
+            sandbox: RateLimiter::new(6000.0, 1000.0),
+        },
+        notifier: Notifier::default(),
+        proof_slots: Arc::new(tokio::sync::Semaphore::new(1)),
+        proof_concurrency: 1,
+        proof_queue: state::ProofQueue::default(),
+        proof_durations: ProofDurations::default(),
+        proof_timeout: Duration::from_secs(60),
+        proof_mode: ProofMode::Dev,
+        maintenance_forced: false,
+        proof_max_retries: 0,
+        prover_host: String::from("test"),
+        battles: state::BattleTasks::default(),
+        webhooks: Webhooks::new(),
+        arena_methods: Arc::new(HashMap::new()),
+        metrics: PrometheusBuilder::new().build_recorder().handle(),
+    }
+}
+
+// A creation that follows the deck rules, `strength` sets it apart from the others a test plays
+pub fn deck(strength: u32) -> Deck {
+    Deck {
+        cards: [0, 1, 2, 3, 4].map(|card| {
+            let attack = (strength + card) % CARD_STAT_TOTAL;
+            Card { health: CARD_STAT_TOTAL - attack, attack }
+        }),
+    }
+}
+
+// A lobby both players have joined, with no game yet
+pub async fn full_lobby(db: &Database, lobby_id: &str, player1_id: &str, player2_id: &str) {
+    db.collection::<Document>("lobby")
+        .insert_one(
+            doc! {
+                "lobby_id": lobby_id,
+                "player1_id": player1_id,
+                "player2_id": player2_id,
+                "created_at": bson::DateTime::now(),
+            },
+            None,
+        )
+        .await
+        .expect("Failed to insert lobby");
+}
+
+// A handler's response body, which is always JSON
+pub async fn body_json(response: Response) -> serde_json::Value {
+    let body = hyper::body::to_bytes(response.into_body()).await.expect("Failed to read body");
+    serde_json::from_slice(&body).expect("Body is not JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decks_follow_the_deck_rules() {
+        for strength in 0..CARD_STAT_TOTAL {
+            assert_eq!(tenet_core::validate_creation(&deck(strength)), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{Binary, Bson, Document};
+use tenet_core::BattleEvent;
+
+use crate::errors::AppError;
+
+// How a game's timeline is stored, in "timeline_format". Games from before the timeline was
+// compressed have no format and keep it as a plain array under "timeline".
+// 1: bincode, zstd compressed, under "timeline_zstd"
+pub const TIMELINE_FORMAT: i32 = 1;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+// The timeline fields to `$set` on a game, compressed in the current format
+pub fn compressed(timeline: &[BattleEvent]) -> Result<Document, AppError> {
+    let encoded = bincode::serialize(timeline)
+        .map_err(|err| AppError::Internal(format!("Failed to encode timeline: {}", err)))?;
+    let bytes = zstd::bulk::compress(&encoded, COMPRESSION_LEVEL)
+        .map_err(|err| AppError::Internal(format!("Failed to compress timeline: {}", err)))?;
+
+    let mut fields = Document::new();
+    fields.insert("timeline_zstd", Binary { subtype: BinarySubtype::Generic, bytes });
+    fields.insert("timeline_format", TIMELINE_FORMAT);
+    Ok(fields)
+}
+
+// Puts a compressed timeline back under "timeline" in place, before the document is read as a
+// Game. A game stored before compression already has it there and is left as it is.
+pub fn inflate(game: &mut Document) -> Result<(), AppError> {
+    let format = match game.get("timeline_format") {
+        Some(Bson::Int32(format)) => *format,
+        _ => return Ok(()),
+    };
+    if format != TIMELINE_FORMAT {
+        return Err(AppError::Internal(format!("Unknown timeline format {}", format)));
+    }
+
+    let bytes = game.get_binary_generic("timeline_zstd")?;
+    let encoded = zstd::stream::decode_all(bytes.as_slice())
+        .map_err(|err| AppError::Internal(format!("Failed to decompress timeline: {}", err)))?;
+    let timeline: Vec<BattleEvent> = bincode::deserialize(&encoded)
+        .map_err(|err| AppError::Internal(format!("Failed to decode timeline: {}", err)))?;
+
+    game.insert("timeline", bson::to_bson(&timeline)?);
+    game.remove("timeline_zstd");
+    game.remove("timeline_format");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn timeline() -> Vec<BattleEvent> {
+        (0..tenet_core::MAX_TIMELINE_EVENTS as u32)
+            .map(|event| BattleEvent {
+                turn: event / 2 + 1,
+                attacker: (event % 2 + 1) as u8,
+                attacker_card: (event % 5) as u8,
+                defender_card: (event % 5) as u8,
+                damage: event % 10,
+                defender_health: 10 - event % 10,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compressed_timeline_inflates_to_the_same_events() {
+        let mut game = doc! { "lobby_id": "lobby" };
+        game.extend(compressed(&timeline()).unwrap());
+        assert!(!game.contains_key("timeline"));
+
+        inflate(&mut game).unwrap();
+        assert_eq!(game.get("timeline"), Some(&bson::to_bson(&timeline()).unwrap()));
+        assert!(!game.contains_key("timeline_zstd"));
+        assert!(!game.contains_key("timeline_format"));
+    }
+
+    #[test]
+    fn uncompressed_timeline_is_left_as_it_is() {
+        let legacy = doc! { "lobby_id": "lobby", "timeline": bson::to_bson(&timeline()).unwrap() };
+        let mut game = legacy.clone();
+
+        inflate(&mut game).unwrap();
+        assert_eq!(game, legacy);
+    }
+
+    #[test]
+    fn unknown_timeline_format_is_an_error() {
+        let mut game = doc! { "lobby_id": "lobby", "timeline_format": TIMELINE_FORMAT + 1 };
+        assert!(inflate(&mut game).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::Serialize;
+use sha2::Sha256;
+
+// DB
+use mongodb::bson::doc;
+use mongodb::bson::Document;
+use mongodb::Database;
+
+// The header receivers check the body against, "sha256=" and the hex HMAC of the body keyed
+// with the webhook's secret
+pub const SIGNATURE_HEADER: &str = "X-Tenet-Signature";
+
+// How many times a delivery is tried, the first wait between tries and how long a receiver
+// gets to answer each one. The wait doubles every try.
+const DELIVERY_ATTEMPTS: u32 = 5;
+const DELIVERY_BACKOFF: Duration = Duration::from_secs(2);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Sent to every registered webhook once a game is complete
+#[derive(Debug, Clone, Serialize)]
+pub struct GameCompleted {
+    pub event: &'static str,
+    pub game_id: String,
+    pub lobby_id: String,
+    pub arena_id: String,
+    // none for a draw
+    pub winner_id: Option<String>,
+    pub result: String,
+    pub completed_at: bson::DateTime,
+}
+
+// Hands finished games to the webhooks integrations registered
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new() -> Webhooks {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .expect("Failed to build the webhook client");
+        Webhooks { client }
+    }
+
+    // Delivers the event to every webhook in the background, the game is complete whether or
+    // not the receivers hear about it. Deliveries still being retried are lost on a restart.
+    pub fn game_completed(&self, db: &Database, event: GameCompleted) {
+        let (client, db) = (self.client.clone(), db.clone());
+        tokio::spawn(async move {
+            let body = match serde_json::to_vec(&event) {
+                Ok(body) => body,
+                Err(err) => {
+                    tracing::error!("Failed to serialize webhook event: {}", err);
+                    return;
+                }
+            };
+            let webhooks = match registered(&db).await {
+                Ok(webhooks) => webhooks,
+                Err(err) => {
+                    tracing::error!("Failed to read webhooks: {}", err);
+                    return;
+                }
+            };
+            for (url, secret) in webhooks {
+                let (client, body) = (client.clone(), body.clone());
+                tokio::spawn(deliver(client, url, secret, body, DELIVERY_BACKOFF));
+            }
+        });
+    }
+}
+
+async fn registered(db: &Database) -> mongodb::error::Result<Vec<(String, String)>> {
+    let mut cursor = db.collection::<Document>("webhook").find(doc! {}, None).await?;
+    let mut webhooks = Vec::new();
+    while cursor.advance().await? {
+        let webhook = cursor.deserialize_current()?;
+        if let (Ok(url), Ok(secret)) = (webhook.get_str("url"), webhook.get_str("secret")) {
+            webhooks.push((url.to_string(), secret.to_string()));
+        }
+    }
+    Ok(webhooks)
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// Posts the body until the receiver answers with a 2xx or the tries run out, waiting about
+// `backoff` after the first failure
+async fn deliver(
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+    body: Vec<u8>,
+    backoff: Duration,
+) {
+    let signature = sign(&secret, &body);
+    let mut backoff = backoff;
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let sent = client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature.as_str())
+            .body(body.clone())
+            .send()
+            .await;
+        let failure = match sent {
+            Ok(answer) if answer.status().is_success() => {
+                metrics::increment_counter!("webhook_deliveries_total", "outcome" => "delivered");
+                return;
+            }
+            Ok(answer) => format!("answered {}", answer.status()),
+            Err(err) => err.to_string(),
+        };
+        if attempt == DELIVERY_ATTEMPTS {
+            tracing::warn!("Gave up on webhook {} after {} tries: {}", url, attempt, failure);
+            break;
+        }
+
+        // jitter so deliveries that failed together don't all come back together
+        let wait = backoff.mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+        tracing::info!("Webhook {} {}, trying again in {:?}", url, failure, wait);
+        tokio::time::sleep(wait).await;
+        backoff *= 2;
+    }
+    metrics::increment_counter!("webhook_deliveries_total", "outcome" => "failed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+
+    #[derive(Clone, Default)]
+    struct Receiver {
+        // how many deliveries it turns away before taking one
+        failures: u32,
+        attempts: Arc<AtomicU32>,
+        signatures: Arc<Mutex<Vec<String>>>,
+    }
+
+    async fn receive(State(receiver): State<Receiver>, headers: HeaderMap) -> StatusCode {
+        let signature = headers.get(SIGNATURE_HEADER).unwrap().to_str().unwrap().to_string();
+        receiver.signatures.lock().unwrap().push(signature);
+        let attempt = receiver.attempts.fetch_add(1, Ordering::SeqCst) + 1;
+        if attempt <= receiver.failures {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        }
+    }
+
+    // A receiver on a free local port, returns its URL
+    fn listen(receiver: Receiver) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new().route("/hook", post(receive)).with_state(receiver);
+        let server = axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service());
+        tokio::spawn(server);
+        url
+    }
+
+    #[test]
+    fn signature_is_the_hex_hmac_of_the_body() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_ne!(sign("Jefe", b"what do ya want for nothing!"), sign("Jeff", b"what do ya want for nothing!"));
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried_until_it_goes_through() {
+        let receiver = Receiver { failures: 2, ..Receiver::default() };
+        let url = listen(receiver.clone());
+
+        let body = b"{}".to_vec();
+        let client = Webhooks::new().client;
+        deliver(client, url, String::from("secret"), body.clone(), Duration::from_millis(1)).await;
+
+        assert_eq!(receiver.attempts.load(Ordering::SeqCst), 3);
+        let signatures = receiver.signatures.lock().unwrap();
+        assert!(signatures.iter().all(|signature| *signature == sign("secret", &body)));
+    }
+
+    #[tokio::test]
+    async fn delivery_gives_up_after_the_last_try() {
+        let receiver = Receiver { failures: u32::MAX, ..Receiver::default() };
+        let url = listen(receiver.clone());
+
+        let client = Webhooks::new().client;
+        deliver(client, url, String::from("secret"), Vec::new(), Duration::from_millis(1)).await;
+
+        assert_eq!(receiver.attempts.load(Ordering::SeqCst), DELIVERY_ATTEMPTS);
+    }
+}